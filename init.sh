@@ -4,7 +4,6 @@ set -euo pipefail
 
 DIR="$( cd "$( dirname "${BASH_SOURCE[0]}" )" >/dev/null && pwd )"
 DATA_DIR=$DIR/data
-PLOT_DIR=$DIR/plots
 
 SMALL="https://polybox.ethz.ch/index.php/s/qRlRpFhoPtdO6bR/download"
 BIG="https://polybox.ethz.ch/index.php/s/8JRHOc3fICXtqzN/download"
@@ -14,7 +13,6 @@ BIG_DIR=$DATA_DIR/big
 
 # make data directory
 mkdir -p $DATA_DIR
-mkdir -p $PLOT_DIR
 
 function usage() {
     echo "Usage: $0 [OPTION]..."
@@ -23,7 +21,6 @@ function usage() {
     echo -e "\nOptions: "
     printf "\t %- 30s %s\n" "-s | --download-small" "Download small dataset."
     printf "\t %- 30s %s\n" "-b | --download-big" "Download big dataset."
-    printf "\t %- 30s %s\n" "-v | --video-outliers" "Generate video for outlier posts."
     echo ""
     echo "Example usage: "
     echo "$ ./data.sh --small"
@@ -39,14 +36,6 @@ function download() {
     popd
 }
 
-function generate_video() {
-    pushd $PLOT_DIR
-
-    ffmpeg -framerate 1 -i scatter%000d.svg -c:v libx264 -crf 20 outliers.mp4
-
-    popd
-}
-
 function parse_command_line_options() {
     while [ "${1:-}" != "" ]; do
         case $1 in
@@ -58,10 +47,6 @@ function parse_command_line_options() {
                 download $BIG
                 shift
                 ;;
-            -v | --video-outliers)
-                generate_video
-                shift
-                ;;
             -h | --help)
                 usage
                 exit 0
@@ -0,0 +1,249 @@
+extern crate rand;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::str::FromStr;
+
+// A model of the out-of-orderness of the replayed events. The delays are bounded, so the
+// bound of the model is also the spacing between the watermarks written by the producer.
+pub trait DelayModel: Send {
+    // Delay (in seconds) of an event created at `creation_time`; always less than `bound`.
+    fn delay(&mut self, rng: &mut StdRng, creation_time: usize) -> usize;
+    fn bound(&self) -> usize;
+}
+
+// Events are written in creation order.
+pub struct NoDelay {
+    bound: usize,
+}
+
+impl NoDelay {
+    pub fn new(bound: usize) -> NoDelay {
+        assert!(bound > 0, "bound must be positive");
+        NoDelay { bound }
+    }
+}
+
+impl DelayModel for NoDelay {
+    fn delay(&mut self, _rng: &mut StdRng, _creation_time: usize) -> usize {
+        0
+    }
+
+    fn bound(&self) -> usize {
+        self.bound
+    }
+}
+
+// Delays are uniform in [1, bound).
+pub struct Uniform {
+    bound: usize,
+}
+
+impl Uniform {
+    pub fn new(bound: usize) -> Uniform {
+        assert!(bound > 1, "bound must be greater than 1");
+        Uniform { bound }
+    }
+}
+
+impl DelayModel for Uniform {
+    fn delay(&mut self, rng: &mut StdRng, _creation_time: usize) -> usize {
+        rng.gen_range(1, self.bound)
+    }
+
+    fn bound(&self) -> usize {
+        self.bound
+    }
+}
+
+// Exponential delays with the given mean, capped at the bound.
+pub struct Exponential {
+    mean: f64,
+    bound: usize,
+}
+
+impl Exponential {
+    pub fn new(mean: f64, bound: usize) -> Exponential {
+        assert!(bound > 0, "bound must be positive");
+        Exponential { mean, bound }
+    }
+}
+
+impl DelayModel for Exponential {
+    fn delay(&mut self, rng: &mut StdRng, _creation_time: usize) -> usize {
+        // inverse transform sampling
+        let u: f64 = rng.gen();
+        let delay = -self.mean * (1. - u).ln();
+        cap(delay, self.bound)
+    }
+
+    fn bound(&self) -> usize {
+        self.bound
+    }
+}
+
+// Heavy-tailed delays: most events arrive almost on time, while a few of them arrive
+// close to the bound.
+pub struct Pareto {
+    scale: f64,
+    shape: f64,
+    bound: usize,
+}
+
+impl Pareto {
+    pub fn new(scale: f64, shape: f64, bound: usize) -> Pareto {
+        assert!(bound > 0, "bound must be positive");
+        assert!(scale > 0. && shape > 0., "scale and shape must be positive");
+        Pareto {
+            scale,
+            shape,
+            bound,
+        }
+    }
+}
+
+impl DelayModel for Pareto {
+    fn delay(&mut self, rng: &mut StdRng, _creation_time: usize) -> usize {
+        // inverse transform sampling; the support starts at `scale`
+        let u: f64 = rng.gen();
+        let delay = self.scale / (1. - u).powf(1. / self.shape) - self.scale;
+        cap(delay, self.bound)
+    }
+
+    fn bound(&self) -> usize {
+        self.bound
+    }
+}
+
+// Events are mostly written on time, but with the given probability a burst starts: the
+// following `length` events are held back and released in reverse creation order.
+pub struct BurstReorder {
+    probability: f64,
+    length: usize,
+    bound: usize,
+    release_time: usize,
+    remaining: usize,
+}
+
+impl BurstReorder {
+    pub fn new(probability: f64, length: usize, bound: usize) -> BurstReorder {
+        assert!(bound > 0, "bound must be positive");
+        BurstReorder {
+            probability,
+            length,
+            bound,
+            release_time: 0,
+            remaining: 0,
+        }
+    }
+}
+
+impl DelayModel for BurstReorder {
+    fn delay(&mut self, rng: &mut StdRng, creation_time: usize) -> usize {
+        if self.remaining == 0 && rng.gen::<f64>() < self.probability {
+            self.release_time = creation_time + self.bound - 1;
+            self.remaining = self.length;
+        }
+
+        if self.remaining > 0 {
+            // the i-th event of the burst is released i seconds before the first one
+            let index = self.length - self.remaining;
+            self.remaining -= 1;
+            if self.release_time >= creation_time + index {
+                return self.release_time - index - creation_time;
+            }
+            // the burst has outlived the bound
+            self.remaining = 0;
+        }
+        0
+    }
+
+    fn bound(&self) -> usize {
+        self.bound
+    }
+}
+
+fn cap(delay: f64, bound: usize) -> usize {
+    if delay >= (bound - 1) as f64 {
+        bound - 1
+    } else {
+        delay as usize
+    }
+}
+
+// The delay models that can be selected when loading the streams.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delay {
+    None,
+    Uniform,
+    Exponential,
+    Pareto,
+    Burst,
+}
+
+impl Delay {
+    pub fn model(&self, bound: usize) -> Box<dyn DelayModel> {
+        match self {
+            Delay::None => Box::new(NoDelay::new(bound)),
+            Delay::Uniform => Box::new(Uniform::new(bound)),
+            Delay::Exponential => Box::new(Exponential::new(bound as f64 / 10., bound)),
+            Delay::Pareto => Box::new(Pareto::new(1., 1.2, bound)),
+            Delay::Burst => Box::new(BurstReorder::new(0.01, 50, bound)),
+        }
+    }
+}
+
+impl FromStr for Delay {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Delay::None),
+            "uniform" => Ok(Delay::Uniform),
+            "exponential" => Ok(Delay::Exponential),
+            "pareto" => Ok(Delay::Pareto),
+            "burst" => Ok(Delay::Burst),
+            _ => Err("Unrecognised delay distribution"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod delay_tests {
+    use crate::connection::delay::*;
+    use rand::SeedableRng;
+
+    fn sample(delay: Delay, seed: u64) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut model = delay.model(300);
+        (0..1000).map(|t| model.delay(&mut rng, 1000 + t)).collect()
+    }
+
+    #[test]
+    fn test_delays_are_bounded() {
+        for delay in vec![
+            Delay::None,
+            Delay::Uniform,
+            Delay::Exponential,
+            Delay::Pareto,
+            Delay::Burst,
+        ] {
+            assert!(sample(delay, 42).iter().all(|d| *d < 300));
+        }
+    }
+
+    #[test]
+    fn test_delays_are_reproducible() {
+        assert_eq!(sample(Delay::Pareto, 42), sample(Delay::Pareto, 42));
+        assert_eq!(sample(Delay::Burst, 7), sample(Delay::Burst, 7));
+    }
+
+    #[test]
+    fn test_burst_is_released_in_reverse_order() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut model = BurstReorder::new(1., 3, 10);
+
+        let arrivals: Vec<_> = (0..3).map(|t| t + model.delay(&mut rng, t)).collect();
+        assert_eq!(arrivals, vec![9, 8, 7]);
+    }
+}
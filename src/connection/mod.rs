@@ -1,2 +1,4 @@
+pub mod delay;
 pub mod import;
 pub mod producer;
+pub mod replay;
@@ -2,13 +2,15 @@ extern crate futures;
 extern crate rand;
 extern crate rdkafka;
 
-use crate::dsa::stash::{Stash, Stashable};
+use crate::connection::delay::DelayModel;
+use crate::dsa::stash::Stash;
 use chrono::{DateTime, Duration, FixedOffset};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
 use rdkafka::config::ClientConfig;
 use rdkafka::message::ToBytes;
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
 
 use futures::future::Future;
 use std::fmt::Debug;
@@ -17,16 +19,24 @@ use std::io::{BufRead, BufReader};
 
 pub const FIXED_BOUNDED_DELAY: usize = 300; //seconds
 
+// the number of partitions of every topic, each of which gets the watermarks
+const PARTITIONS: i32 = 4;
+
+// the message written to every partition after the last event of the stream
+pub const END_OF_STREAM: &str = "End";
+
 pub struct Producer {
     producer: FutureProducer,
     topic: String,
     key: u32,
+    delay: Box<dyn DelayModel>,
+    rng: StdRng,
 }
 
 trait Data: Debug + ToBytes {}
 
 impl Producer {
-    pub fn new(topic: String) -> Producer {
+    pub fn new(topic: String, delay: Box<dyn DelayModel>, seed: u64) -> Producer {
         let producer = ClientConfig::new()
             .set("bootstrap.servers", "localhost:9092")
             .set("produce.offset.report", "true")
@@ -37,7 +47,62 @@ impl Producer {
             producer: producer,
             topic: topic,
             key: 0u32,
+            delay: delay,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // Sends the payload with the event time as its timestamp, in milliseconds like any Kafka
+    // timestamp, so that the sources can start at an event time.
+    fn send(
+        &mut self,
+        time: &DateTime<FixedOffset>,
+        payload: &str,
+        partition: Option<i32>,
+    ) -> DeliveryFuture {
+        let key = self.key.to_string();
+        self.key += 1;
+        let mut record = FutureRecord::to(&self.topic)
+            .payload(payload)
+            .key(&key)
+            .timestamp(time.timestamp_millis());
+        if let Some(partition) = partition {
+            record = record.partition(partition);
+        }
+        self.producer.send(record, 0)
+    }
+
+    // Sends the stashed lines and the watermarks of every period of the delay bound until
+    // the watermarks are within the bound of `time`.
+    fn release(
+        &mut self,
+        stash: &mut Stash<(DateTime<FixedOffset>, String, Option<i32>)>,
+        epoch_start_time: &mut DateTime<FixedOffset>,
+        time: DateTime<FixedOffset>,
+    ) -> Vec<DeliveryFuture> {
+        let bound = Duration::seconds(self.delay.bound() as i64);
+        let old_time = epoch_start_time.timestamp();
+        while time > *epoch_start_time + bound {
+            for i in 0..PARTITIONS {
+                stash.stash(
+                    epoch_start_time.timestamp() as usize,
+                    (
+                        *epoch_start_time,
+                        "Watermark|".to_owned() + &epoch_start_time.timestamp().to_string(),
+                        Some(i),
+                    ),
+                );
+            }
+            *epoch_start_time = *epoch_start_time + bound;
         }
+        let stashed_lines = stash.extract(
+            (epoch_start_time.timestamp() - old_time + 1) as usize,
+            epoch_start_time.timestamp() as usize,
+        );
+        stashed_lines
+            .into_iter()
+            .map(|(timestamp, line, partition)| self.send(&timestamp, &line, partition))
+            .collect()
     }
 
     pub fn write_file(
@@ -50,78 +115,33 @@ impl Producer {
         let f = BufReader::new(f);
 
         let mut stash = Stash::new();
+        // watermarks are spaced by the bound of the delay model
+        let bound = Duration::seconds(self.delay.bound() as i64);
 
         let mut epoch_start_time = start_time.clone();
         let mut futures = Vec::new();
 
         let mut cnt = 0;
         for line in f.lines().skip(1) {
+            if lines.map_or(false, |lines| cnt >= lines) {
+                break;
+            }
             let line = line.unwrap();
             let fields: Vec<&str> = line.split("|").collect();
             let creation_time = DateTime::parse_from_rfc3339(fields[2]).unwrap();
 
-            if creation_time <= epoch_start_time + Duration::seconds(FIXED_BOUNDED_DELAY as i64) {
-            } else {
-                let old_time = epoch_start_time.timestamp();
-                // Generate some watermarks for every five minute period between
-                // and output the stashed lines
-                while creation_time
-                    > epoch_start_time + Duration::seconds(FIXED_BOUNDED_DELAY as i64)
-                {
-                    // Gen watermark
-                    for i in 0..4 {
-                        stash.stash(
-                            epoch_start_time.timestamp() as usize,
-                            (
-                                epoch_start_time,
-                                "Watermark|".to_owned() + &epoch_start_time.timestamp().to_string(),
-                                Some(i),
-                            ),
-                        );
-                    }
-                    epoch_start_time =
-                        epoch_start_time + Duration::seconds(FIXED_BOUNDED_DELAY as i64);
-                }
-                let mut stashed_lines = stash.extract(
-                    (epoch_start_time.timestamp() - old_time + 1) as usize,
-                    epoch_start_time.timestamp() as usize,
-                );
-                for (timestamp, stashed_line, maybe_partition) in stashed_lines.drain(..) {
-                    let future = match maybe_partition {
-                        None => self.producer.send(
-                            FutureRecord::to(&self.topic)
-                                .payload(&stashed_line)
-                                .key(&self.key.to_string())
-                                .timestamp(timestamp.timestamp()),
-                            0,
-                        ),
-                        Some(partition) => self.producer.send(
-                            FutureRecord::to(&self.topic)
-                                .payload(&stashed_line)
-                                .partition(partition)
-                                .key(&self.key.to_string())
-                                .timestamp(timestamp.timestamp()),
-                            0,
-                        ),
-                    };
-                    self.key += 1;
-                    futures.push(future);
-                    if let Some(lines) = lines {
-                        if cnt >= lines {
-                            for future in futures {
-                                match future.wait() {
-                                    Ok(_) => (),
-                                    Err(e) => println!("{:?}", e),
-                                }
-                            }
-                            return cnt;
-                        }
-                    }
-                }
+            // Generate watermarks for every period of the delay bound between
+            // and output the stashed lines
+            if creation_time > epoch_start_time + bound {
+                let mut sent = self.release(&mut stash, &mut epoch_start_time, creation_time);
+                futures.append(&mut sent);
             }
-            // Stash the line with a random fixed bounded delay
-            let offset =
-                Duration::seconds(rand::thread_rng().gen_range(1, FIXED_BOUNDED_DELAY) as i64);
+            // Stash the line with a random bounded delay
+            let offset = Duration::seconds(
+                self.delay
+                    .delay(&mut self.rng, creation_time.timestamp() as usize)
+                    as i64,
+            );
             let insertion_time = creation_time + offset;
             stash.stash(
                 insertion_time.timestamp() as usize,
@@ -130,6 +150,20 @@ impl Producer {
             cnt += 1;
         }
 
+        // The end of the stream: the lines left are sent along with the watermarks which
+        // pass them, then a last watermark and the end-of-stream marker on every partition.
+        if let Some(last) = stash.last_time() {
+            let last = *start_time + Duration::seconds(last as i64 - start_time.timestamp());
+            let end = last + bound + Duration::seconds(1);
+            let mut sent = self.release(&mut stash, &mut epoch_start_time, end);
+            futures.append(&mut sent);
+        }
+        for i in 0..PARTITIONS {
+            let watermark = "Watermark|".to_owned() + &epoch_start_time.timestamp().to_string();
+            futures.push(self.send(&epoch_start_time, &watermark, Some(i)));
+            futures.push(self.send(&epoch_start_time, END_OF_STREAM, Some(i)));
+        }
+
         for future in futures {
             match future.wait() {
                 Ok(_) => (),
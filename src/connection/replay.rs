@@ -0,0 +1,88 @@
+extern crate timely;
+
+use crate::connection::import::parse_csv;
+use crate::dto::common::{Importable, Timestamped, Watermarkable};
+
+use timely::dataflow::InputHandle;
+use timely::Data;
+
+use std::fmt::Debug;
+use std::path::Path;
+
+// Replays a stream read from a CSV file through an input handle the same way the Kafka
+// source emits it: without delays, the records created up to a watermark are given
+// before the watermark and the following ones at the time of the watermark.
+pub struct Replay<D: Data> {
+    input: InputHandle<usize, D>,
+    records: Vec<D>,
+    next: usize,
+    bound: usize,
+    // the partition replayed, which the watermarks are marked with
+    index: usize,
+    // the times of the first and the last record of the whole stream
+    start_time: usize,
+    end_time: usize,
+}
+
+impl<D: Data + Debug + Importable<D> + Watermarkable + Timestamped> Replay<D> {
+    pub fn new(path: &Path, bound: usize) -> Replay<D> {
+        let mut records = vec![];
+        parse_csv(path.to_str().unwrap(), |record: D| records.push(record));
+        records.sort_by_key(|record| record.timestamp());
+
+        Replay {
+            input: InputHandle::new(),
+            start_time: records[0].timestamp(),
+            end_time: records.last().map_or(0, |record| record.timestamp()),
+            records,
+            next: 0,
+            bound,
+            index: 0,
+        }
+    }
+
+    // Only replays the partition `index` of `peers`, made of every `peers`-th record, so
+    // that each worker can replay its own partition.
+    pub fn partition(mut self, index: usize, peers: usize) -> Replay<D> {
+        self.records = self
+            .records
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| i % peers == index)
+            .map(|(_, record)| record)
+            .collect();
+        self.index = index;
+        self
+    }
+
+    pub fn input(&mut self) -> &mut InputHandle<usize, D> {
+        &mut self.input
+    }
+
+    pub fn start_time(&self) -> usize {
+        self.start_time
+    }
+
+    pub fn end_time(&self) -> usize {
+        self.end_time
+    }
+
+    // Gives the watermark `time` and the records created before the next watermark.
+    pub fn advance(&mut self, time: usize) {
+        // the records created before the first watermark are given at the initial time
+        self.give_until(time);
+        self.input.advance_to(time);
+        self.input
+            .send_batch(&mut vec![D::from_watermark(&time.to_string(), self.index)]);
+        self.give_until(time + self.bound);
+    }
+
+    fn give_until(&mut self, time: usize) {
+        let mut batch = vec![];
+        while self.next < self.records.len() && self.records[self.next].timestamp() <= time {
+            batch.push(self.records[self.next].clone());
+            self.next += 1;
+        }
+        self.input.send_batch(&mut batch);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::dsa::kmeans::*;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Ordering::Equal;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+const NEIGHBOURS: usize = 10;
+const LOF_THRESHOLD: f64 = 1.5;
+// keeps the density of duplicated points finite
+const MIN_REACH: f64 = 0.000_000_1;
+const TREES: usize = 100;
+const SAMPLE_SIZE: usize = 256;
+const ISOLATION_THRESHOLD: f64 = 0.7;
+const MAD_THRESHOLD: f64 = 3.5;
+// scales the MAD to the standard deviation of normally distributed values
+const MAD_SCALE: f64 = 0.6745;
+const EULER_GAMMA: f64 = 0.577_215_664_9;
+
+// The points searched for outliers, with what the detectors need to know about them.
+pub struct Observations<'a> {
+    pub points: &'a [Point],
+    // the author of each point
+    pub persons: &'a [u32],
+    // the centers of the clusters of the points
+    pub centers: &'a [Point],
+}
+
+pub trait AnomalyDetector {
+    // The anomaly score of each point. Points scoring at least 1 are outliers.
+    fn scores(&mut self, observations: &Observations) -> Vec<f64>;
+}
+
+// Points far away from the closest center, using the percentile and gap rule.
+pub struct Clusters {
+    percentile: f64,
+}
+
+impl Clusters {
+    pub fn new(percentile: f64) -> Clusters {
+        Clusters { percentile }
+    }
+}
+
+impl AnomalyDetector for Clusters {
+    fn scores(&mut self, observations: &Observations) -> Vec<f64> {
+        let (centers, points) = (observations.centers, observations.points);
+        if centers.is_empty() {
+            return vec![0.; points.len()];
+        }
+
+        let dist_outlier = outlier_distance(centers, points, self.percentile);
+        points
+            .iter()
+            .map(|point| min_dist(centers, point) / dist_outlier)
+            .collect()
+    }
+}
+
+// Points much less dense than their nearest neighbours.
+pub struct LocalOutlierFactor {
+    neighbours: usize,
+    threshold: f64,
+}
+
+impl LocalOutlierFactor {
+    pub fn new(neighbours: usize, threshold: f64) -> LocalOutlierFactor {
+        LocalOutlierFactor {
+            neighbours,
+            threshold,
+        }
+    }
+}
+
+impl AnomalyDetector for LocalOutlierFactor {
+    fn scores(&mut self, observations: &Observations) -> Vec<f64> {
+        let points = observations.points;
+        let k = self.neighbours.min(points.len().saturating_sub(1));
+        if k == 0 {
+            return vec![0.; points.len()];
+        }
+
+        // the k nearest neighbours of each point, with their distances
+        let neighbours: Vec<Vec<(usize, f64)>> = (0..points.len())
+            .map(|i| {
+                let mut dist: Vec<(usize, f64)> = (0..points.len())
+                    .filter(|j| *j != i)
+                    .map(|j| (j, sqr_dist(&points[i], &points[j]).sqrt()))
+                    .collect();
+                dist.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Equal));
+                dist.truncate(k);
+                dist
+            })
+            .collect();
+        let k_dist: Vec<f64> = neighbours.iter().map(|n| n[k - 1].1).collect();
+
+        // local reachability density
+        let lrd: Vec<f64> = neighbours
+            .iter()
+            .map(|n| {
+                let reach: f64 = n.iter().map(|(j, d)| d.max(k_dist[*j])).sum();
+                k as f64 / reach.max(MIN_REACH)
+            })
+            .collect();
+
+        (0..points.len())
+            .map(|i| {
+                let lof =
+                    neighbours[i].iter().map(|(j, _)| lrd[*j]).sum::<f64>() / (k as f64 * lrd[i]);
+                lof / self.threshold
+            })
+            .collect()
+    }
+}
+
+enum Node {
+    Leaf(usize),
+    Split(usize, f64, Box<Node>, Box<Node>),
+}
+
+// The average path length of an unsuccessful search in a binary search tree of n points.
+fn average_path(n: usize) -> f64 {
+    match n {
+        0 | 1 => 0.,
+        2 => 1.,
+        _ => {
+            let n = n as f64;
+            2. * ((n - 1.).ln() + EULER_GAMMA) - 2. * (n - 1.) / n
+        }
+    }
+}
+
+// Points isolated by few random splits.
+pub struct IsolationForest {
+    trees: usize,
+    sample_size: usize,
+    threshold: f64,
+    rng: StdRng,
+}
+
+impl IsolationForest {
+    pub fn new(trees: usize, sample_size: usize, threshold: f64, seed: u64) -> IsolationForest {
+        IsolationForest {
+            trees,
+            sample_size,
+            threshold,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    fn build(&mut self, points: &[&Point], height: usize) -> Node {
+        if height == 0 || points.len() <= 1 {
+            return Node::Leaf(points.len());
+        }
+
+        // split on a random dimension along which the points are not all equal
+        let dimensions = points[0].len();
+        let spread: Vec<(usize, f64, f64)> = (0..dimensions)
+            .map(|d| {
+                let min = points.iter().map(|p| p[d]).fold(f64::INFINITY, f64::min);
+                let max = points
+                    .iter()
+                    .map(|p| p[d])
+                    .fold(f64::NEG_INFINITY, f64::max);
+                (d, min, max)
+            })
+            .filter(|(_, min, max)| min < max)
+            .collect();
+        if spread.is_empty() {
+            return Node::Leaf(points.len());
+        }
+        let (d, min, max) = spread[self.rng.gen_range(0, spread.len())];
+        let value = self.rng.gen_range(min, max);
+
+        let (left, right): (Vec<&Point>, Vec<&Point>) = points.iter().partition(|p| p[d] < value);
+        Node::Split(
+            d,
+            value,
+            Box::new(self.build(&left, height - 1)),
+            Box::new(self.build(&right, height - 1)),
+        )
+    }
+
+    fn path(node: &Node, point: &Point, depth: usize) -> f64 {
+        match node {
+            Node::Leaf(size) => depth as f64 + average_path(*size),
+            Node::Split(d, value, left, right) => match point[*d] < *value {
+                true => IsolationForest::path(left, point, depth + 1),
+                false => IsolationForest::path(right, point, depth + 1),
+            },
+        }
+    }
+}
+
+impl AnomalyDetector for IsolationForest {
+    fn scores(&mut self, observations: &Observations) -> Vec<f64> {
+        let points = observations.points;
+        let sample_size = self.sample_size.min(points.len());
+        if sample_size < 2 {
+            return vec![0.; points.len()];
+        }
+        let height = (sample_size as f64).log2().ceil() as usize;
+
+        let mut paths = vec![0.; points.len()];
+        for _ in 0..self.trees {
+            let sample: Vec<&Point> = (0..sample_size)
+                .map(|_| &points[self.rng.gen_range(0, points.len())])
+                .collect();
+            let tree = self.build(&sample, height);
+            for (path, point) in paths.iter_mut().zip(points.iter()) {
+                *path += IsolationForest::path(&tree, point, 0);
+            }
+        }
+
+        paths
+            .iter()
+            .map(|path| {
+                let score = 2f64.powf(-path / self.trees as f64 / average_path(sample_size));
+                score / self.threshold
+            })
+            .collect()
+    }
+}
+
+fn median(values: &mut Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
+    let n = values.len();
+    match n % 2 {
+        0 => (values[n / 2 - 1] + values[n / 2]) / 2.,
+        _ => values[n / 2],
+    }
+}
+
+// Points of people posting much more than the others, using the robust z-score of the
+// number of points of each person.
+pub struct Rates {
+    threshold: f64,
+}
+
+impl Rates {
+    pub fn new(threshold: f64) -> Rates {
+        Rates { threshold }
+    }
+}
+
+impl AnomalyDetector for Rates {
+    fn scores(&mut self, observations: &Observations) -> Vec<f64> {
+        let mut rates: HashMap<u32, f64> = HashMap::new();
+        for person_id in observations.persons.iter() {
+            *rates.entry(*person_id).or_insert(0.) += 1.;
+        }
+        if rates.is_empty() {
+            return vec![];
+        }
+
+        let mut values: Vec<f64> = rates.values().cloned().collect();
+        let median_rate = median(&mut values);
+        let mut deviations: Vec<f64> = values.iter().map(|r| (r - median_rate).abs()).collect();
+        let mad = median(&mut deviations);
+        if mad <= 0. {
+            // more than half of the people post at the same rate
+            return observations
+                .persons
+                .iter()
+                .map(|person_id| match rates[person_id] > median_rate {
+                    true => 1.,
+                    false => 0.,
+                })
+                .collect();
+        }
+
+        observations
+            .persons
+            .iter()
+            .map(|person_id| MAD_SCALE * (rates[person_id] - median_rate) / mad / self.threshold)
+            .collect()
+    }
+}
+
+// Points that most of the detectors agree are outliers.
+pub struct Ensemble {
+    detectors: Vec<Box<dyn AnomalyDetector>>,
+}
+
+impl Ensemble {
+    pub fn new(detectors: Vec<Box<dyn AnomalyDetector>>) -> Ensemble {
+        Ensemble { detectors }
+    }
+}
+
+impl AnomalyDetector for Ensemble {
+    fn scores(&mut self, observations: &Observations) -> Vec<f64> {
+        let mut votes = vec![0.; observations.points.len()];
+        for detector in self.detectors.iter_mut() {
+            for (vote, score) in votes.iter_mut().zip(detector.scores(observations)) {
+                if score >= 1. {
+                    *vote += 1.;
+                }
+            }
+        }
+
+        let majority = (self.detectors.len() / 2 + 1) as f64;
+        votes.iter().map(|vote| vote / majority).collect()
+    }
+}
+
+// The detectors that can be selected for the unusual activity task.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DetectorKind {
+    Clusters,
+    Lof,
+    IsolationForest,
+    Rates,
+    Ensemble,
+}
+
+impl DetectorKind {
+    // The ensemble votes with all the other detectors.
+    pub fn detector(&self, percentile: f64, seed: u64) -> Box<dyn AnomalyDetector> {
+        match self {
+            DetectorKind::Clusters => Box::new(Clusters::new(percentile)),
+            DetectorKind::Lof => Box::new(LocalOutlierFactor::new(NEIGHBOURS, LOF_THRESHOLD)),
+            DetectorKind::IsolationForest => Box::new(IsolationForest::new(
+                TREES,
+                SAMPLE_SIZE,
+                ISOLATION_THRESHOLD,
+                seed,
+            )),
+            DetectorKind::Rates => Box::new(Rates::new(MAD_THRESHOLD)),
+            DetectorKind::Ensemble => Box::new(Ensemble::new(
+                vec![
+                    DetectorKind::Clusters,
+                    DetectorKind::Lof,
+                    DetectorKind::IsolationForest,
+                    DetectorKind::Rates,
+                ]
+                .iter()
+                .map(|kind| kind.detector(percentile, seed))
+                .collect(),
+            )),
+        }
+    }
+}
+
+impl FromStr for DetectorKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clusters" => Ok(DetectorKind::Clusters),
+            "lof" => Ok(DetectorKind::Lof),
+            "isolation-forest" => Ok(DetectorKind::IsolationForest),
+            "rates" => Ok(DetectorKind::Rates),
+            "ensemble" => Ok(DetectorKind::Ensemble),
+            _ => Err("Unrecognised detector"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod anomaly_tests {
+    use crate::dsa::anomaly::*;
+
+    // A grid of points with a single point far away from it, which is the last one.
+    fn grid() -> Vec<Point> {
+        let mut points: Vec<Point> = (0..100)
+            .map(|i| vec![(i % 10) as f64, (i / 10) as f64])
+            .collect();
+        points.push(vec![50., 50.]);
+        points
+    }
+
+    fn outliers(detector: &mut dyn AnomalyDetector, observations: &Observations) -> Vec<usize> {
+        detector
+            .scores(observations)
+            .iter()
+            .enumerate()
+            .filter(|(_, score)| **score >= 1.)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn test_point_detectors_find_the_isolated_point() {
+        let points = grid();
+        let persons: Vec<u32> = (0..points.len() as u32).collect();
+        let centers = vec![vec![4.5, 4.5]];
+        let observations = Observations {
+            points: &points,
+            persons: &persons,
+            centers: &centers,
+        };
+
+        for kind in vec![
+            DetectorKind::Clusters,
+            DetectorKind::Lof,
+            DetectorKind::IsolationForest,
+        ] {
+            let mut detector = kind.detector(0.97, 0);
+            assert_eq!(
+                outliers(&mut *detector, &observations),
+                vec![100],
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn test_lof_scores_of_duplicated_points_are_finite() {
+        // 20 copies of the same point, and one point next to them
+        let mut points = vec![vec![0., 0.]; 20];
+        points.push(vec![1., 0.]);
+        let persons: Vec<u32> = (0..points.len() as u32).collect();
+        let observations = Observations {
+            points: &points,
+            persons: &persons,
+            centers: &[],
+        };
+
+        let mut detector = LocalOutlierFactor::new(NEIGHBOURS, LOF_THRESHOLD);
+        assert!(detector
+            .scores(&observations)
+            .iter()
+            .all(|score| score.is_finite()));
+        assert_eq!(outliers(&mut detector, &observations), vec![20]);
+    }
+
+    #[test]
+    fn test_rates_find_the_person_posting_the_most() {
+        let points = vec![vec![0.]; 12];
+        // person 4 posts 5 times, while everyone else posts once or twice
+        let persons = vec![0, 1, 1, 2, 2, 3, 4, 4, 4, 4, 4, 5];
+        let observations = Observations {
+            points: &points,
+            persons: &persons,
+            centers: &[],
+        };
+
+        assert_eq!(
+            outliers(&mut Rates::new(MAD_THRESHOLD), &observations),
+            vec![6, 7, 8, 9, 10]
+        );
+    }
+
+    #[test]
+    fn test_ensemble_needs_a_majority() {
+        let points = grid();
+        // the person of the isolated point posts as often as the others
+        let persons: Vec<u32> = (0..points.len() as u32).collect();
+        let centers = vec![vec![4.5, 4.5]];
+        let observations = Observations {
+            points: &points,
+            persons: &persons,
+            centers: &centers,
+        };
+
+        let mut detector = DetectorKind::Ensemble.detector(0.97, 0);
+        assert_eq!(outliers(&mut *detector, &observations), vec![100]);
+
+        let mut rates_only = Ensemble::new(vec![
+            Box::new(Rates::new(MAD_THRESHOLD)),
+            Box::new(Clusters::new(0.97)),
+            Box::new(Rates::new(MAD_THRESHOLD)),
+        ]);
+        assert_eq!(
+            outliers(&mut rates_only, &observations),
+            Vec::<usize>::new()
+        );
+    }
+}
@@ -0,0 +1,471 @@
+use crate::dto::comment::Comment;
+use crate::dto::common::Browser;
+use crate::dto::like::Like;
+use crate::dto::post::Post;
+
+use std::cmp::Ordering::Equal;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+// comments made this soon after their post are suspiciously fast, in seconds
+const FAST_COMMENT: usize = 10;
+// posts are forgotten after this long, in seconds, so likes and comments on them are not
+// linked
+const RETENTION: usize = 7 * 24 * 60 * 60;
+// weight of the last period in the baseline of a person
+const BASELINE_RATE: f64 = 0.1;
+// periods a person must have been active for before they have a baseline
+const MIN_BASELINE: usize = 3;
+// the deviations are at least this, so that steady behaviours are not flagged for any change
+const MIN_DEVIATION: f64 = 1.;
+const Z_THRESHOLD: f64 = 3.5;
+// scales the MAD to the standard deviation of normally distributed values
+const MAD_SCALE: f64 = 0.6745;
+
+// The behaviours tracked for each person, with the minimum value that can be unusual.
+pub const BEHAVIOURS: [(&str, f64); 5] = [
+    ("activity", 5.),
+    ("like burst", 5.),
+    ("ips", 3.),
+    ("browsers", 3.),
+    ("fast comments", 3.),
+];
+
+// The behaviour of a person in a period.
+#[derive(Default)]
+struct Counts {
+    // posts and comments
+    activity: usize,
+    // map: author -> likes on their posts
+    likes_by_author: HashMap<u32, usize>,
+    ips: HashSet<Ipv4Addr>,
+    browsers: HashSet<String>,
+    fast_comments: usize,
+}
+
+impl Counts {
+    fn values(&self) -> Vec<f64> {
+        vec![
+            self.activity as f64,
+            *self.likes_by_author.values().max().unwrap_or(&0) as f64,
+            self.ips.len() as f64,
+            self.browsers.len() as f64,
+            self.fast_comments as f64,
+        ]
+    }
+}
+
+// The usual behaviour of a person, as moving averages of the periods they were active in.
+#[derive(Default)]
+struct Baseline {
+    periods: usize,
+    mean: Vec<f64>,
+    variance: Vec<f64>,
+}
+
+impl Baseline {
+    fn update(&mut self, values: &[f64]) {
+        if self.periods == 0 {
+            self.mean = values.to_vec();
+            self.variance = vec![0.; values.len()];
+        } else {
+            for i in 0..values.len() {
+                let diff = values[i] - self.mean[i];
+                self.mean[i] += BASELINE_RATE * diff;
+                self.variance[i] =
+                    (1. - BASELINE_RATE) * (self.variance[i] + BASELINE_RATE * diff * diff);
+            }
+        }
+        self.periods += 1;
+    }
+
+    fn z_score(&self, i: usize, value: f64) -> f64 {
+        if self.periods < MIN_BASELINE {
+            return 0.;
+        }
+        (value - self.mean[i]) / self.variance[i].sqrt().max(MIN_DEVIATION)
+    }
+}
+
+fn median(values: &mut Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
+    let n = values.len();
+    match n % 2 {
+        0 => (values[n / 2 - 1] + values[n / 2]) / 2.,
+        _ => values[n / 2],
+    }
+}
+
+// Browsers are named as in the comments, whatever the stream.
+fn browser_name(browser: &Browser) -> String {
+    format!("{:?}", browser)
+}
+
+// A person whose behaviour in a period deviates from their own baseline or from the
+// behaviour of the other people active in the period.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Flag {
+    pub person_id: u32,
+    // the values of the behaviours, in the order of BEHAVIOURS
+    pub values: Vec<f64>,
+    // at least 1, the largest deviation relative to the threshold
+    pub score: f64,
+}
+
+// The author and creation time of the recent posts, so that the likes and comments on them
+// can be linked to them.
+pub struct Posts {
+    // map: post id -> (author, creation time)
+    posts: HashMap<u32, (u32, usize)>,
+    // map: creation time -> post ids, for the expiry
+    by_time: BTreeMap<usize, Vec<u32>>,
+}
+
+impl Posts {
+    pub fn new() -> Posts {
+        Posts {
+            posts: HashMap::new(),
+            by_time: BTreeMap::new(),
+        }
+    }
+
+    pub fn publish(&mut self, post: &Post) {
+        self.posts.insert(post.id, (post.person_id, post.timestamp));
+        self.by_time
+            .entry(post.timestamp)
+            .or_insert(vec![])
+            .push(post.id);
+    }
+
+    // The (author, creation time) of the post.
+    pub fn get(&self, post_id: u32) -> Option<(u32, usize)> {
+        self.posts.get(&post_id).cloned()
+    }
+
+    // Forgets the posts that are too old at `time`.
+    pub fn expire(&mut self, time: usize) {
+        let expired: Vec<usize> = self
+            .by_time
+            .range(..time.saturating_sub(RETENTION))
+            .map(|(created, _)| *created)
+            .collect();
+        for created in expired {
+            for post_id in self.by_time.remove(&created).unwrap_or(vec![]) {
+                self.posts.remove(&post_id);
+            }
+        }
+    }
+}
+
+// Counts the behaviour of people in the current period.
+pub struct Behaviour {
+    current: HashMap<u32, Counts>,
+}
+
+impl Behaviour {
+    pub fn new() -> Behaviour {
+        Behaviour {
+            current: HashMap::new(),
+        }
+    }
+
+    pub fn post(&mut self, post: &Post) {
+        let browser = match post.browser_used.parse::<Browser>() {
+            Ok(browser) => browser_name(&browser),
+            Err(_) => post.browser_used.clone(),
+        };
+        let counts = self.current.entry(post.person_id).or_default();
+        counts.activity += 1;
+        counts.ips.insert(post.location_ip);
+        counts.browsers.insert(browser);
+    }
+
+    // The post is the (author, creation time) of the post commented on, if known.
+    pub fn comment(&mut self, comment: &Comment, post: Option<(u32, usize)>) {
+        let counts = self.current.entry(comment.person_id).or_default();
+        counts.activity += 1;
+        counts.ips.insert(comment.location_ip);
+        counts.browsers.insert(browser_name(&comment.browser_used));
+        if let Some((_, created)) = post {
+            if comment.timestamp < created + FAST_COMMENT {
+                counts.fast_comments += 1;
+            }
+        }
+    }
+
+    // The post is the (author, creation time) of the post liked, if known.
+    pub fn like(&mut self, like: &Like, post: Option<(u32, usize)>) {
+        if let Some((author, _)) = post {
+            let counts = self.current.entry(like.person_id).or_default();
+            *counts.likes_by_author.entry(author).or_insert(0) += 1;
+        }
+    }
+
+    // Ends the period and returns the values of the behaviours of the people active in it,
+    // in increasing order of id.
+    pub fn period(&mut self) -> Vec<(u32, Vec<f64>)> {
+        let mut current: Vec<(u32, Vec<f64>)> = self
+            .current
+            .drain()
+            .map(|(person_id, counts)| (person_id, counts.values()))
+            .collect();
+        current.sort_by_key(|(person_id, _)| *person_id);
+        current
+    }
+}
+
+// The (median, median absolute deviation) of each behaviour among all the people active in
+// a period, given the values of their behaviours.
+pub fn population(current: &[Vec<f64>]) -> Vec<(f64, f64)> {
+    (0..BEHAVIOURS.len())
+        .map(|i| {
+            let mut values: Vec<f64> = current.iter().map(|values| values[i]).collect();
+            if values.is_empty() {
+                return (0., 0.);
+            }
+            let median_value = median(&mut values);
+            let mut deviations: Vec<f64> =
+                values.iter().map(|v| (v - median_value).abs()).collect();
+            (median_value, median(&mut deviations))
+        })
+        .collect()
+}
+
+// The usual behaviour of every person, against which their periods are compared.
+pub struct Baselines {
+    baselines: HashMap<u32, Baseline>,
+}
+
+impl Baselines {
+    pub fn new() -> Baselines {
+        Baselines {
+            baselines: HashMap::new(),
+        }
+    }
+
+    // Takes in the behaviours of the people in a period, and the population baseline of
+    // the period, and returns the people behaving unusually, in increasing order of id.
+    pub fn flag(&mut self, current: Vec<(u32, Vec<f64>)>, population: &[(f64, f64)]) -> Vec<Flag> {
+        let mut flags = vec![];
+        for (person_id, values) in current {
+            let baseline = self.baselines.entry(person_id).or_default();
+            let mut score = 0f64;
+            for (i, (_, min_value)) in BEHAVIOURS.iter().enumerate() {
+                if values[i] < *min_value {
+                    continue;
+                }
+                let (median_value, mad) = population[i];
+                let population_z = MAD_SCALE * (values[i] - median_value) / mad.max(MIN_DEVIATION);
+                let z = baseline.z_score(i, values[i]).max(population_z);
+                score = score.max(z / Z_THRESHOLD);
+            }
+            baseline.update(&values);
+
+            if score >= 1. {
+                flags.push(Flag {
+                    person_id,
+                    values,
+                    score,
+                });
+            }
+        }
+        flags.sort_by_key(|flag| flag.person_id);
+        flags
+    }
+}
+
+#[cfg(test)]
+mod behaviour_tests {
+    use crate::dsa::behaviour::*;
+    use crate::dto::common::Watermarkable;
+
+    fn post(id: u32, person_id: u32, timestamp: usize) -> Post {
+        Post {
+            id,
+            person_id,
+            timestamp,
+            browser_used: "Firefox".to_string(),
+            is_watermark: false,
+            ..Post::from_watermark("0", 0)
+        }
+    }
+
+    fn comment(person_id: u32, timestamp: usize, post_id: u32, ip: u8) -> Comment {
+        Comment {
+            person_id,
+            timestamp,
+            reply_to_post_id: Some(post_id),
+            location_ip: Ipv4Addr::new(10, 0, 0, ip),
+            is_watermark: false,
+            ..Comment::from_watermark("0", 0)
+        }
+    }
+
+    fn like(person_id: u32, post_id: u32) -> Like {
+        Like {
+            person_id,
+            post_id,
+            timestamp: 0,
+            is_watermark: false,
+        }
+    }
+
+    // The steps of the task on a single worker.
+    struct Tracker {
+        posts: Posts,
+        behaviour: Behaviour,
+        baselines: Baselines,
+    }
+
+    impl Tracker {
+        fn new() -> Tracker {
+            Tracker {
+                posts: Posts::new(),
+                behaviour: Behaviour::new(),
+                baselines: Baselines::new(),
+            }
+        }
+
+        fn publish(&mut self, post: &Post) {
+            self.posts.publish(post);
+        }
+
+        fn post(&mut self, post: &Post) {
+            self.behaviour.post(post);
+        }
+
+        fn comment(&mut self, comment: &Comment) {
+            let post = comment
+                .reply_to_post_id
+                .and_then(|post_id| self.posts.get(post_id));
+            self.behaviour.comment(comment, post);
+        }
+
+        fn like(&mut self, like: &Like) {
+            self.behaviour.like(like, self.posts.get(like.post_id));
+        }
+
+        fn period(&mut self) -> Vec<Flag> {
+            let current = self.behaviour.period();
+            let values: Vec<Vec<f64>> = current.iter().map(|(_, values)| values.clone()).collect();
+            self.baselines.flag(current, &population(&values))
+        }
+    }
+
+    // Ten people posting once, each on their own post and from their own address.
+    fn quiet_period(behaviour: &mut Tracker, time: usize) {
+        for person_id in 0..10 {
+            let id = (time + person_id) as u32;
+            behaviour.publish(&post(id, person_id as u32, time));
+            behaviour.post(&post(id, person_id as u32, time));
+        }
+    }
+
+    #[test]
+    fn test_quiet_people_are_not_flagged() {
+        let mut behaviour = Tracker::new();
+        for time in 1..5 {
+            quiet_period(&mut behaviour, time * 1000);
+            assert_eq!(behaviour.period(), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_activity_spikes_are_flagged() {
+        let mut behaviour = Tracker::new();
+        for time in 1..5 {
+            quiet_period(&mut behaviour, time * 1000);
+            behaviour.period();
+        }
+
+        // person 3 suddenly posts twenty times
+        quiet_period(&mut behaviour, 5000);
+        for i in 0..20 {
+            behaviour.post(&post(100 + i, 3, 5000));
+        }
+        let flags = behaviour.period();
+        assert_eq!(flags.len(), 1);
+        assert_eq!((flags[0].person_id, flags[0].values[0]), (3, 21.));
+    }
+
+    #[test]
+    fn test_like_bursts_and_fast_comments_are_flagged() {
+        let mut behaviour = Tracker::new();
+        quiet_period(&mut behaviour, 1000);
+
+        // person 20 likes every post of person 5, and person 21 comments on them at once
+        for i in 0..8 {
+            let id = 200 + i;
+            behaviour.publish(&post(id, 5, 1000 + i as usize * 60));
+            behaviour.like(&like(20, id));
+            behaviour.comment(&comment(21, 1000 + i as usize * 60 + 2, id, 1));
+        }
+        // everyone else likes a post and comments slowly
+        for person_id in 30..40 {
+            behaviour.like(&like(person_id, 1000));
+            behaviour.comment(&comment(person_id, 1500, 1000, 1));
+        }
+
+        let flagged: Vec<u32> = behaviour
+            .period()
+            .iter()
+            .map(|flag| flag.person_id)
+            .collect();
+        assert_eq!(flagged, vec![20, 21]);
+    }
+
+    #[test]
+    fn test_many_addresses_are_flagged() {
+        let mut behaviour = Tracker::new();
+        quiet_period(&mut behaviour, 1000);
+        for ip in 0..8 {
+            behaviour.comment(&comment(7, 1500, 1000, ip));
+        }
+
+        let flags = behaviour.period();
+        assert_eq!(flags.len(), 1);
+        assert_eq!((flags[0].person_id, flags[0].values[2]), (7, 9.));
+    }
+
+    #[test]
+    fn test_workers_flag_the_same_people_with_the_population_of_all_of_them() {
+        // the people of the spike test, split between two workers by the parity of their id
+        let mut single = Tracker::new();
+        let mut workers = vec![Baselines::new(), Baselines::new()];
+        for time in 1..6 {
+            quiet_period(&mut single, time * 1000);
+            if time == 5 {
+                for i in 0..20 {
+                    single.post(&post(100 + i, 3, 5000));
+                }
+            }
+
+            let current = single.behaviour.period();
+            let values: Vec<Vec<f64>> = current.iter().map(|(_, values)| values.clone()).collect();
+            let population = population(&values);
+            let mut flags = vec![];
+            for (worker, baselines) in workers.iter_mut().enumerate() {
+                let local = current
+                    .iter()
+                    .filter(|(person_id, _)| *person_id as usize % 2 == worker)
+                    .cloned()
+                    .collect();
+                flags.extend(baselines.flag(local, &population));
+            }
+            flags.sort_by_key(|flag| flag.person_id);
+
+            assert_eq!(flags.len(), if time == 5 { 1 } else { 0 });
+            assert_eq!(flags, single.baselines.flag(current, &population));
+        }
+    }
+
+    #[test]
+    fn test_old_posts_are_forgotten() {
+        let mut posts = Posts::new();
+        posts.publish(&post(1, 1, 0));
+        posts.expire(RETENTION + 1);
+
+        assert!(posts.posts.is_empty());
+        assert!(posts.by_time.is_empty());
+    }
+}
@@ -1,17 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::mem::size_of;
 
-#[allow(dead_code)]
-struct Node<K, V> {
+#[derive(Serialize, Deserialize)]
+struct Node<K> {
     parent: usize,
+    rank: usize,
     key: K,
+}
+
+// The members, value and last activity of a set, kept for its root.
+#[derive(Serialize, Deserialize)]
+struct Set<V> {
+    members: Vec<usize>,
     value: V,
+    last_active: Option<usize>,
 }
 
+// A handle to a set, valid until the set is merged into another one or removed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SetId(usize);
+
+// A union-find of keys, with a value for each set. It can be serialized, for instance to
+// checkpoint it.
+#[derive(Serialize, Deserialize)]
 pub struct Dsu<K: Hash + Eq + Clone + Debug, V> {
     to_id: HashMap<K, usize>,
-    data: Vec<Node<K, V>>,
+    data: Vec<Option<Node<K>>>,
+    // the ids of the removed nodes, which are reused
+    free: Vec<usize>,
+    // map: root -> set
+    sets: HashMap<usize, Set<V>>,
+    // map: time -> roots last active at that time; the roots which were active since are
+    // skipped on expiry
+    activity: BTreeMap<usize, Vec<usize>>,
 }
 
 #[allow(dead_code)]
@@ -20,78 +43,215 @@ impl<K: Hash + Eq + Clone + Debug, V> Dsu<K, V> {
         Dsu {
             to_id: HashMap::new(),
             data: vec![],
+            free: vec![],
+            sets: HashMap::new(),
+            activity: BTreeMap::new(),
         }
     }
 
-    fn get(&mut self, id: usize) -> &mut Node<K, V> {
+    fn node(&mut self, id: usize) -> &mut Node<K> {
+        self.data[id].as_mut().unwrap()
+    }
+
+    fn find_root(&mut self, id: usize) -> usize {
         // find root
         let mut root = id;
-
-        while self.data[root].parent != root {
-            root = self.data[root].parent;
+        while self.node(root).parent != root {
+            root = self.node(root).parent;
         }
 
         // compress path
         let mut cur = id;
-        while self.data[cur].parent != root {
-            let next = self.data[cur].parent;
-            self.data[cur].parent = root;
+        while self.node(cur).parent != root {
+            let next = self.node(cur).parent;
+            self.node(cur).parent = root;
             cur = next;
         }
+        root
+    }
 
-        // return relevant node
-        &mut self.data[root]
+    fn root(&mut self, key: &K) -> Option<usize> {
+        let id = *self.to_id.get(key)?;
+        Some(self.find_root(id))
     }
 
+    pub fn contains(&self, key: &K) -> bool {
+        self.to_id.contains_key(key)
+    }
+
+    // The set of `key`.
+    pub fn find(&mut self, key: K) -> Option<SetId> {
+        self.root(&key).map(SetId)
+    }
+
+    // The key of the root of the set of `key`.
     pub fn key(&mut self, key: K) -> Option<&K> {
-        match self.to_id.get(&key) {
-            Some(id) => Some(&self.get(*id).key),
-            None => None,
-        }
+        let root = self.root(&key)?;
+        Some(&self.node(root).key)
     }
 
+    // The value of the set of `key`.
     pub fn value(&mut self, key: K) -> Option<&V> {
-        match self.to_id.get(&key) {
-            Some(id) => Some(&self.get(*id).value),
-            None => None,
-        }
+        let root = self.root(&key)?;
+        Some(&self.sets[&root].value)
     }
 
     pub fn value_mut(&mut self, key: K) -> Option<&mut V> {
-        match self.to_id.get(&key) {
-            Some(id) => Some(&mut self.get(*id).value),
-            None => None,
-        }
+        let root = self.root(&key)?;
+        Some(&mut self.sets.get_mut(&root).unwrap().value)
+    }
+
+    // The number of keys in the set, 0 if the handle is no longer valid.
+    pub fn size(&self, set: SetId) -> usize {
+        self.sets.get(&set.0).map_or(0, |set| set.members.len())
     }
 
+    // The keys in the set, none if the handle is no longer valid.
+    pub fn members<'a>(&'a self, set: SetId) -> impl Iterator<Item = &'a K> + 'a {
+        let data = &self.data;
+        self.sets
+            .get(&set.0)
+            .into_iter()
+            .flat_map(|set| set.members.iter())
+            .map(move |id| &data[*id].as_ref().unwrap().key)
+    }
+
+    // Merges the sets of the two keys, by rank, if both are present. The merged set keeps
+    // the value of the set of `lhs`.
     pub fn union(&mut self, lhs: K, rhs: K) {
-        match self.to_id.get(&lhs) {
-            Some(&lhs) => match self.to_id.get(&rhs) {
-                Some(&rhs) => {
-                    // If both sides are ok, make a union
-                    self.get(rhs).parent = self.get(lhs).parent;
-                }
-                None => {}
+        self.union_with(lhs, rhs, |_, _| {});
+    }
+
+    // Merges the sets of the two keys, by rank, if both are present. The value of the set
+    // of `rhs` is combined into the value of the set of `lhs` by `merge`.
+    pub fn union_with<F: FnOnce(&mut V, V)>(&mut self, lhs: K, rhs: K, merge: F) {
+        let (lhs, rhs) = match (self.root(&lhs), self.root(&rhs)) {
+            (Some(lhs), Some(rhs)) if lhs != rhs => (lhs, rhs),
+            _ => return,
+        };
+        let (root, child) = match self.node(lhs).rank < self.node(rhs).rank {
+            true => (rhs, lhs),
+            false => (lhs, rhs),
+        };
+        self.node(child).parent = root;
+        if self.node(root).rank == self.node(child).rank {
+            self.node(root).rank += 1;
+        }
+
+        let mut lhs_set = self.sets.remove(&lhs).unwrap();
+        let rhs_set = self.sets.remove(&rhs).unwrap();
+        merge(&mut lhs_set.value, rhs_set.value);
+        lhs_set.members.extend(rhs_set.members);
+        lhs_set.last_active = lhs_set.last_active.max(rhs_set.last_active);
+        if let Some(time) = lhs_set.last_active {
+            self.activity.entry(time).or_insert(vec![]).push(root);
+        }
+        self.sets.insert(root, lhs_set);
+    }
+
+    // Inserts the key in a set of its own, unless it is already present, in which case
+    // its set is left as it is. Returns whether the key was inserted.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        if self.to_id.contains_key(&key) {
+            return false;
+        }
+        let id = self.free.pop().unwrap_or(self.data.len());
+        let node = Node {
+            parent: id,
+            rank: 0,
+            key: key.clone(),
+        };
+        match id == self.data.len() {
+            true => self.data.push(Some(node)),
+            false => self.data[id] = Some(node),
+        }
+        self.to_id.insert(key, id);
+        self.sets.insert(
+            id,
+            Set {
+                members: vec![id],
+                value,
+                last_active: None,
             },
-            None => {}
+        );
+        true
+    }
+
+    // Records activity on the set of `key` at `time`. Sets without any activity never
+    // expire.
+    pub fn touch(&mut self, key: K, time: usize) {
+        let root = match self.root(&key) {
+            Some(root) => root,
+            None => return,
+        };
+        let set = self.sets.get_mut(&root).unwrap();
+        if set
+            .last_active
+            .map_or(true, |last_active| last_active < time)
+        {
+            set.last_active = Some(time);
+            self.activity.entry(time).or_insert(vec![]).push(root);
+        }
+    }
+
+    // Removes the whole set of `key`, and returns the number of keys removed.
+    pub fn remove(&mut self, key: K) -> usize {
+        match self.root(&key) {
+            Some(root) => self.remove_set(root),
+            None => 0,
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        let k = key.clone();
-        let id = *self.to_id.entry(key).or_insert(self.data.len());
-
-        if id == self.data.len() {
-            // new value
-            self.data.push(Node {
-                parent: id,
-                key: k,
-                value: value,
-            });
-        } else {
-            // different value
-            self.get(id).value = value;
+    fn remove_set(&mut self, root: usize) -> usize {
+        let set = self.sets.remove(&root).unwrap();
+        for id in set.members.iter() {
+            let node = self.data[*id].take().unwrap();
+            self.to_id.remove(&node.key);
+            self.free.push(*id);
         }
+        set.members.len()
+    }
+
+    // Removes the sets without activity since `time`, and returns the number of keys
+    // removed.
+    pub fn expire(&mut self, time: usize) -> usize {
+        let active = self.activity.split_off(&time);
+        let inactive = std::mem::replace(&mut self.activity, active);
+
+        let mut removed = 0;
+        for (last_active, roots) in inactive {
+            for root in roots {
+                let expired = match self.sets.get(&root) {
+                    Some(set) => set.last_active == Some(last_active),
+                    None => false,
+                };
+                if expired {
+                    removed += self.remove_set(root);
+                }
+            }
+        }
+        removed
+    }
+
+    // The number of keys.
+    pub fn len(&self) -> usize {
+        self.to_id.len()
+    }
+
+    // The number of sets.
+    pub fn sets(&self) -> usize {
+        self.sets.len()
+    }
+
+    // The approximate number of bytes allocated, without the memory the keys and values
+    // allocate themselves.
+    pub fn memory(&self) -> usize {
+        let members: usize = self.sets.values().map(|set| set.members.capacity()).sum();
+        let activity: usize = self.activity.values().map(|roots| roots.len()).sum();
+        self.data.capacity() * size_of::<Option<Node<K>>>()
+            + self.to_id.capacity() * (size_of::<K>() + size_of::<usize>())
+            + self.sets.capacity() * (size_of::<usize>() + size_of::<Set<V>>())
+            + (self.free.capacity() + members + activity) * size_of::<usize>()
     }
 }
 
@@ -122,15 +282,90 @@ mod dsu_tests {
         assert!(dsu.value(2) == Some(&"a"));
         assert!(dsu.value(5) == Some(&"a"));
 
-        dsu.insert(2, "d");
+        // inserting a present key leaves its set as it is
+        assert!(!dsu.insert(2, "d"));
+        assert!(!dsu.insert(5, "e"));
+        assert!(dsu.value(2) == Some(&"a"));
+        assert!(dsu.value(5) == Some(&"a"));
+
+        *dsu.value_mut(5).unwrap() = "d";
         assert!(dsu.value(2) == Some(&"d"));
-        assert!(dsu.value(5) == Some(&"d"));
+        assert!(dsu.value(1) == Some(&"c"));
+    }
 
-        dsu.insert(5, "e");
-        assert!(dsu.value(5) == Some(&"e"));
-        assert!(dsu.value(2) == Some(&"e"));
+    #[test]
+    fn test_union_by_rank_keeps_the_value_of_lhs() {
+        let mut dsu: Dsu<usize, usize> = Dsu::new();
+        for key in 0..4 {
+            dsu.insert(key, key);
+        }
+        dsu.union(0, 1);
+        // the set of 0 is deeper, so it becomes the root, but 2 keeps its value
+        dsu.union(2, 0);
+        assert_eq!(dsu.key(1), Some(&0));
+        assert_eq!(dsu.value(1), Some(&2));
+        assert_eq!(dsu.sets(), 2);
+    }
+
+    #[test]
+    fn test_inactive_sets_expire_as_a_whole() {
+        let mut dsu: Dsu<usize, ()> = Dsu::new();
+        for key in 0..5 {
+            dsu.insert(key, ());
+        }
+        dsu.union(0, 1);
+        dsu.union(0, 2);
+        dsu.union(3, 4);
+        dsu.touch(1, 10);
+        dsu.touch(3, 10);
+        dsu.touch(4, 20);
+
+        assert_eq!(dsu.expire(15), 3);
+        assert_eq!(dsu.len(), 2);
+        assert_eq!(dsu.value(0), None);
+        assert_eq!(dsu.key(3), Some(&3));
+
+        // the removed ids are reused
+        dsu.insert(7, ());
+        dsu.insert(8, ());
+        assert_eq!(dsu.data.len(), 5);
+        assert!(dsu.memory() >= 5 * std::mem::size_of::<usize>());
+        assert_eq!(dsu.remove(4), 2);
+        assert_eq!(dsu.len(), 2);
+    }
+
+    #[test]
+    fn test_sets_are_found_merged_and_listed() {
+        let mut dsu: Dsu<usize, usize> = Dsu::new();
+        for key in 0..5 {
+            dsu.insert(key, 1);
+        }
+        dsu.union_with(0, 1, |lhs, rhs| *lhs += rhs);
+        dsu.union_with(2, 1, |lhs, rhs| *lhs += rhs);
+        assert_eq!(dsu.value(2), Some(&3));
+
+        let set = dsu.find(1).unwrap();
+        assert_eq!(dsu.find(0), Some(set));
+        assert_ne!(dsu.find(3), Some(set));
+        assert_eq!(dsu.find(7), None);
+        assert_eq!(dsu.size(set), 3);
+        let mut members: Vec<usize> = dsu.members(set).cloned().collect();
+        members.sort();
+        assert_eq!(members, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_dsu_can_be_checkpointed() {
+        let mut dsu: Dsu<String, u32> = Dsu::new();
+        dsu.insert("a".to_string(), 1);
+        dsu.insert("b".to_string(), 2);
+        dsu.union("a".to_string(), "b".to_string());
+        dsu.touch("a".to_string(), 10);
 
-        dsu.insert(1, "d");
-        assert!(dsu.value(1) == Some(&"d"));
+        let bytes = bincode::serialize(&dsu).unwrap();
+        let mut restored: Dsu<String, u32> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(restored.value("b".to_string()), Some(&1));
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.expire(11), 2);
     }
 }
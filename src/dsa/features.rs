@@ -0,0 +1,500 @@
+use crate::dsa::kmeans::Point;
+use crate::dto::post::Post;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::str::FromStr;
+
+const MAX_POST_LENGTH: usize = 64;
+const MAX_LINKS: usize = 4;
+const NGRAM_LENGTH: usize = 3;
+const TF_IDF_DIMENSIONS: usize = 8;
+// number of stop words a post needs for its language to be detected
+const MIN_LANGUAGE_EVIDENCE: usize = 2;
+
+const STOP_WORDS: [(&str, &[&str]); 5] = [
+    (
+        "en",
+        &[
+            "the", "and", "a", "of", "to", "in", "is", "it", "that", "for", "on", "with", "as",
+            "was", "he", "she", "you", "i", "this", "are", "be", "at", "by", "not", "or", "have",
+            "from", "his", "her", "they", "we", "an", "but", "which",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "de", "que", "y", "en", "los", "se", "del", "las", "un", "por", "con",
+            "no", "una", "su", "para", "es", "al", "lo", "como", "pero", "sus", "le",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "in", "den", "von", "zu", "das", "mit", "sich", "des", "auf",
+            "ist", "im", "dem", "nicht", "ein", "eine", "als", "auch", "es", "an", "er", "hat",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "de", "la", "et", "les", "des", "en", "un", "du", "une", "que", "est", "pour",
+            "qui", "dans", "par", "sur", "au", "il", "ne", "pas", "avec", "se", "plus", "son",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "de", "a", "e", "do", "da", "em", "que", "um", "para", "com", "uma", "os", "no",
+            "se", "na", "por", "mais", "as", "dos", "como", "mas", "ao", "ele",
+        ],
+    ),
+];
+
+fn ratio(count: usize, max: usize) -> f64 {
+    if count > max {
+        1.
+    } else {
+        count as f64 / max as f64
+    }
+}
+
+// A post split into the tokens the features are computed on.
+pub struct Text<'a> {
+    pub post: &'a Post,
+    // whitespace separated tokens of the content
+    pub tokens: Vec<&'a str>,
+    // lower case alphabetic words of the content
+    pub words: Vec<String>,
+}
+
+impl<'a> Text<'a> {
+    pub fn new(post: &'a Post) -> Text<'a> {
+        let mut alpha_text = post.content.clone();
+
+        // remove punctuation
+        for sep in "?!.,;:".chars() {
+            alpha_text = alpha_text.replace(sep, " ");
+        }
+
+        // keep only alpha text
+        alpha_text.retain(|c| c.is_alphabetic() || c.is_whitespace());
+        let words = alpha_text
+            .split_whitespace()
+            .map(|x| x.to_lowercase())
+            .collect();
+
+        Text {
+            post,
+            tokens: post.content.split_whitespace().collect(),
+            words,
+        }
+    }
+}
+
+// A feature of the posts, which can span several dimensions of the points.
+pub trait Feature {
+    // The name of each dimension, used to label the plots.
+    fn names(&self) -> Vec<String>;
+    // The value of each dimension, between 0 and 1.
+    fn extract(&mut self, text: &Text) -> Vec<f64>;
+}
+
+// Number of unique words and bigrams.
+pub struct Lengths;
+
+impl Feature for Lengths {
+    fn names(&self) -> Vec<String> {
+        vec!["unique words".to_string(), "unique bigrams".to_string()]
+    }
+
+    fn extract(&mut self, text: &Text) -> Vec<f64> {
+        let unique_words: HashSet<_> = HashSet::from_iter(text.words.iter());
+        let unique_bigrams: HashSet<_> = HashSet::from_iter(text.words.windows(2));
+
+        vec![
+            ratio(unique_words.len(), MAX_POST_LENGTH),
+            ratio(unique_bigrams.len(), MAX_POST_LENGTH),
+        ]
+    }
+}
+
+// TF-IDF vector of the words, hashed into a fixed number of dimensions. The document
+// frequencies are learned from the posts seen so far.
+pub struct TfIdf {
+    dimensions: usize,
+    documents: usize,
+    frequencies: HashMap<String, usize>,
+}
+
+impl TfIdf {
+    pub fn new(dimensions: usize) -> TfIdf {
+        TfIdf {
+            dimensions,
+            documents: 0,
+            frequencies: HashMap::new(),
+        }
+    }
+
+    fn dimension(&self, word: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        word.hash(&mut hasher);
+        hasher.finish() as usize % self.dimensions
+    }
+}
+
+impl Feature for TfIdf {
+    fn names(&self) -> Vec<String> {
+        (0..self.dimensions)
+            .map(|i| format!("tf-idf {}", i))
+            .collect()
+    }
+
+    fn extract(&mut self, text: &Text) -> Vec<f64> {
+        let mut counts: HashMap<&String, usize> = HashMap::new();
+        for word in text.words.iter() {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+
+        self.documents += 1;
+        for word in counts.keys() {
+            *self.frequencies.entry((*word).clone()).or_insert(0) += 1;
+        }
+
+        let mut vector = vec![0.; self.dimensions];
+        for (word, count) in counts {
+            let tf = count as f64 / text.words.len() as f64;
+            let idf = ((1 + self.documents) as f64 / (1 + self.frequencies[word]) as f64).ln() + 1.;
+            vector[self.dimension(word)] += tf * idf;
+        }
+
+        // normalise, so that the values are independent of the length of the post
+        let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm > 0. {
+            for x in vector.iter_mut() {
+                *x /= norm;
+            }
+        }
+        vector
+    }
+}
+
+// Shannon entropy of the character n-grams, relative to its maximum. Generated and
+// repeated text has a lower entropy than natural text.
+pub struct NgramEntropy;
+
+impl Feature for NgramEntropy {
+    fn names(&self) -> Vec<String> {
+        vec!["char n-gram entropy".to_string()]
+    }
+
+    fn extract(&mut self, text: &Text) -> Vec<f64> {
+        let chars: Vec<char> = text.words.join(" ").chars().collect();
+        let mut counts: HashMap<&[char], usize> = HashMap::new();
+        for ngram in chars.windows(NGRAM_LENGTH) {
+            *counts.entry(ngram).or_insert(0) += 1;
+        }
+
+        let total: usize = counts.values().sum();
+        if total < 2 {
+            return vec![0.];
+        }
+        let entropy: f64 = counts
+            .values()
+            .map(|count| {
+                let p = *count as f64 / total as f64;
+                -p * p.log2()
+            })
+            .sum();
+        vec![entropy / (total as f64).log2()]
+    }
+}
+
+// Ratio of the words that are stop words, in any of the known languages.
+pub struct StopWords {
+    stop_words: HashSet<&'static str>,
+}
+
+impl StopWords {
+    pub fn new() -> StopWords {
+        StopWords {
+            stop_words: STOP_WORDS
+                .iter()
+                .flat_map(|(_, words)| words.iter().cloned())
+                .collect(),
+        }
+    }
+}
+
+impl Feature for StopWords {
+    fn names(&self) -> Vec<String> {
+        vec!["stop-word ratio".to_string()]
+    }
+
+    fn extract(&mut self, text: &Text) -> Vec<f64> {
+        let stop_words = text
+            .words
+            .iter()
+            .filter(|word| self.stop_words.contains(word.as_str()))
+            .count();
+        vec![stop_words as f64 / text.words.len() as f64]
+    }
+}
+
+// Number of URLs and of mentions of other people.
+pub struct Links;
+
+impl Feature for Links {
+    fn names(&self) -> Vec<String> {
+        vec!["urls".to_string(), "mentions".to_string()]
+    }
+
+    fn extract(&mut self, text: &Text) -> Vec<f64> {
+        let urls = text
+            .tokens
+            .iter()
+            .filter(|token| {
+                token.starts_with("http://")
+                    || token.starts_with("https://")
+                    || token.starts_with("www.")
+            })
+            .count();
+        let mentions = text
+            .tokens
+            .iter()
+            .filter(|token| token.len() > 1 && token.starts_with('@'))
+            .count();
+
+        vec![ratio(urls, MAX_LINKS), ratio(mentions, MAX_LINKS)]
+    }
+}
+
+// Whether the language detected from the stop words differs from the language of the
+// post. Posts in languages that cannot be detected never mismatch.
+pub struct Language;
+
+impl Language {
+    fn detect(text: &Text) -> Option<&'static str> {
+        STOP_WORDS
+            .iter()
+            .map(|(language, stop_words)| {
+                let evidence = text
+                    .words
+                    .iter()
+                    .filter(|word| stop_words.contains(&word.as_str()))
+                    .count();
+                (evidence, *language)
+            })
+            .filter(|(evidence, _)| *evidence >= MIN_LANGUAGE_EVIDENCE)
+            .max()
+            .map(|(_, language)| language)
+    }
+}
+
+impl Feature for Language {
+    fn names(&self) -> Vec<String> {
+        vec!["language mismatch".to_string()]
+    }
+
+    fn extract(&mut self, text: &Text) -> Vec<f64> {
+        let known = STOP_WORDS
+            .iter()
+            .any(|(language, _)| *language == text.post.language);
+        match Language::detect(text) {
+            Some(language) if known && language != text.post.language => vec![1.],
+            _ => vec![0.],
+        }
+    }
+}
+
+// Ratio of the words that repeat an earlier word of the post.
+pub struct Repetition;
+
+impl Feature for Repetition {
+    fn names(&self) -> Vec<String> {
+        vec!["repetition".to_string()]
+    }
+
+    fn extract(&mut self, text: &Text) -> Vec<f64> {
+        let unique_words: HashSet<_> = HashSet::from_iter(text.words.iter());
+        vec![1. - unique_words.len() as f64 / text.words.len() as f64]
+    }
+}
+
+// The features that can be selected for the unusual activity task.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeatureKind {
+    Lengths,
+    TfIdf,
+    Entropy,
+    StopWords,
+    Links,
+    Language,
+    Repetition,
+}
+
+impl FeatureKind {
+    pub fn feature(&self) -> Box<dyn Feature> {
+        match self {
+            FeatureKind::Lengths => Box::new(Lengths),
+            FeatureKind::TfIdf => Box::new(TfIdf::new(TF_IDF_DIMENSIONS)),
+            FeatureKind::Entropy => Box::new(NgramEntropy),
+            FeatureKind::StopWords => Box::new(StopWords::new()),
+            FeatureKind::Links => Box::new(Links),
+            FeatureKind::Language => Box::new(Language),
+            FeatureKind::Repetition => Box::new(Repetition),
+        }
+    }
+}
+
+impl FromStr for FeatureKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lengths" => Ok(FeatureKind::Lengths),
+            "tf-idf" => Ok(FeatureKind::TfIdf),
+            "entropy" => Ok(FeatureKind::Entropy),
+            "stop-words" => Ok(FeatureKind::StopWords),
+            "links" => Ok(FeatureKind::Links),
+            "language" => Ok(FeatureKind::Language),
+            "repetition" => Ok(FeatureKind::Repetition),
+            _ => Err("Unrecognised feature"),
+        }
+    }
+}
+
+// Turns posts into points by concatenating the values of the selected features.
+pub struct FeatureExtractor {
+    features: Vec<Box<dyn Feature>>,
+}
+
+impl FeatureExtractor {
+    pub fn new(kinds: &[FeatureKind]) -> FeatureExtractor {
+        FeatureExtractor {
+            features: kinds.iter().map(|kind| kind.feature()).collect(),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.features
+            .iter()
+            .flat_map(|feature| feature.names())
+            .collect()
+    }
+
+    // Posts with less than two words are too short to tell anything from.
+    pub fn extract(&mut self, post: &Post) -> Option<Point> {
+        let text = Text::new(post);
+        if text.words.len() < 2 {
+            return None;
+        }
+
+        Some(
+            self.features
+                .iter_mut()
+                .flat_map(|feature| feature.extract(&text))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod features_tests {
+    use crate::dsa::features::*;
+    use crate::dto::common::Watermarkable;
+
+    fn post(content: &str, language: &str) -> Post {
+        Post {
+            content: content.to_string(),
+            language: language.to_string(),
+            is_watermark: false,
+            ..Post::from_watermark("0", 0)
+        }
+    }
+
+    fn extract(kind: FeatureKind, content: &str) -> Vec<f64> {
+        let post = post(content, "en");
+        kind.feature().extract(&Text::new(&post))
+    }
+
+    fn all() -> Vec<FeatureKind> {
+        vec![
+            FeatureKind::Lengths,
+            FeatureKind::TfIdf,
+            FeatureKind::Entropy,
+            FeatureKind::StopWords,
+            FeatureKind::Links,
+            FeatureKind::Language,
+            FeatureKind::Repetition,
+        ]
+    }
+
+    #[test]
+    fn test_lengths_count_the_last_bigram() {
+        // the bigrams are "a b", "b c" and "c d"
+        assert_eq!(
+            extract(FeatureKind::Lengths, "a b c d"),
+            vec![4. / 64., 3. / 64.]
+        );
+    }
+
+    #[test]
+    fn test_repeated_text_is_detected() {
+        let natural = "the quick brown fox jumps over the lazy dog";
+        let spam = "buy now buy now buy now buy now buy now";
+
+        assert!(extract(FeatureKind::Repetition, spam)[0] > 0.8);
+        assert!(extract(FeatureKind::Repetition, natural)[0] < 0.2);
+        assert!(extract(FeatureKind::Entropy, spam)[0] < extract(FeatureKind::Entropy, natural)[0]);
+    }
+
+    #[test]
+    fn test_links_and_mentions_are_counted() {
+        assert_eq!(
+            extract(
+                FeatureKind::Links,
+                "see http://a.com and www.b.com, cc @bob @alice @"
+            ),
+            vec![0.5, 0.5]
+        );
+    }
+
+    #[test]
+    fn test_language_mismatch_is_detected() {
+        let spanish = post("el perro y la casa de los amigos", "en");
+        let english = post("the dog and the house of the friends", "en");
+        let unknown = post("el perro y la casa de los amigos", "uz");
+
+        for (post, mismatch) in vec![(spanish, 1.), (english, 0.), (unknown, 0.)] {
+            assert_eq!(Language.extract(&Text::new(&post)), vec![mismatch]);
+        }
+    }
+
+    #[test]
+    fn test_points_concatenate_the_features() {
+        let mut extractor = FeatureExtractor::new(&all());
+        let point = extractor
+            .extract(&post("the dog and the cat", "en"))
+            .unwrap();
+
+        assert_eq!(point.len(), extractor.names().len());
+        assert!(point.iter().all(|x| *x >= 0. && *x <= 1.));
+        assert_eq!(extractor.extract(&post("dog", "en")), None);
+    }
+
+    #[test]
+    fn test_tf_idf_weighs_down_common_words() {
+        let mut tf_idf = TfIdf::new(1024);
+        for _ in 0..10 {
+            tf_idf.extract(&Text::new(&post("common words", "en")));
+        }
+        let vector = tf_idf.extract(&Text::new(&post("common rare", "en")));
+
+        let common = vector[tf_idf.dimension("common")];
+        let rare = vector[tf_idf.dimension("rare")];
+        assert!(rare > common);
+    }
+}
@@ -1,220 +1,448 @@
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::cmp::Ordering::Equal;
 use std::f64;
 
-pub type Point = (f64, f64);
+// Points have as many dimensions as the features they are made of. K-means takes any type
+// of point that can be read as a slice of coordinates, but the centers it computes are
+// always owned points.
+pub type Point = Vec<f64>;
 pub const EPS: f64 = 0.00001;
 pub const SQR_DIST_FACTOR: f64 = 2.;
+// bounds the refinement of the centers when they do not converge
+pub const MAX_ITERATIONS: usize = 100;
 
-pub fn sqr_dist((x, y): &Point, (x2, y2): &Point) -> f64 {
-    (x - x2) * (x - x2) + (y - y2) * (y - y2)
+pub fn sqr_dist(point: &[f64], other: &[f64]) -> f64 {
+    point
+        .iter()
+        .zip(other.iter())
+        .map(|(x, x2)| (x - x2) * (x - x2))
+        .sum()
 }
 
-pub fn compute_clusters(centers: &Vec<Point>, points: &Vec<Point>) -> Vec<Vec<Point>> {
+// The points closest to each center. Only the tests look at the clusters themselves.
+#[cfg(test)]
+pub fn compute_clusters(centers: &[Point], points: &[Point]) -> Vec<Vec<Point>> {
     let mut clusters = Vec::new();
     for _ in 0..centers.len() {
         clusters.push(vec![]);
     }
     for point in points {
-        let mut i_closest = 0;
-        for (i, center) in centers.iter().enumerate() {
-            if sqr_dist(&center, &point) < sqr_dist(&centers[i_closest], &point) {
-                i_closest = i;
-            }
-        }
-
-        clusters[i_closest].push(*point);
+        clusters[closest(centers, point)].push(point.clone());
     }
     clusters
 }
 
-pub fn compute_centers(
-    old_centers: &Vec<Point>,
-    points: &Vec<Point>,
+// The index of the center closest to the point.
+pub fn closest(centers: &[Point], point: &[f64]) -> usize {
+    let mut i_closest = 0;
+    for (i, center) in centers.iter().enumerate() {
+        if sqr_dist(center, point) < sqr_dist(&centers[i_closest], point) {
+            i_closest = i;
+        }
+    }
+    i_closest
+}
+
+// K-means over points of any dimension. The missing centers are seeded with k-means++ and
+// the centers are refined either with Lloyd's iterations or with mini-batch updates.
+pub struct KMeans {
     num_clusters: usize,
     min_coverage: usize,
-) -> Vec<Point> {
-    let mut centers = vec![];
-    for center in old_centers.iter() {
-        centers.push(*center);
-    }
-
-    // add new random centers
-    while centers.len() < num_clusters {
-        centers.push((
-            rand::thread_rng().gen_range(0., 1.),
-            rand::thread_rng().gen_range(0., 1.),
-        ));
-    }
-
-    loop {
-        let clusters = compute_clusters(&centers, &points);
-
-        let mut new_centers = vec![];
-        for cluster in clusters {
-            let mut x_c = 0f64;
-            let mut y_c = 0f64;
-            for (x, y) in cluster.iter() {
-                x_c += x;
-                y_c += y;
+    max_iterations: usize,
+    batch_size: Option<usize>,
+    rng: StdRng,
+}
+
+impl KMeans {
+    pub fn new(num_clusters: usize, min_coverage: usize, seed: u64) -> KMeans {
+        KMeans {
+            num_clusters,
+            min_coverage,
+            max_iterations: MAX_ITERATIONS,
+            batch_size: None,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn num_clusters(&self) -> usize {
+        self.num_clusters
+    }
+
+    pub fn max_iterations(mut self, max_iterations: usize) -> KMeans {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    // Refines the centers with random batches of `batch_size` points instead of all the
+    // points, which is much cheaper for large sets of points.
+    pub fn mini_batch(mut self, batch_size: usize) -> KMeans {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    // Adds centers chosen with probability proportional to their weighted squared distance
+    // from the closest center, until there are `num_clusters` of them.
+    fn seed_centers<P: AsRef<[f64]>>(
+        &mut self,
+        centers: &mut Vec<Point>,
+        points: &[P],
+        weights: &[f64],
+    ) {
+        while centers.len() < self.num_clusters {
+            let dist: Vec<f64> = points
+                .iter()
+                .zip(weights.iter())
+                .map(|(point, weight)| {
+                    let min_dist = centers
+                        .iter()
+                        .map(|center| sqr_dist(center, point.as_ref()))
+                        .fold(f64::INFINITY, f64::min);
+                    min_dist * weight
+                })
+                .collect();
+            let total: f64 = dist.iter().filter(|d| d.is_finite()).sum();
+
+            let chosen = if centers.is_empty() || total <= 0. {
+                // every point is already a center, so any of them will do
+                self.rng.gen_range(0, points.len())
+            } else {
+                let mut target = self.rng.gen_range(0., total);
+                let mut chosen = points.len() - 1;
+                for (i, d) in dist.iter().enumerate() {
+                    if target < *d {
+                        chosen = i;
+                        break;
+                    }
+                    target -= d;
+                }
+                chosen
+            };
+            centers.push(points[chosen].as_ref().to_vec());
+        }
+    }
+
+    // One iteration of Lloyd's algorithm. The center of an empty cluster is moved to the
+    // point farthest from its center, which splits the worst fitting cluster.
+    fn lloyd<P: AsRef<[f64]>>(
+        &self,
+        centers: &[Point],
+        points: &[P],
+        weights: &[f64],
+    ) -> Vec<Point> {
+        let dimensions = points[0].as_ref().len();
+        let mut sums = vec![vec![0f64; dimensions]; centers.len()];
+        let mut totals = vec![0f64; centers.len()];
+        for (point, weight) in points.iter().zip(weights.iter()) {
+            let i = closest(centers, point.as_ref());
+            totals[i] += weight;
+            for (s, x) in sums[i].iter_mut().zip(point.as_ref().iter()) {
+                *s += weight * x;
             }
-            x_c /= cluster.len() as f64;
-            y_c /= cluster.len() as f64;
-            new_centers.push((x_c, y_c));
         }
 
-        let mut tot_diff = 0f64;
-        for i in 0..new_centers.len() {
-            tot_diff += sqr_dist(&new_centers[i], &centers[i]);
+        let mut new_centers: Vec<Point> = centers.to_vec();
+        for i in 0..centers.len() {
+            if totals[i] > 0. {
+                new_centers[i] = sums[i].iter().map(|s| s / totals[i]).collect();
+            }
         }
-        if tot_diff < EPS {
-            break;
+
+        for i in 0..centers.len() {
+            if totals[i] > 0. {
+                continue;
+            }
+            let farthest = points.iter().map(|point| point.as_ref()).max_by(|a, b| {
+                let dist_a = sqr_dist(&new_centers[closest(&new_centers, a)], a);
+                let dist_b = sqr_dist(&new_centers[closest(&new_centers, b)], b);
+                dist_a.partial_cmp(&dist_b).unwrap_or(Equal)
+            });
+            if let Some(farthest) = farthest {
+                new_centers[i] = farthest.to_vec();
+            }
         }
+        new_centers
+    }
 
-        centers = new_centers;
-        centers.retain(|(x, y)| !x.is_nan() && !y.is_nan());
+    // One mini-batch update: each center moves towards the sampled points closest to it,
+    // with a learning rate that decreases with the weight of the points it has seen.
+    fn mini_batch_step<P: AsRef<[f64]>>(
+        &mut self,
+        centers: &[Point],
+        points: &[P],
+        weights: &[f64],
+        batch_size: usize,
+        counts: &mut Vec<f64>,
+    ) -> Vec<Point> {
+        let mut new_centers = centers.to_vec();
+        for _ in 0..batch_size {
+            let sample = self.rng.gen_range(0, points.len());
+            let (point, weight) = (points[sample].as_ref(), weights[sample]);
+            let i = closest(&new_centers, point);
+            counts[i] += weight;
+            let rate = weight / counts[i];
+            for (c, x) in new_centers[i].iter_mut().zip(point.iter()) {
+                *c = (1. - rate) * *c + rate * x;
+            }
+        }
+        new_centers
     }
 
-    // sort centers by coverage
-    let mut ord: Vec<usize> = (0..centers.len()).collect();
-    let clusters = compute_clusters(&centers, &points);
-    ord.sort_by(|a, b| clusters[*a].len().cmp(&clusters[*b].len()));
+    // Refines the old centers, together with newly seeded ones, on the points and keeps
+    // the ones covering at least `min_coverage` points.
+    pub fn fit<P: AsRef<[f64]>>(&mut self, old_centers: &[Point], points: &[P]) -> Vec<Point> {
+        self.fit_weighted(old_centers, points, &vec![1.; points.len()])
+    }
 
-    // remove clusters with small coverage
-    let mut relevant_centers = vec![];
-    for i in 0..centers.len() {
-        if clusters[i].len() >= min_coverage {
-            relevant_centers.push(centers[i]);
+    // The same as `fit`, but each point stands for `weight` points.
+    pub fn fit_weighted<P: AsRef<[f64]>>(
+        &mut self,
+        old_centers: &[Point],
+        points: &[P],
+        weights: &[f64],
+    ) -> Vec<Point> {
+        if points.is_empty() {
+            return vec![];
         }
-    }
 
-    relevant_centers
-}
+        let mut centers = old_centers.to_vec();
+        centers.truncate(self.num_clusters);
+        self.seed_centers(&mut centers, points, weights);
 
-pub fn compute_outliers(centers: &Vec<Point>, points: &Vec<Point>, percentile: f64) -> Vec<Point> {
-    // calculate distances to closest cluster
-    let mut dist = vec![];
-    for point in points.iter() {
-        let mut min_dist = f64::INFINITY;
-        for center in centers.iter() {
-            if sqr_dist(center, point) < min_dist {
-                min_dist = sqr_dist(center, point);
+        let mut counts = vec![0.; centers.len()];
+        for _ in 0..self.max_iterations {
+            let new_centers = match self.batch_size {
+                Some(batch_size) => {
+                    self.mini_batch_step(&centers, points, weights, batch_size, &mut counts)
+                }
+                None => self.lloyd(&centers, points, weights),
+            };
+
+            let mut tot_diff = 0f64;
+            for i in 0..new_centers.len() {
+                tot_diff += sqr_dist(&new_centers[i], &centers[i]);
+            }
+            centers = new_centers;
+            if tot_diff < EPS {
+                break;
+            }
+        }
+
+        // remove clusters with small coverage
+        let mut coverage = vec![0.; centers.len()];
+        for (point, weight) in points.iter().zip(weights.iter()) {
+            coverage[closest(&centers, point.as_ref())] += weight;
+        }
+        let mut relevant_centers = vec![];
+        for i in 0..centers.len() {
+            if coverage[i] >= self.min_coverage as f64 {
+                relevant_centers.push(centers[i].clone());
             }
         }
-        dist.push(min_dist);
+
+        relevant_centers
+    }
+}
+
+// The squared distance from the point to the closest center.
+pub fn min_dist(centers: &[Point], point: &[f64]) -> f64 {
+    let mut min_dist = f64::INFINITY;
+    for center in centers.iter() {
+        if sqr_dist(center, point) < min_dist {
+            min_dist = sqr_dist(center, point);
+        }
     }
+    min_dist
+}
+
+// The minimum squared distance to the closest center for a point to be an outlier. It is
+// the first gap above the percentile that is much larger than the previous one.
+pub fn outlier_distance(centers: &[Point], points: &[Point], percentile: f64) -> f64 {
+    // calculate distances to closest cluster
+    let mut dist: Vec<f64> = points
+        .iter()
+        .map(|point| min_dist(centers, point))
+        .collect();
 
     // compute percentile
     dist.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
     let pct = (percentile * dist.len() as f64) as usize;
 
     // find minimum distance for an outlier
-    let mut dist_outlier = f64::INFINITY;
-    for i in pct..dist.len() - 1 {
+    for i in pct.max(1)..dist.len().max(1) - 1 {
         if dist[i + 1] - dist[i] > (dist[i] - dist[i - 1]) * SQR_DIST_FACTOR {
-            dist_outlier = dist[i + 1];
-            break;
-        }
-    }
-
-    // find outliers
-    let mut outliers = vec![];
-    for point in points.iter() {
-        let mut min_dist = f64::INFINITY;
-        for center in centers.iter() {
-            if sqr_dist(center, point) < min_dist {
-                min_dist = sqr_dist(center, point);
-            }
-        }
-
-        if min_dist >= dist_outlier {
-            outliers.push(*point);
+            return dist[i + 1];
         }
     }
-
-    outliers
+    f64::INFINITY
 }
 
 #[cfg(test)]
 mod kmeans_tests {
     use crate::dsa::kmeans::*;
 
+    const SEED: u64 = 42;
+
+    fn squares() -> Vec<Point> {
+        vec![
+            vec![0., 0.],
+            vec![1., 0.],
+            vec![0., 1.],
+            vec![1., 1.],
+            vec![5., 5.],
+            vec![5., 6.],
+            vec![6., 5.],
+            vec![6., 6.],
+        ]
+    }
+
+    fn sorted(mut centers: Vec<Point>) -> Vec<Point> {
+        centers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
+        centers
+    }
+
     #[test]
     fn test_custers_computed_correctly() {
         let points = vec![
-            (0., 0.),
-            (1., 0.),
-            (0., 1.),
-            (1., 1.),
-            (5., 5.),
-            (5., 6.),
-            (6., 5.),
-            (6., 6.),
+            vec![0., 0.],
+            vec![1., 0.],
+            vec![0., 1.],
+            vec![1., 1.],
+            vec![5., 5.],
+            vec![5., 6.],
+            vec![6., 5.],
+            vec![6., 6.],
         ];
 
-        let centers = vec![(0.5, 0.5), (5.5, 5.5)];
+        let centers = vec![vec![0.5, 0.5], vec![5.5, 5.5]];
 
         assert_eq!(
             compute_clusters(&centers, &points)[0],
-            vec![(0., 0.), (1., 0.), (0., 1.), (1., 1.),]
+            vec![vec![0., 0.], vec![1., 0.], vec![0., 1.], vec![1., 1.],]
         );
         assert_eq!(
             compute_clusters(&centers, &points)[1],
-            vec![(5., 5.), (5., 6.), (6., 5.), (6., 6.),]
+            vec![vec![5., 5.], vec![5., 6.], vec![6., 5.], vec![6., 6.],]
         );
     }
 
     #[test]
     fn test_centers_computed_correctly() {
         let points = vec![
-            (0., 0.),
-            (1., 0.),
-            (0., 1.),
-            (1., 1.),
-            (5., 5.),
-            (5., 6.),
-            (6., 5.),
-            (6., 6.),
+            vec![0., 0.],
+            vec![1., 0.],
+            vec![0., 1.],
+            vec![1., 1.],
+            vec![5., 5.],
+            vec![5., 6.],
+            vec![6., 5.],
+            vec![6., 6.],
         ];
-        let mut centers = compute_centers(&vec![], &points, 2, 4);
+        let mut centers = KMeans::new(2, 4, SEED).fit(&[], &points);
         centers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
 
-        assert_eq!(centers, vec![(0.5, 0.5), (5.5, 5.5),],);
+        assert_eq!(centers, vec![vec![0.5, 0.5], vec![5.5, 5.5],],);
     }
 
     #[test]
     fn test_centers_without_coverage_eliminated() {
         let points = vec![
-            (0., 0.),
-            (1., 0.),
-            (0., 1.),
-            (1., 1.),
-            (5., 5.),
-            (5., 6.),
-            (6., 5.),
-            (6., 6.),
+            vec![0., 0.],
+            vec![1., 0.],
+            vec![0., 1.],
+            vec![1., 1.],
+            vec![5., 5.],
+            vec![5., 6.],
+            vec![6., 5.],
+            vec![6., 6.],
         ];
 
-        assert_eq!(compute_centers(&vec![], &points, 2, 5), vec![]);
+        assert_eq!(
+            KMeans::new(2, 5, SEED).fit(&[], &points),
+            Vec::<Point>::new()
+        );
     }
 
     #[test]
     fn test_outliers_computed_correctly() {
         let points = vec![
-            (0., 0.),
-            (1., 0.),
-            (0., 1.),
-            (1., 1.),
-            (5., 5.),
-            (5., 6.),
-            (6., 5.),
-            (6., 6.),
-            (7.5, 7.5),
-            (-10., -2.),
+            vec![0., 0.],
+            vec![1., 0.],
+            vec![0., 1.],
+            vec![1., 1.],
+            vec![5., 5.],
+            vec![5., 6.],
+            vec![6., 5.],
+            vec![6., 6.],
+            vec![7.5, 7.5],
+            vec![-10., -2.],
         ];
 
-        let centers = vec![(0.5, 0.5), (5.5, 5.5)];
+        let centers = vec![vec![0.5, 0.5], vec![5.5, 5.5]];
 
-        assert_eq!(compute_outliers(&centers, &points, 0.8), vec![(-10., -2.),]);
+        let dist_outlier = outlier_distance(&centers, &points, 0.8);
+        let outliers: Vec<&Point> = points
+            .iter()
+            .filter(|point| min_dist(&centers, point) >= dist_outlier)
+            .collect();
+        assert_eq!(outliers, vec![&vec![-10., -2.]]);
+    }
+
+    #[test]
+    fn test_seeded_centers_are_reproducible() {
+        let points: Vec<Point> = (0..100)
+            .map(|i| vec![(i % 10) as f64, (i / 10) as f64, (i % 7) as f64])
+            .collect();
+
+        let centers = KMeans::new(4, 1, SEED).fit(&[], &points);
+        assert_eq!(centers.len(), 4);
+        assert!(centers.iter().all(|center| center.len() == 3));
+        assert_eq!(centers, KMeans::new(4, 1, SEED).fit(&[], &points));
+    }
+
+    #[test]
+    fn test_empty_clusters_are_moved_to_the_points() {
+        // no point is closest to the second center
+        let old_centers = vec![vec![0.5, 0.5], vec![100., 100.]];
+
+        assert_eq!(
+            sorted(KMeans::new(2, 4, SEED).fit(&old_centers, &squares())),
+            vec![vec![0.5, 0.5], vec![5.5, 5.5]]
+        );
+    }
+
+    #[test]
+    fn test_iterations_are_capped() {
+        let old_centers = vec![vec![0., 0.], vec![1., 0.]];
+
+        // a single iteration only moves the centers to the means of their first clusters
+        assert_eq!(
+            sorted(
+                KMeans::new(2, 1, SEED)
+                    .max_iterations(1)
+                    .fit(&old_centers, &squares())
+            ),
+            vec![vec![0., 0.5], vec![4., 23. / 6.]]
+        );
+    }
+
+    #[test]
+    fn test_centers_of_borrowed_points() {
+        let points: Vec<[f64; 2]> = squares().iter().map(|point| [point[0], point[1]]).collect();
+
+        assert_eq!(
+            sorted(KMeans::new(2, 4, SEED).fit(&[], &points)),
+            sorted(KMeans::new(2, 4, SEED).fit(&[], &squares()))
+        );
+    }
+
+    #[test]
+    fn test_mini_batch_centers_are_close_to_the_means() {
+        let centers = sorted(KMeans::new(2, 4, SEED).mini_batch(4).fit(&[], &squares()));
+
+        assert_eq!(centers.len(), 2);
+        for (center, mean) in centers.iter().zip(vec![vec![0.5, 0.5], vec![5.5, 5.5]]) {
+            assert!(sqr_dist(center, &mean) < 0.5, "{:?}", center);
+        }
     }
 }
@@ -1,3 +1,11 @@
+pub mod anomaly;
+pub mod behaviour;
 pub mod dsu;
+pub mod features;
 pub mod kmeans;
+pub mod online_kmeans;
+pub mod pca;
+pub mod pending;
 pub mod stash;
+pub mod thread;
+pub mod watermarks;
@@ -0,0 +1,207 @@
+use crate::dsa::kmeans::*;
+
+// Sufficient statistics of a set of points: their number and their sum. Statistics of
+// different sets are merged by adding them up.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Statistics {
+    pub count: f64,
+    pub sum: Point,
+}
+
+impl Statistics {
+    pub fn new(point: &Point) -> Statistics {
+        Statistics {
+            count: 1.,
+            sum: point.clone(),
+        }
+    }
+
+    pub fn add(&mut self, point: &Point) {
+        self.count += 1.;
+        for (s, x) in self.sum.iter_mut().zip(point.iter()) {
+            *s += x;
+        }
+    }
+
+    pub fn mean(&self) -> Point {
+        self.sum.iter().map(|s| s / self.count).collect()
+    }
+}
+
+// Summarises the points as the statistics of their clusters, which are much fewer than
+// the points themselves.
+pub fn summarise(kmeans: &mut KMeans, points: &[Point]) -> Vec<Statistics> {
+    let centers = kmeans.fit(&[], points);
+    let mut statistics: Vec<Option<Statistics>> = vec![None; centers.len()];
+    for point in points.iter() {
+        match &mut statistics[closest(&centers, point)] {
+            Some(cluster) => cluster.add(point),
+            cluster => *cluster = Some(Statistics::new(point)),
+        }
+    }
+    statistics
+        .into_iter()
+        .filter_map(|cluster| cluster)
+        .collect()
+}
+
+// Sequential k-means with decay. Every update fades the weights of the centers, so that
+// old points are forgotten, and moves the closest center towards each new set of points.
+// The centers are refitted on a bounded window of recent statistics when some of them
+// fade away.
+pub struct OnlineKMeans {
+    kmeans: KMeans,
+    decay: f64,
+    min_weight: f64,
+    centers: Vec<Point>,
+    // the decayed number of points each center stands for
+    weights: Vec<f64>,
+}
+
+impl OnlineKMeans {
+    pub fn new(kmeans: KMeans, decay: f64, min_weight: f64) -> OnlineKMeans {
+        OnlineKMeans {
+            kmeans,
+            decay,
+            min_weight,
+            centers: vec![],
+            weights: vec![],
+        }
+    }
+
+    pub fn centers(&self) -> &[Point] {
+        &self.centers
+    }
+
+    pub fn weights(&self) -> &[f64] {
+        &self.weights
+    }
+
+    // Takes in the statistics of a period. `window` holds the recent statistics, including
+    // these ones, and is only used to refit the centers.
+    pub fn update(&mut self, statistics: &[Statistics], window: &[Statistics]) {
+        for weight in self.weights.iter_mut() {
+            *weight *= self.decay;
+        }
+
+        // drop the centers of the clusters that faded away
+        let min_weight = self.min_weight;
+        let mut i = 0;
+        while i < self.centers.len() {
+            if self.weights[i] < min_weight {
+                self.centers.remove(i);
+                self.weights.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        if self.centers.len() < self.kmeans.num_clusters() {
+            self.refit(window);
+            return;
+        }
+
+        for cluster in statistics.iter() {
+            let mean = cluster.mean();
+            let i = closest(&self.centers, &mean);
+            self.weights[i] += cluster.count;
+            let rate = cluster.count / self.weights[i];
+            for (c, x) in self.centers[i].iter_mut().zip(mean.iter()) {
+                *c += (x - *c) * rate;
+            }
+        }
+    }
+
+    fn refit(&mut self, window: &[Statistics]) {
+        let means: Vec<Point> = window.iter().map(|cluster| cluster.mean()).collect();
+        let counts: Vec<f64> = window.iter().map(|cluster| cluster.count).collect();
+        self.centers = self.kmeans.fit_weighted(&self.centers, &means, &counts);
+
+        self.weights = vec![0.; self.centers.len()];
+        for (mean, count) in means.iter().zip(counts.iter()) {
+            if !self.centers.is_empty() {
+                self.weights[closest(&self.centers, mean)] += count;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod online_kmeans_tests {
+    use crate::dsa::kmeans::*;
+    use crate::dsa::online_kmeans::*;
+
+    fn square(x: f64, y: f64) -> Vec<Point> {
+        vec![
+            vec![x, y],
+            vec![x + 1., y],
+            vec![x, y + 1.],
+            vec![x + 1., y + 1.],
+        ]
+    }
+
+    fn statistics(points: &[Point]) -> Vec<Statistics> {
+        points.iter().map(Statistics::new).collect()
+    }
+
+    fn assert_close(centers: &[Point], expected: Point) {
+        assert_eq!(centers.len(), 1);
+        assert!(sqr_dist(&centers[0], &expected) < EPS, "{:?}", centers);
+    }
+
+    #[test]
+    fn test_centers_follow_new_points() {
+        let mut model = OnlineKMeans::new(KMeans::new(1, 1, 0), 1., 1.);
+        let points = statistics(&square(0., 0.));
+        model.update(&points, &points);
+        assert_eq!(model.centers(), &[vec![0.5, 0.5]][..]);
+
+        // the center is the mean of all the points seen
+        model.update(&statistics(&square(4., 4.)), &statistics(&square(4., 4.)));
+        assert_close(model.centers(), vec![2.5, 2.5]);
+        assert_eq!(model.weights(), &[8.][..]);
+    }
+
+    #[test]
+    fn test_old_points_are_forgotten() {
+        let mut model = OnlineKMeans::new(KMeans::new(1, 1, 0), 0.5, 1.);
+        let points = statistics(&square(0., 0.));
+        model.update(&points, &points);
+
+        // the old points weigh half as much as the new ones
+        model.update(&statistics(&square(3., 3.)), &statistics(&square(3., 3.)));
+        assert_close(model.centers(), vec![2.5, 2.5]);
+        assert_eq!(model.weights(), &[6.][..]);
+    }
+
+    #[test]
+    fn test_faded_clusters_are_refitted_on_the_window() {
+        let mut model = OnlineKMeans::new(KMeans::new(1, 1, 0), 0.1, 1.);
+        let points = statistics(&square(0., 0.));
+        model.update(&points, &points);
+
+        // the weight of the center drops to 0.4, so the center is refitted on the window
+        let window = statistics(&square(5., 5.));
+        model.update(&[], &window);
+        assert_eq!(model.centers(), &[vec![5.5, 5.5]][..]);
+        assert_eq!(model.weights(), &[4.][..]);
+    }
+
+    #[test]
+    fn test_merged_summaries_match_the_points() {
+        let mut points = square(0., 0.);
+        points.append(&mut square(5., 5.));
+        let mut kmeans = KMeans::new(2, 1, 0);
+
+        // the summaries of two workers, each seeing half of the points
+        let mut summaries = summarise(&mut kmeans, &points[..6]);
+        summaries.append(&mut summarise(&mut kmeans, &points[6..]));
+        assert_eq!(summaries.iter().map(|s| s.count).sum::<f64>(), 8.);
+
+        let mut merged = OnlineKMeans::new(KMeans::new(2, 1, 0), 1., 1.);
+        merged.update(&summaries, &summaries);
+        let mut centers = merged.centers().to_vec();
+        centers.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(centers, vec![vec![0.5, 0.5], vec![5.5, 5.5]]);
+    }
+}
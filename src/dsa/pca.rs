@@ -0,0 +1,140 @@
+use crate::dsa::kmeans::Point;
+
+const POWER_ITERATIONS: usize = 100;
+const EPS: f64 = 1e-12;
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+// Removes the components of the vector along the unit axes, then scales it to unit
+// length, unless nothing is left of it.
+fn orthonormal(mut vector: Point, axes: &[Point]) -> Point {
+    for axis in axes.iter() {
+        let overlap = dot(&vector, axis);
+        for (x, a) in vector.iter_mut().zip(axis.iter()) {
+            *x -= overlap * a;
+        }
+    }
+    let norm = dot(&vector, &vector).sqrt();
+    if norm < EPS {
+        return vector;
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+// A projection of the points on their principal components, the orthogonal directions
+// along which they vary the most.
+#[derive(Clone, Debug)]
+pub struct Projection {
+    mean: Point,
+    // unit vectors, by decreasing variance
+    axes: Vec<Point>,
+}
+
+impl Projection {
+    // Finds the first `dimensions` principal components of the points by power iteration
+    // on their covariance matrix, keeping each one orthogonal to the ones found before.
+    // Points with at most `dimensions` dimensions are only centered.
+    pub fn fit(points: &[Point], dimensions: usize) -> Projection {
+        let n = points.first().map_or(0, |point| point.len());
+        let mut mean = vec![0.; n];
+        for point in points.iter() {
+            for (m, x) in mean.iter_mut().zip(point.iter()) {
+                *m += x / points.len() as f64;
+            }
+        }
+
+        let identity = |i: usize| (0..n).map(|j| if i == j { 1. } else { 0. }).collect();
+        if n <= dimensions {
+            return Projection {
+                mean,
+                axes: (0..n).map(identity).collect(),
+            };
+        }
+
+        let mut covariance = vec![vec![0.; n]; n];
+        for point in points.iter() {
+            for i in 0..n {
+                for j in 0..n {
+                    covariance[i][j] += (point[i] - mean[i]) * (point[j] - mean[j]);
+                }
+            }
+        }
+
+        let mut axes: Vec<Point> = vec![];
+        for _ in 0..dimensions {
+            // any start which is not orthogonal to the component will do
+            let mut axis = orthonormal((0..n).map(|j| 1. + j as f64).collect(), &axes);
+            for _ in 0..POWER_ITERATIONS {
+                let next = covariance.iter().map(|row| dot(row, &axis)).collect();
+                let next = orthonormal(next, &axes);
+                if dot(&next, &next) < EPS {
+                    break;
+                }
+                axis = next;
+            }
+            axes.push(axis);
+        }
+        Projection { mean, axes }
+    }
+
+    pub fn axes(&self) -> &[Point] {
+        &self.axes
+    }
+
+    pub fn project(&self, point: &Point) -> Point {
+        let centered: Point = point
+            .iter()
+            .zip(self.mean.iter())
+            .map(|(x, m)| x - m)
+            .collect();
+        self.axes.iter().map(|axis| dot(axis, &centered)).collect()
+    }
+}
+
+#[cfg(test)]
+mod pca_tests {
+    use crate::dsa::pca::*;
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert!(
+            a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-6),
+            "{:?}",
+            a
+        );
+    }
+
+    #[test]
+    fn test_low_dimensional_points_are_only_centered() {
+        let points = vec![vec![1., 2.], vec![3., 4.]];
+        let projection = Projection::fit(&points, 2);
+        assert_close(&projection.project(&points[0]), &[-1., -1.]);
+    }
+
+    #[test]
+    fn test_components_follow_the_variance() {
+        // the points vary a lot along the diagonal of the first two dimensions, a little
+        // along the third one, independently, and not at all along the last one
+        let mut points = vec![];
+        for i in 0..10 {
+            let x = i as f64;
+            let y = if i == 0 || i == 9 { 0.1 } else { 0. };
+            points.push(vec![x, x, y, 5.]);
+        }
+        let projection = Projection::fit(&points, 2);
+
+        let half = 0.5f64.sqrt();
+        let first = &projection.axes()[0];
+        assert_close(
+            &first.iter().map(|x| x.abs()).collect::<Vec<_>>(),
+            &[half, half, 0., 0.],
+        );
+        let second = &projection.axes()[1];
+        assert_close(
+            &second.iter().map(|x| x.abs()).collect::<Vec<_>>(),
+            &[0., 0., 1., 0.],
+        );
+        assert_eq!(projection.project(&points[0]).len(), 2);
+    }
+}
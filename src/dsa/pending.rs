@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Records waiting for their parent to arrive, such as replies to comments not seen yet,
+// indexed by the missing parent. Records waiting for longer than the timeout are dropped.
+pub struct Pending<K: Hash + Eq + Clone, T> {
+    timeout: usize,
+    // map: parent -> (creation time, record)
+    waiting: HashMap<K, Vec<(usize, T)>>,
+    // map: creation time -> parents, for the expiry; the parents resolved since are skipped
+    by_time: BTreeMap<usize, Vec<K>>,
+    dropped: usize,
+}
+
+impl<K: Hash + Eq + Clone, T> Pending<K, T> {
+    pub fn new(timeout: usize) -> Pending<K, T> {
+        Pending {
+            timeout,
+            waiting: HashMap::new(),
+            by_time: BTreeMap::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn park(&mut self, parent: K, time: usize, record: T) {
+        self.by_time
+            .entry(time)
+            .or_insert(vec![])
+            .push(parent.clone());
+        self.waiting
+            .entry(parent)
+            .or_insert(vec![])
+            .push((time, record));
+    }
+
+    // Removes the records waiting for `parent`, in the order they were parked.
+    pub fn resolve(&mut self, parent: &K) -> Vec<T> {
+        match self.waiting.remove(parent) {
+            Some(records) => records.into_iter().map(|(_, record)| record).collect(),
+            None => vec![],
+        }
+    }
+
+    // Drops the records created more than the timeout before `time`, and returns how many
+    // were dropped.
+    pub fn expire(&mut self, time: usize) -> usize {
+        let cutoff = time.saturating_sub(self.timeout);
+        let recent = self.by_time.split_off(&cutoff);
+        let expired = std::mem::replace(&mut self.by_time, recent);
+
+        let mut dropped = 0;
+        for (_, parents) in expired {
+            for parent in parents {
+                let records = match self.waiting.get_mut(&parent) {
+                    Some(records) => records,
+                    None => continue,
+                };
+                let before = records.len();
+                records.retain(|(created, _)| *created >= cutoff);
+                dropped += before - records.len();
+                if records.is_empty() {
+                    self.waiting.remove(&parent);
+                }
+            }
+        }
+        self.dropped += dropped;
+        dropped
+    }
+
+    // The number of records dropped so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    // The number of records waiting.
+    pub fn len(&self) -> usize {
+        self.waiting.values().map(|records| records.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod pending_tests {
+    use crate::dsa::pending::Pending;
+
+    #[test]
+    fn test_records_are_resolved_by_their_parent() {
+        let mut pending: Pending<u32, &str> = Pending::new(10);
+        pending.park(1, 5, "a");
+        pending.park(2, 6, "b");
+        pending.park(1, 7, "c");
+
+        assert_eq!(pending.resolve(&1), vec!["a", "c"]);
+        assert_eq!(pending.resolve(&1), Vec::<&str>::new());
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn test_old_records_are_dropped_and_counted() {
+        let mut pending: Pending<u32, &str> = Pending::new(10);
+        pending.park(1, 5, "a");
+        pending.park(1, 8, "b");
+        pending.park(2, 9, "c");
+        pending.resolve(&2);
+
+        assert_eq!(pending.expire(15), 0);
+        assert_eq!(pending.expire(16), 1);
+        assert_eq!(pending.resolve(&1), vec!["b"]);
+        assert_eq!(pending.expire(100), 0);
+        assert_eq!(pending.dropped(), 1);
+        assert_eq!(pending.len(), 0);
+    }
+}
@@ -1,40 +1,90 @@
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::collections::BTreeMap;
 
-pub type Stash<T> = HashMap<usize, Vec<T>>;
-
-pub trait Stashable<T> {
-    fn stash(&mut self, time: usize, value: T);
-    fn extract(&mut self, length: usize, time_end: usize) -> Vec<T>;
+// Values stashed by time, extracted by ranges of time.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stash<T> {
+    // map: time -> values stashed at that time
+    items: BTreeMap<usize, Vec<T>>,
+    len: usize,
 }
 
-impl<T: Debug> Stashable<T> for Stash<T> {
+impl<T> Stash<T> {
+    pub fn new() -> Stash<T> {
+        Stash {
+            items: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
     // Stash at time `time`.
-    fn stash(&mut self, time: usize, value: T) {
-        self.entry(time).or_insert(vec![]).push(value);
+    pub fn stash(&mut self, time: usize, value: T) {
+        self.items.entry(time).or_insert(vec![]).push(value);
+        self.len += 1;
     }
 
-    // Extracts everything on the interval [time_end-length, time_end)
-    fn extract(&mut self, length: usize, time_end: usize) -> Vec<T> {
+    // Extracts everything on the interval [time_end-length, time_end), in order of time.
+    pub fn extract(&mut self, length: usize, time_end: usize) -> Vec<T> {
+        let start = time_end.saturating_sub(length);
+        let times: Vec<usize> = self
+            .items
+            .range(start..time_end)
+            .map(|(time, _)| *time)
+            .collect();
+
         let mut all = Vec::new();
-        let start = if time_end < length {
-            0
-        } else {
-            time_end - length
-        };
-        for t in start..time_end {
-            match self.remove(&t) {
-                Some(mut vec) => all.append(&mut vec),
-                None => {}
-            }
+        for time in times {
+            all.append(&mut self.remove(&time).unwrap());
         }
         all
     }
+
+    // The values on the interval [time_end-length, time_end), in order of time, without
+    // extracting them.
+    pub fn peek<'a>(&'a self, length: usize, time_end: usize) -> impl Iterator<Item = &'a T> {
+        let start = time_end.saturating_sub(length);
+        self.items
+            .range(start..time_end)
+            .flat_map(|(_, values)| values)
+    }
+
+    // Extracts the values stashed at `time`.
+    pub fn remove(&mut self, time: &usize) -> Option<Vec<T>> {
+        let values = self.items.remove(time)?;
+        self.len -= values.len();
+        Some(values)
+    }
+
+    // Drops everything before `time`, such as the values which arrived after their window
+    // was extracted, and returns the number of values dropped.
+    pub fn evict(&mut self, time: usize) -> usize {
+        let later = self.items.split_off(&time);
+        let evicted: usize = std::mem::replace(&mut self.items, later)
+            .values()
+            .map(|values| values.len())
+            .sum();
+        self.len -= evicted;
+        evicted
+    }
+
+    // The number of values stashed.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // The number of distinct times values are stashed at.
+    pub fn times(&self) -> usize {
+        self.items.len()
+    }
+
+    // The latest time a value is stashed at.
+    pub fn last_time(&self) -> Option<usize> {
+        self.items.keys().next_back().cloned()
+    }
 }
 
 #[cfg(test)]
 mod stash_tests {
-    use crate::dsa::stash::{Stash, Stashable};
+    use crate::dsa::stash::Stash;
 
     #[test]
     fn test_stash_doesnt_take_last_element() {
@@ -71,4 +121,29 @@ mod stash_tests {
         let vec = stash.extract(1, 4);
         assert!(vec == vec![4, 4, 1]);
     }
+
+    #[test]
+    fn test_stash_can_be_peeked_and_evicted() {
+        let mut stash: Stash<u32> = Stash::new();
+        for time in vec![40_000, 3, 7, 7, 50_000] {
+            stash.stash(time, time as u32);
+        }
+        assert_eq!(stash.len(), 5);
+        assert_eq!(stash.times(), 4);
+
+        let peeked: Vec<u32> = stash.peek(43_200, 43_200).cloned().collect();
+        assert_eq!(peeked, vec![3, 7, 7, 40_000]);
+        assert_eq!(stash.len(), 5);
+
+        // the values left behind by a window are dropped
+        assert_eq!(stash.extract(10_000, 43_200), vec![40_000]);
+        assert_eq!(stash.evict(43_200), 3);
+        assert_eq!(stash.last_time(), Some(50_000));
+        assert_eq!(stash.len(), 1);
+
+        let bytes = bincode::serialize(&stash).unwrap();
+        let restored: Stash<u32> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(restored.peek(1, 50_001).collect::<Vec<_>>(), vec![&50_000]);
+        assert_eq!(restored.len(), 1);
+    }
 }
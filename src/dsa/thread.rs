@@ -0,0 +1,336 @@
+use crate::dsa::pending::Pending;
+use crate::dto::comment::Comment;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// The shape of the thread of a post.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ThreadStats {
+    pub post_id: u32,
+    pub comments: usize,
+    // the number of levels of comments below the post
+    pub depth: usize,
+    // the average number of replies of the post and the comments which have any
+    pub branching: f64,
+    // the ids of the comments on the longest chain, from the post down
+    pub longest_chain: Vec<u32>,
+    pub participants: BTreeSet<u32>,
+}
+
+struct Node {
+    // the comment, without its replies
+    comment: Comment,
+    parent: Option<u32>,
+    depth: usize,
+    children: Vec<u32>,
+}
+
+// The tree of the comments and replies of a post.
+pub struct Thread {
+    post_id: u32,
+    // the comments on the post itself
+    roots: Vec<u32>,
+    nodes: HashMap<u32, Node>,
+    last_active: usize,
+}
+
+impl Thread {
+    pub fn new(post_id: u32) -> Thread {
+        Thread {
+            post_id,
+            roots: vec![],
+            nodes: HashMap::new(),
+            last_active: 0,
+        }
+    }
+
+    // Adds the comment below its parent. Returns false if the comment is already in the
+    // thread or its parent is not.
+    pub fn add(&mut self, mut comment: Comment) -> bool {
+        if self.nodes.contains_key(&comment.id) {
+            return false;
+        }
+        let parent = comment.reply_to_comment_id;
+        let depth = match parent {
+            Some(parent) => match self.nodes.get_mut(&parent) {
+                Some(node) => {
+                    node.children.push(comment.id);
+                    node.depth + 1
+                }
+                None => return false,
+            },
+            None => {
+                self.roots.push(comment.id);
+                1
+            }
+        };
+
+        self.last_active = self.last_active.max(comment.timestamp);
+        comment.replies = vec![];
+        let node = Node {
+            comment,
+            parent,
+            depth,
+            children: vec![],
+        };
+        self.nodes.insert(node.comment.id, node);
+        true
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.nodes.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn last_active(&self) -> usize {
+        self.last_active
+    }
+
+    pub fn depth(&self) -> usize {
+        self.nodes
+            .values()
+            .map(|node| node.depth)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn branching(&self) -> f64 {
+        let parents = self
+            .nodes
+            .values()
+            .filter(|node| !node.children.is_empty())
+            .count()
+            + if self.roots.is_empty() { 0 } else { 1 };
+        match parents {
+            0 => 0.,
+            parents => self.len() as f64 / parents as f64,
+        }
+    }
+
+    // The chain from the post to the deepest comment, the earliest one on ties.
+    pub fn longest_chain(&self) -> Vec<u32> {
+        let deepest = self.nodes.values().max_by(|lhs, rhs| {
+            lhs.depth
+                .cmp(&rhs.depth)
+                .then(rhs.comment.timestamp.cmp(&lhs.comment.timestamp))
+                .then(rhs.comment.id.cmp(&lhs.comment.id))
+        });
+        let mut chain = vec![];
+        let mut cur = deepest.map(|node| node.comment.id);
+        while let Some(id) = cur {
+            chain.push(id);
+            cur = self.nodes[&id].parent;
+        }
+        chain.reverse();
+        chain
+    }
+
+    pub fn participants(&self) -> BTreeSet<u32> {
+        self.nodes
+            .values()
+            .map(|node| node.comment.person_id)
+            .collect()
+    }
+
+    // The comment with its replies filled in, recursively.
+    pub fn subtree(&self, id: u32) -> Option<Comment> {
+        self.nodes.get(&id)?;
+
+        // the comments of the subtree, each one before its replies
+        let mut order = vec![id];
+        let mut i = 0;
+        while i < order.len() {
+            order.extend(self.nodes[&order[i]].children.iter().cloned());
+            i += 1;
+        }
+
+        // built from the leaves up, so that the replies are always built first
+        let mut built: HashMap<u32, Comment> = HashMap::new();
+        for id in order.into_iter().rev() {
+            let node = &self.nodes[&id];
+            let mut comment = node.comment.clone();
+            comment.replies = node
+                .children
+                .iter()
+                .map(|child| built.remove(child).unwrap())
+                .collect();
+            built.insert(id, comment);
+        }
+        built.remove(&id)
+    }
+
+    pub fn stats(&self) -> ThreadStats {
+        ThreadStats {
+            post_id: self.post_id,
+            comments: self.len(),
+            depth: self.depth(),
+            branching: self.branching(),
+            longest_chain: self.longest_chain(),
+            participants: self.participants(),
+        }
+    }
+}
+
+// The threads of the posts, built from comments linked to their post. The replies which
+// arrive before their parent wait for it.
+pub struct Threads {
+    threads: HashMap<u32, Thread>,
+    // map: comment -> post
+    posts: HashMap<u32, u32>,
+    // map: parent comment -> replies waiting for it
+    waiting: Pending<u32, Comment>,
+    // the posts whose thread changed since the last call to `changed`
+    changed: HashSet<u32>,
+}
+
+impl Threads {
+    pub fn new(orphan_timeout: usize) -> Threads {
+        Threads {
+            threads: HashMap::new(),
+            posts: HashMap::new(),
+            waiting: Pending::new(orphan_timeout),
+            changed: HashSet::new(),
+        }
+    }
+
+    // Adds a comment whose `reply_to_post_id` is the root post of its thread, along with
+    // the replies waiting for it. Comments of no post are ignored.
+    pub fn add(&mut self, comment: Comment) {
+        let post_id = match comment.reply_to_post_id {
+            Some(post_id) => post_id,
+            None => return,
+        };
+        let thread = self
+            .threads
+            .entry(post_id)
+            .or_insert_with(|| Thread::new(post_id));
+
+        let mut ready = vec![comment];
+        while let Some(comment) = ready.pop() {
+            let id = comment.id;
+            match comment.reply_to_comment_id {
+                Some(parent) if !thread.contains(parent) => {
+                    self.waiting.park(parent, comment.timestamp, comment);
+                    continue;
+                }
+                _ => {}
+            }
+            if thread.add(comment) {
+                self.posts.insert(id, post_id);
+                self.changed.insert(post_id);
+                ready.extend(self.waiting.resolve(&id));
+            }
+        }
+    }
+
+    // The subtree of a comment of any thread.
+    pub fn subtree(&self, id: u32) -> Option<Comment> {
+        self.threads.get(self.posts.get(&id)?)?.subtree(id)
+    }
+
+    // The statistics of the threads which changed since the last call, by post id.
+    pub fn changed(&mut self) -> Vec<ThreadStats> {
+        let mut changed: Vec<u32> = self.changed.drain().collect();
+        changed.sort();
+        changed
+            .into_iter()
+            .filter_map(|post_id| self.threads.get(&post_id))
+            .map(|thread| thread.stats())
+            .collect()
+    }
+
+    // Forgets the threads without comments since `time`, and the replies waiting for
+    // their parent for longer than the timeout at `now`. Returns the number of replies
+    // dropped.
+    pub fn expire(&mut self, time: usize, now: usize) -> usize {
+        let expired: Vec<u32> = self
+            .threads
+            .iter()
+            .filter(|(_, thread)| thread.last_active() < time)
+            .map(|(post_id, _)| *post_id)
+            .collect();
+        for post_id in expired {
+            let thread = self.threads.remove(&post_id).unwrap();
+            for id in thread.nodes.keys() {
+                self.posts.remove(id);
+            }
+            self.changed.remove(&post_id);
+        }
+        self.waiting.expire(now)
+    }
+}
+
+#[cfg(test)]
+mod thread_tests {
+    use crate::dsa::thread::{Thread, Threads};
+    use crate::dto::comment::Comment;
+    use crate::dto::common::Watermarkable;
+
+    fn comment(id: u32, person_id: u32, parent: Option<u32>, timestamp: usize) -> Comment {
+        Comment {
+            id,
+            person_id,
+            timestamp,
+            reply_to_post_id: Some(1),
+            reply_to_comment_id: parent,
+            is_watermark: false,
+            ..Comment::from_watermark("0", 0)
+        }
+    }
+
+    #[test]
+    fn test_thread_shape() {
+        // 2 -> 3 -> 5, 2 -> 4, 6
+        let mut thread = Thread::new(1);
+        assert!(thread.add(comment(2, 10, None, 1)));
+        assert!(thread.add(comment(3, 11, Some(2), 2)));
+        assert!(thread.add(comment(4, 10, Some(2), 3)));
+        assert!(thread.add(comment(5, 12, Some(3), 4)));
+        assert!(thread.add(comment(6, 13, None, 5)));
+        assert!(!thread.add(comment(7, 13, Some(8), 6)));
+        assert!(!thread.add(comment(6, 13, None, 6)));
+
+        let stats = thread.stats();
+        assert_eq!(stats.comments, 5);
+        assert_eq!(stats.depth, 3);
+        // the post and comment 2 have 2 replies, comment 3 has 1
+        assert_eq!(stats.branching, 5. / 3.);
+        assert_eq!(stats.longest_chain, vec![2, 3, 5]);
+        assert_eq!(
+            stats.participants.into_iter().collect::<Vec<_>>(),
+            vec![10, 11, 12, 13]
+        );
+
+        let subtree = thread.subtree(2).unwrap();
+        let replies: Vec<u32> = subtree.replies.iter().map(|c| c.id).collect();
+        assert_eq!(replies, vec![3, 4]);
+        assert_eq!(subtree.replies[0].replies[0].id, 5);
+        assert!(subtree.replies[1].replies.is_empty());
+        assert!(thread.subtree(7).is_none());
+    }
+
+    #[test]
+    fn test_replies_wait_for_their_parent() {
+        let mut threads = Threads::new(10);
+        threads.add(comment(4, 10, Some(3), 3));
+        threads.add(comment(3, 10, Some(2), 2));
+        threads.add(comment(9, 10, Some(8), 2));
+        assert!(threads.changed().is_empty());
+
+        threads.add(comment(2, 10, None, 1));
+        let changed = threads.changed();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].longest_chain, vec![2, 3, 4]);
+        assert_eq!(threads.subtree(3).unwrap().replies[0].id, 4);
+
+        // the reply to the missing comment 8 is dropped, then the idle thread
+        assert_eq!(threads.expire(0, 13), 1);
+        assert!(threads.subtree(3).is_some());
+        threads.expire(4, 13);
+        assert!(threads.subtree(3).is_none());
+        assert!(threads.threads.is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Tracks the watermark of every input partition. The aligned watermark is the minimum of
+// the watermarks of the partitions, so that no partition is overtaken, and there is none
+// until every partition has sent one. A partition which sent no watermark for `idle_after`
+// of wall-clock time is idle, and is left out until it sends one again, so that it does
+// not stall the others.
+pub struct Watermarks {
+    partitions: usize,
+    idle_after: Duration,
+    // map: partition -> latest watermark
+    watermarks: HashMap<usize, usize>,
+    // map: partition -> when its last watermark arrived
+    last_seen: HashMap<usize, Instant>,
+    // partitions which never sent anything are idle this long after the start
+    started: Instant,
+    // the aligned watermark last returned by `update`
+    aligned: Option<usize>,
+}
+
+impl Watermarks {
+    pub fn new(partitions: usize, idle_after: Duration, now: Instant) -> Watermarks {
+        Watermarks {
+            partitions,
+            idle_after,
+            watermarks: HashMap::new(),
+            last_seen: HashMap::new(),
+            started: now,
+            aligned: None,
+        }
+    }
+
+    pub fn is_idle(&self, partition: usize, now: Instant) -> bool {
+        let last_seen = self.last_seen.get(&partition).unwrap_or(&self.started);
+        now.duration_since(*last_seen) >= self.idle_after
+    }
+
+    // The minimum watermark of the partitions which are not idle, if they all sent one.
+    // Once all of them are idle, nothing holds back the latest watermark.
+    pub fn aligned(&self, now: Instant) -> Option<usize> {
+        let active: Vec<usize> = (0..self.partitions)
+            .filter(|partition| !self.is_idle(*partition, now))
+            .collect();
+        if active.is_empty() {
+            return self.watermarks.values().max().cloned();
+        }
+        active
+            .iter()
+            .map(|partition| self.watermarks.get(partition).cloned())
+            .collect::<Option<Vec<usize>>>()?
+            .into_iter()
+            .min()
+    }
+
+    // Records a watermark of a partition, and returns the aligned watermark if it advanced.
+    // Older watermarks of the partition are ignored.
+    pub fn update(&mut self, partition: usize, watermark: usize, now: Instant) -> Option<usize> {
+        self.last_seen.insert(partition, now);
+        let latest = self.watermarks.entry(partition).or_insert(watermark);
+        *latest = (*latest).max(watermark);
+
+        let aligned = self.aligned(now)?;
+        if self.aligned.map_or(false, |last| last >= aligned) {
+            return None;
+        }
+        self.aligned = Some(aligned);
+        Some(aligned)
+    }
+}
+
+#[cfg(test)]
+mod watermarks_tests {
+    use crate::dsa::watermarks::Watermarks;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_watermarks_are_aligned_to_the_slowest_partition() {
+        let now = Instant::now();
+        let mut watermarks = Watermarks::new(2, Duration::from_secs(60), now);
+        // nothing is released until every partition has sent a watermark
+        assert_eq!(watermarks.update(0, 10, now), None);
+        assert_eq!(watermarks.update(0, 20, now), None);
+        assert_eq!(watermarks.update(1, 10, now), Some(10));
+        assert_eq!(watermarks.update(1, 30, now), Some(20));
+        // late watermarks are ignored
+        assert_eq!(watermarks.update(1, 25, now), None);
+        assert_eq!(watermarks.aligned(now), Some(20));
+    }
+
+    #[test]
+    fn test_idle_partitions_do_not_stall_the_others() {
+        let start = Instant::now();
+        let mut watermarks = Watermarks::new(3, Duration::from_secs(15), start);
+        assert_eq!(watermarks.update(0, 10, start), None);
+        assert_eq!(watermarks.update(1, 10, start), None);
+        assert!(!watermarks.is_idle(2, start));
+
+        // partitions 1 and 2 sent nothing for 15 seconds, so 0 goes on alone
+        let later = start + Duration::from_secs(15);
+        assert_eq!(watermarks.update(0, 20, later), Some(20));
+        assert!(watermarks.is_idle(1, later) && watermarks.is_idle(2, later));
+        assert_eq!(watermarks.update(1, 30, later), None);
+        assert!(!watermarks.is_idle(1, later) && watermarks.is_idle(2, later));
+
+        // the idle partition is aligned again once it sends a watermark
+        assert_eq!(watermarks.update(2, 25, later), None);
+        assert!(!watermarks.is_idle(2, later));
+        assert_eq!(watermarks.update(0, 40, later), Some(25));
+    }
+}
@@ -61,9 +61,9 @@ impl Importable<Comment> for Comment {
 }
 
 impl Watermarkable for Comment {
-    fn from_watermark(watermark: &str, _index: usize) -> Self {
+    fn from_watermark(watermark: &str, index: usize) -> Self {
         Self {
-            id: 0,
+            id: index as u32,
             person_id: 0,
             timestamp: watermark.parse().unwrap(),
             location_ip: Ipv4Addr::new(0, 0, 0, 0),
@@ -80,6 +80,10 @@ impl Watermarkable for Comment {
     fn is_watermark(&self) -> bool {
         self.is_watermark
     }
+
+    fn partition(&self) -> usize {
+        self.id as usize
+    }
 }
 
 impl Timestamped for Comment {
@@ -13,6 +13,8 @@ pub trait Importable<T> {
 pub trait Watermarkable {
     fn from_watermark(watermark: &str, index: usize) -> Self;
     fn is_watermark(&self) -> bool;
+    // The partition a watermark was read from.
+    fn partition(&self) -> usize;
 }
 
 pub trait Timestamped {
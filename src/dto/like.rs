@@ -47,6 +47,10 @@ impl Watermarkable for Like {
     fn is_watermark(&self) -> bool {
         self.is_watermark
     }
+
+    fn partition(&self) -> usize {
+        self.post_id as usize
+    }
 }
 
 impl Timestamped for Like {
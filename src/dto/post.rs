@@ -87,6 +87,10 @@ impl Watermarkable for Post {
     fn is_watermark(&self) -> bool {
         self.is_watermark
     }
+
+    fn partition(&self) -> usize {
+        self.id as usize
+    }
 }
 
 impl Timestamped for Post {
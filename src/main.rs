@@ -10,18 +10,29 @@ mod tasks;
 mod util;
 
 use clap::{App, Arg, SubCommand};
-use std::path::Path;
-use tasks::{load, post_stats, unusual_activity, who_to_follow};
+use connection::delay::Delay;
+use connection::producer::FIXED_BOUNDED_DELAY;
+use dsa::anomaly::DetectorKind;
+use dsa::features::FeatureKind;
+use operators::link_replies::ThreadConfig;
+use operators::source::{SourceConfig, StartPosition};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tasks::load::{EventStream, LoadConfig};
+use tasks::unusual_activity::Config as UnusualConfig;
+use tasks::{batch, load, post_stats, unusual_activity, verify, who_to_follow};
+
+fn sub_dir(path: &Path, name: &str) -> PathBuf {
+    let sub_path = path.join(name);
+    if !sub_path.is_dir() {
+        panic!("Specified path does not contain {} directory", name);
+    }
+    sub_path
+}
 
 fn main() {
+    let default_bound = FIXED_BOUNDED_DELAY.to_string();
     let matches = App::new("DSPA")
-        .arg(
-            Arg::with_name("records")
-                .short("r")
-                .long("records")
-                .help("Set the number of records to read.")
-                .takes_value(true),
-        )
         .arg(
             Arg::with_name("path")
                 .short("p")
@@ -30,54 +41,354 @@ fn main() {
                 .help("Set the path to the directory containing streams & tables")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("bound")
+                .short("b")
+                .long("bound")
+                .default_value(&default_bound)
+                .help("Set the bound of the event delays, which is also the watermark period.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-batch")
+                .long("max-batch")
+                .help("Set the maximum number of messages a source reads at once.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("poll-timeout")
+                .long("poll-timeout")
+                .help("Set how many milliseconds an idle source waits before polling again.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-lag")
+                .long("max-lag")
+                .help("Pause the sources while the outputs lag behind by this many seconds.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("until")
+                .long("until")
+                .help("End the streams at the first watermark at or past this event time.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("end-after-idle")
+                .long("end-after-idle")
+                .help("End the streams once they are fully read for this many seconds.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("idle-partition")
+                .long("idle-partition")
+                .help("Stop waiting for partitions without watermarks for this many seconds.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("start")
+                .long("start")
+                .default_value("earliest")
+                .help(
+                    "Set where the sources start reading: earliest, latest, \
+                     offsets:<offset>,... (one per partition) or time:<timestamp>.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("thread-horizon")
+                .long("thread-horizon")
+                .help("Forget the comment threads without comments for this many seconds.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("orphan-timeout")
+                .long("orphan-timeout")
+                .help("Drop the comments whose parent has not arrived for this many seconds.")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .about("Loads the event streams into Kafka.")
+                .arg(
+                    Arg::with_name("records")
+                        .short("r")
+                        .long("records")
+                        .help("Set the number of records to read.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("streams")
+                        .short("s")
+                        .long("streams")
+                        .help("Set the streams to load.")
+                        .possible_values(&["posts", "comments", "likes"])
+                        .use_delimiter(true)
+                        .default_value("posts,comments,likes")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("delay")
+                        .short("d")
+                        .long("delay")
+                        .help("Set the distribution of the delay applied to events.")
+                        .possible_values(&["none", "uniform", "exponential", "pareto", "burst"])
+                        .default_value("uniform")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .help("Set the seed of the delays to reproduce a previous load.")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("post-stats")
-                .about("Active posts(12 hours) statistics updated every 30 minutes."),
+                .about("Active posts(12 hours) statistics updated every 30 minutes.")
+                .arg(
+                    Arg::with_name("charts")
+                        .long("charts")
+                        .help("Chart the statistics over time in this directory.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .help("Print the depth and shape of the comment threads."),
+                )
+                .arg(
+                    Arg::with_name("subtrees")
+                        .long("subtrees")
+                        .help("Print the replies to these comments, every 30 minutes.")
+                        .use_delimiter(true)
+                        .takes_value(true),
+                ),
         )
         .subcommand(SubCommand::with_name("who-to-follow").about("Friend recommendation service."))
         .subcommand(
             SubCommand::with_name("unusual-activity")
-                .about("Suggests users that post unusual content."),
+                .about("Suggests users that post unusual content.")
+                .arg(
+                    Arg::with_name("features")
+                        .short("f")
+                        .long("features")
+                        .help("Set the features of the posts used for clustering.")
+                        .possible_values(&[
+                            "lengths",
+                            "tf-idf",
+                            "entropy",
+                            "stop-words",
+                            "links",
+                            "language",
+                            "repetition",
+                        ])
+                        .use_delimiter(true)
+                        .default_value("lengths")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .help("Set the seed of the initial cluster centers.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("mini-batch")
+                        .long("mini-batch")
+                        .help("Refine the clusters with random batches of this size.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("detector")
+                        .short("d")
+                        .long("detector")
+                        .help("Set how the outliers are detected.")
+                        .possible_values(&[
+                            "clusters",
+                            "lof",
+                            "isolation-forest",
+                            "rates",
+                            "ensemble",
+                        ])
+                        .default_value("clusters")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-behaviour")
+                        .long("no-behaviour")
+                        .help("Only look at the content of the posts."),
+                )
+                .arg(
+                    Arg::with_name("plot")
+                        .long("plot")
+                        .help("Plot the clusters of every period in this directory.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cooldown")
+                        .long("cooldown")
+                        .help("Do not report a person again for this many seconds.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Computes the exact outputs of the tasks from the complete streams."),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Compares the outputs of the tasks with the exact outputs.")
+                .arg(
+                    Arg::with_name("outputs")
+                        .long("outputs")
+                        .help(
+                            "Compare the outputs printed by the tasks, in this file or \
+                             directory, instead of replaying the streams.",
+                        )
+                        .takes_value(true),
+                ),
         )
         .get_matches();
 
-    let records = match matches.is_present("records") {
-        true => Some(
-            matches
-                .value_of("records")
-                .unwrap()
-                .parse()
-                .expect("records must be integer"),
-        ),
-        false => None,
-    };
-
     let path = matches.value_of("path").unwrap();
     let path = Path::new(path);
     if !path.is_dir() {
         panic!("Specified path is not a directory");
     }
 
-    let streams_path = path.join("streams");
-    if !streams_path.is_dir() {
-        panic!("Specified path does not contain streams directory");
-    }
-    let tables_path = path.join("tables");
-    if !tables_path.is_dir() {
-        panic!("Specified path does not contain tables directory");
+    let bound: usize = matches
+        .value_of("bound")
+        .unwrap()
+        .parse()
+        .expect("bound must be integer");
+    if bound < 1 {
+        panic!("bound must be at least 1 second");
     }
 
-    load::run(records, &streams_path);
-
-    if let ("post-stats", _) = matches.subcommand() {
-        post_stats::run();
+    let start: StartPosition = matches.value_of("start").unwrap().parse().unwrap();
+    let mut source = SourceConfig {
+        start,
+        ..SourceConfig::default()
+    };
+    if let Some(max_batch) = matches.value_of("max-batch") {
+        source.max_batch = max_batch.parse().expect("max-batch must be integer");
+    }
+    if let Some(timeout) = matches.value_of("poll-timeout") {
+        source.poll_timeout =
+            Duration::from_millis(timeout.parse().expect("poll-timeout must be integer"));
+    }
+    if let Some(max_lag) = matches.value_of("max-lag") {
+        source.max_lag = max_lag.parse().expect("max-lag must be integer");
+    }
+    if let Some(until) = matches.value_of("until") {
+        source.until = Some(until.parse().expect("until must be integer"));
+    }
+    if let Some(idle) = matches.value_of("end-after-idle") {
+        let idle = idle.parse().expect("end-after-idle must be integer");
+        source.end_after_idle = Some(Duration::from_secs(idle));
+    }
+    if let Some(idle) = matches.value_of("idle-partition") {
+        let idle = idle.parse().expect("idle-partition must be integer");
+        source.idle_partition = Duration::from_secs(idle);
     }
 
-    if let ("who-to-follow", _) = matches.subcommand() {
-        who_to_follow::run(tables_path);
+    let mut thread_config = ThreadConfig::default();
+    if let Some(horizon) = matches.value_of("thread-horizon") {
+        thread_config.horizon = horizon.parse().expect("thread-horizon must be integer");
+    }
+    if let Some(timeout) = matches.value_of("orphan-timeout") {
+        thread_config.orphan_timeout = timeout.parse().expect("orphan-timeout must be integer");
     }
 
-    if let ("unusual-activity", _) = matches.subcommand() {
-        unusual_activity::run();
+    match matches.subcommand() {
+        ("load", Some(load_matches)) => {
+            let records = match load_matches.is_present("records") {
+                true => Some(
+                    load_matches
+                        .value_of("records")
+                        .unwrap()
+                        .parse()
+                        .expect("records must be integer"),
+                ),
+                false => None,
+            };
+            let streams: Vec<EventStream> = load_matches
+                .values_of("streams")
+                .unwrap()
+                .map(|s| s.parse().unwrap())
+                .collect();
+            let delay: Delay = load_matches.value_of("delay").unwrap().parse().unwrap();
+            if delay == Delay::Uniform && bound < 2 {
+                panic!("bound must be at least 2 seconds for uniform delays");
+            }
+            let seed = match load_matches.value_of("seed") {
+                Some(seed) => seed.parse().expect("seed must be integer"),
+                None => rand::random(),
+            };
+            println!("Loading with seed {}", seed);
+
+            let config = LoadConfig {
+                streams,
+                records,
+                delay,
+                bound,
+                seed,
+            };
+            load::run(&config, &sub_dir(path, "streams"));
+        }
+        ("post-stats", Some(stats_matches)) => post_stats::run(
+            bound,
+            stats_matches.value_of("charts").map(PathBuf::from),
+            stats_matches.is_present("threads"),
+            stats_matches.values_of("subtrees").map_or(vec![], |ids| {
+                ids.map(|id| id.parse().expect("subtrees must be integers"))
+                    .collect()
+            }),
+            thread_config,
+            source,
+        ),
+        ("who-to-follow", _) => {
+            who_to_follow::run(sub_dir(path, "tables"), bound, thread_config, source)
+        }
+        ("unusual-activity", Some(unusual_matches)) => {
+            let features: Vec<FeatureKind> = unusual_matches
+                .values_of("features")
+                .unwrap()
+                .map(|s| s.parse().unwrap())
+                .collect();
+            let detector: DetectorKind = unusual_matches
+                .value_of("detector")
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut config = UnusualConfig {
+                features,
+                detector,
+                behaviour: !unusual_matches.is_present("no-behaviour"),
+                ..UnusualConfig::default()
+            };
+            if let Some(seed) = unusual_matches.value_of("seed") {
+                config.seed = seed.parse().expect("seed must be integer");
+            }
+            if let Some(batch_size) = unusual_matches.value_of("mini-batch") {
+                config.batch_size = Some(batch_size.parse().expect("mini-batch must be integer"));
+            }
+            if let Some(dir) = unusual_matches.value_of("plot") {
+                config.plot = Some(PathBuf::from(dir));
+            }
+            if let Some(cooldown) = unusual_matches.value_of("cooldown") {
+                config.cooldown = cooldown.parse().expect("cooldown must be integer");
+            }
+            unusual_activity::run(config, source);
+        }
+        ("batch", _) => batch::run(&sub_dir(path, "streams"), &sub_dir(path, "tables")),
+        ("verify", Some(verify_matches)) => verify::run(
+            &sub_dir(path, "streams"),
+            &sub_dir(path, "tables"),
+            bound,
+            verify_matches.value_of("outputs").map(PathBuf::from),
+        ),
+        _ => println!("{}", matches.usage()),
     }
 }
@@ -8,7 +8,7 @@ use crate::dto::comment::Comment;
 use crate::dto::common::{Timestamped, Watermarkable};
 use crate::dto::like::Like;
 
-use crate::dsa::stash::*;
+use crate::dsa::stash::Stash;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
@@ -83,6 +83,9 @@ where
                     // get timestamps for likes and comments
                     let raw_comments = comments_buffer.extract(delay, *cap.time());
                     let raw_likes = likes_buffer.extract(delay, *cap.time());
+                    // the comments and likes arriving after their window are dropped
+                    comments_buffer.evict(cap.time().saturating_sub(delay));
+                    likes_buffer.evict(cap.time().saturating_sub(delay));
 
                     let likes: Vec<_> = raw_likes
                         .iter()
@@ -117,15 +120,11 @@ where
 
                     // push new timestamps
                     for (new_timestamp, post_id, person_id) in all_info.drain(..) {
-                        // get current timestamp
-                        if let Some(current_timestamp) = last_active_time
-                            .entry(post_id)
-                            .or_insert(Some(new_timestamp))
-                        {
-                            // and if I have a better one, update it
-                            if new_timestamp > *current_timestamp {
-                                last_active_time.insert(post_id, Some(new_timestamp));
-                            }
+                        // get current timestamp and if I have a better one, update it; this
+                        // also reactivates posts that went stale
+                        let last_active = last_active_time.entry(post_id).or_insert(None);
+                        if last_active.map_or(true, |current| new_timestamp > current) {
+                            *last_active = Some(new_timestamp);
                         }
 
                         interactions_by_post
@@ -1,14 +1,24 @@
 extern crate timely;
 
+use crate::dsa::watermarks::Watermarks;
 use crate::dto::common::{Timestamped, Watermarkable};
 
-use timely::dataflow::channels::pact::ParallelizationContract;
+use timely::dataflow::channels::pact::{ParallelizationContract, Pipeline};
 use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::{Broadcast, Filter};
 use timely::dataflow::{Scope, Stream};
 use timely::Data;
 
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// partitions which sent no watermark for this long are idle by default
+pub const IDLE_AFTER: Duration = Duration::from_secs(60);
+
 pub trait Buffer<G: Scope, P: ParallelizationContract<usize, D>, D: Data> {
-    fn buffer(&self, pact: P) -> Stream<G, D>;
+    // The partitions which sent no watermark for `idle_after` are left out of the alignment
+    // until they send again.
+    fn buffer(&self, pact: P, idle_after: Duration) -> Stream<G, D>;
 }
 
 impl<
@@ -17,33 +27,65 @@ impl<
         D: Data + Timestamped + Watermarkable,
     > Buffer<G, P, D> for Stream<G, D>
 {
-    fn buffer(&self, pact: P) -> Stream<G, D> {
+    // Holds the data until the watermarks of all the partitions, that is of all the
+    // workers' sources, pass it. The data up to the aligned watermark is then released in
+    // order of time, followed by the watermark, so that a partition ahead of the others
+    // does not release the data early.
+    fn buffer(&self, pact: P, idle_after: Duration) -> Stream<G, D> {
+        let index = self.scope().index();
+        let mut watermarks = Watermarks::new(self.scope().peers(), idle_after, Instant::now());
         let mut data_stash = vec![];
-        self.unary(pact, "Buffer", move |_, _| {
-            move |input, output| {
+        // map: time -> aligned watermark released at that time
+        let mut releases: HashMap<usize, usize> = HashMap::new();
+
+        // every worker sees the watermarks of every partition
+        let all_watermarks = self.filter(|d| d.is_watermark()).broadcast();
+        self.filter(|d| !d.is_watermark()).binary_notify(
+            &all_watermarks,
+            pact,
+            Pipeline,
+            "Buffer",
+            None,
+            move |d_input, w_input, output, notificator| {
                 let mut vector = vec![];
-                while let Some((time, data)) = input.next() {
+                d_input.for_each(|_, data| {
                     data.swap(&mut vector);
-                    let mut needs_release = false;
                     for d in vector.drain(..) {
-                        if d.is_watermark() {
-                            needs_release = true;
-                        }
-                        // Even the watermark will propagate so that we report data all the time
-                        // for the first 2 tasks
-                        data_stash.push((d.timestamp().clone(), d));
+                        data_stash.push((d.timestamp(), d));
                     }
-                    if needs_release {
-                        // Release everything up to watermark
-                        let mut session = output.session(&time);
-                        data_stash.sort_by(|(t1, _), (t2, _)| t1.partial_cmp(t2).unwrap());
-                        for (_post_time, post) in data_stash.drain(..) {
-                            session.give(post.clone());
+                });
+                w_input.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    for watermark in vector.drain(..) {
+                        let partition = watermark.partition();
+                        let time = watermark.timestamp();
+                        if let Some(aligned) = watermarks.update(partition, time, Instant::now()) {
+                            releases.insert(*cap.time(), aligned);
+                            notificator.notify_at(cap.retain());
                         }
                     }
-                }
-            }
-        })
+                });
+
+                // released once the data sent before the watermarks has arrived
+                notificator.for_each(|cap, _, _| {
+                    let aligned = releases.remove(cap.time()).unwrap();
+                    // Even the watermark will propagate so that we report data all the time
+                    // for the first 2 tasks
+                    let watermark = D::from_watermark(&aligned.to_string(), index);
+                    // the data past the watermark waits for the next one
+                    let (mut ready, later): (Vec<_>, Vec<_>) =
+                        data_stash.drain(..).partition(|(time, _)| *time <= aligned);
+                    data_stash = later;
+                    ready.push((aligned, watermark));
+                    ready.sort_by(|(t1, _), (t2, _)| t1.partial_cmp(t2).unwrap());
+
+                    let mut session = output.session(&cap);
+                    for (_post_time, post) in ready {
+                        session.give(post);
+                    }
+                });
+            },
+        )
     }
 }
 
@@ -59,7 +101,7 @@ mod buffers_tests {
     use timely::dataflow::InputHandle;
     use timely::dataflow::operators::{Input, Inspect, Probe};
 
-    use crate::operators::buffer::Buffer;
+    use crate::operators::buffer::{Buffer, IDLE_AFTER};
 
     #[test]
     fn test_buffer_emits_correct_batches() {
@@ -80,7 +122,7 @@ mod buffers_tests {
 
             let probe = worker.dataflow(|scope| {
                 let posts = scope.input_from(&mut posts_input);
-                posts.buffer(Pipeline).inspect_batch(|t, xs: &[Post]| match t {
+                posts.buffer(Pipeline, IDLE_AFTER).inspect_batch(|t, xs: &[Post]| match t {
                     8  => assert_eq!(xs.iter().map(|x| x.timestamp).collect::<Vec<_>>(), vec![1,3,7,10]),
                     13 => assert_eq!(xs.iter().map(|x| x.timestamp).collect::<Vec<_>>(), vec![11,12,13,15]),
                     _  => unreachable!(),
@@ -104,4 +146,51 @@ mod buffers_tests {
         })
         .unwrap();
     }
+
+    #[test]
+    fn test_buffer_waits_for_the_slowest_partition() {
+        timely::execute(timely::Configuration::Process(2), |worker| {
+            let index = worker.index();
+            let mut posts_input = InputHandle::new();
+
+            let default_post = Post{is_watermark:false, ..Post::from_watermark("0", 0)};
+            let post = |timestamp| Post{timestamp, ..default_post.clone()};
+            // partition 0 is ahead of partition 1
+            let batches = match index {
+                0 => vec![
+                    (10, vec![post(1), post(5), Post::from_watermark("10", 0)]),
+                    (20, vec![post(12), Post::from_watermark("20", 0)]),
+                ],
+                _ => vec![
+                    (10, vec![post(2), Post::from_watermark("5", 1)]),
+                    (20, vec![post(8), Post::from_watermark("10", 1)]),
+                ],
+            };
+
+            let probe = worker.dataflow(|scope| {
+                let posts = scope.input_from(&mut posts_input);
+                posts.buffer(Pipeline, IDLE_AFTER).inspect_batch(move |t, xs: &[Post]| {
+                    let released = xs.iter().map(|x| x.timestamp).collect::<Vec<_>>();
+                    match (index, t) {
+                        // the data past the aligned watermark stays buffered
+                        (0, 0)  => assert_eq!(released, vec![1,5,5]),
+                        (0, 10) => assert_eq!(released, vec![10]),
+                        (1, 0)  => assert_eq!(released, vec![2,5]),
+                        (1, 10) => assert_eq!(released, vec![8,10]),
+                        _       => unreachable!(),
+                    }
+                });
+                posts.probe()
+            });
+
+            for (t, mut data) in batches {
+                posts_input.send_batch(&mut data);
+                posts_input.advance_to(t);
+                while probe.less_than(posts_input.time()) {
+                     worker.step();
+                }
+            }
+        })
+        .unwrap();
+    }
 }
@@ -51,7 +51,10 @@ where
                 });
 
                 notificator.for_each(|cap, _, notificator| {
-                    notificator.notify_at(cap.delayed(&(cap.time() + collection_period)));
+                    // stop once the input is exhausted, so that the dataflow can complete
+                    if !notificator.frontier(0).is_empty() {
+                        notificator.notify_at(cap.delayed(&(cap.time() + collection_period)));
+                    }
 
                     let mut session = output.session(&cap);
                     for (post_id, _) in active_post_snapshot.drain(..) {
@@ -9,10 +9,11 @@ use crate::dto::common::{Timestamped, Watermarkable};
 use crate::dto::post::Post;
 
 use crate::dsa::dsu::*;
-use crate::dsa::stash::*;
-
-use std::collections::binary_heap::BinaryHeap;
+use crate::dsa::pending::Pending;
+use crate::dsa::stash::Stash;
 
+// Links the comments and replies to the thread of their root post, whose id they carry
+// in `reply_to_post_id` once routed by `route_replies`.
 pub trait LinkReplies<G, P, P2>
 where
     G: Scope,
@@ -25,13 +26,38 @@ where
         c_pact: P,
         p_pact: P2,
         delay: usize,
+        horizon: usize,
+        orphan_timeout: usize,
     ) -> Stream<G, Comment>;
 }
 
-const POST: u32 = 0;
-const COMMENT: u32 = 1;
+// threads without comments for this long are forgotten, so later replies are not counted
+pub const THREAD_HORIZON: usize = 7 * 24 * 60 * 60; // seconds
+
+// comments whose parent has not arrived for this long are dropped
+pub const ORPHAN_TIMEOUT: usize = 60 * 60; // seconds
+
+pub const POST: u32 = 0;
+pub const COMMENT: u32 = 1;
+
+// How long the comment threads and the comments waiting for their parent are kept.
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadConfig {
+    pub horizon: usize,        // seconds
+    pub orphan_timeout: usize, // seconds
+}
+
+impl Default for ThreadConfig {
+    fn default() -> Self {
+        ThreadConfig {
+            horizon: THREAD_HORIZON,
+            orphan_timeout: ORPHAN_TIMEOUT,
+        }
+    }
+}
 
-type Node = (u32, u32);
+// A post or a comment, told apart by the first id.
+pub type Node = (u32, u32);
 
 fn add_edge(dsu: &mut Dsu<Node, Option<u32>>, parent: Node, child: Node) {
     match dsu.value(child.clone()) {
@@ -49,6 +75,26 @@ fn add_edge(dsu: &mut Dsu<Node, Option<u32>>, parent: Node, child: Node) {
     }
 }
 
+// Links the comment to the thread of its post and returns it. If the post has not arrived
+// yet, the comment waits for it instead.
+fn link(
+    dsu: &mut Dsu<Node, Option<u32>>,
+    orphans: &mut Pending<Node, Comment>,
+    comment: Comment,
+) -> Option<Comment> {
+    let parent = (POST, comment.reply_to_post_id?);
+    if !dsu.contains(&parent) {
+        orphans.park(parent, comment.timestamp, comment);
+        return None;
+    }
+
+    let node = (COMMENT, comment.id);
+    dsu.insert(node, None);
+    add_edge(dsu, parent, node);
+    dsu.touch(node, comment.timestamp);
+    Some(comment)
+}
+
 impl<G, P, P2> LinkReplies<G, P, P2> for Stream<G, Comment>
 where
     G: Scope<Timestamp = usize>,
@@ -61,9 +107,14 @@ where
         c_pact: P,
         p_pact: P2,
         delay: usize,
+        horizon: usize,
+        orphan_timeout: usize,
     ) -> Stream<G, Comment> {
         let mut dsu: Dsu<Node, Option<u32>> = Dsu::new();
         let mut comments_buffer: Stash<Comment> = Stash::new();
+        // map: missing post or comment -> comments waiting for it
+        let mut orphans: Pending<Node, Comment> = Pending::new(orphan_timeout);
+        let mut new_posts = Vec::new();
 
         self.binary_notify(
             posts,
@@ -88,6 +139,8 @@ where
                     for post in p_data.drain(..) {
                         // insert posts values into DSU
                         dsu.insert((POST, post.id), Some(post.id));
+                        dsu.touch((POST, post.id), post.timestamp);
+                        new_posts.push(post.id);
                     }
 
                     // notify when all the posts and comments before the current
@@ -97,68 +150,50 @@ where
 
                 notificator.for_each(|cap, _, _| {
                     let time = *cap.time();
-                    let mut replies = BinaryHeap::new();
-                    let mut all_comments = Vec::new();
+                    let mut watermarks = Vec::new();
+                    let mut linked = Vec::new();
 
-                    // handle comments and stash replies
-                    for comment in comments_buffer.extract(delay, time) {
-                        all_comments.push(comment.clone());
-
-                        // if the comment is a watermark we don't process it
-                        if !comment.is_watermark {
-                            match comment.reply_to_post_id {
-                                Some(post_id) => {
-                                    // insert comment if post present
-                                    match dsu.value((POST, post_id)) {
-                                        Some(_) => dsu.insert((COMMENT, comment.id), None),
-                                        None => { /* the post is on a different worker */ }
-                                    }
-
-                                    // add edge
-                                    add_edge(&mut dsu, (POST, post_id), (COMMENT, comment.id));
-                                }
-                                None => {
-                                    // reply
-                                    replies.push(comment);
-                                }
-                            }
+                    // the comments waiting for the posts received since
+                    for post_id in new_posts.drain(..) {
+                        for comment in orphans.resolve(&(POST, post_id)) {
+                            linked.extend(link(&mut dsu, &mut orphans, comment));
                         }
                     }
 
-                    // handle replies
-                    for reply in replies.into_sorted_vec().drain(..) {
-                        match reply.reply_to_comment_id {
-                            Some(comm_id) => {
-                                // insert reply if comment present
-                                match dsu.value((COMMENT, comm_id)) {
-                                    Some(_) => dsu.insert((COMMENT, reply.id), None),
-                                    None => { /* the comment is on a different worker */ }
-                                }
-
-                                // add edge
-                                add_edge(&mut dsu, (COMMENT, comm_id), (COMMENT, reply.id));
-                            }
-                            None => { /* reply attached to nothing */ }
+                    for comment in comments_buffer.extract(delay, time) {
+                        if comment.is_watermark() {
+                            watermarks.push(comment);
+                        } else {
+                            linked.extend(link(&mut dsu, &mut orphans, comment));
                         }
                     }
 
-                    // attach the post_id to all comments/replies
                     let mut session = output.session(&cap);
-                    for mut comment in all_comments.drain(..) {
-                        if comment.is_watermark() {
-                            session.give(comment);
-                            continue;
-                        }
+                    for comment in linked.drain(..).chain(watermarks.drain(..)) {
+                        session.give(comment);
+                    }
 
-                        match dsu.value((COMMENT, comment.id)) {
-                            None => { /* comment/reply is not attached to our post */ }
-                            Some(maybe_post) => {
-                                if let Some(post) = maybe_post {
-                                    comment.reply_to_post_id = Some(*post);
-                                    session.give(comment);
-                                }
-                            }
-                        }
+                    // forget the threads inactive for longer than the horizon, the
+                    // comments whose parent never arrived, and the comments arriving after
+                    // their window
+                    let expired = dsu.expire(time.saturating_sub(horizon));
+                    let late = comments_buffer.evict(time.saturating_sub(delay));
+                    let dropped = orphans.expire(time);
+                    if expired > 0 || dropped > 0 || late > 0 {
+                        println!(
+                            "Threads @t={}: {} posts and comments in {} threads ({} bytes), \
+                             dropped {} orphaned comments ({} in total) and {} late comments, \
+                             {} comments stashed at {} times",
+                            time,
+                            dsu.len(),
+                            dsu.sets(),
+                            dsu.memory(),
+                            dropped,
+                            orphans.dropped(),
+                            late,
+                            comments_buffer.len(),
+                            comments_buffer.times()
+                        );
                     }
                 });
             },
@@ -200,11 +235,23 @@ mod link_replies_tests {
             let comments_data = vec![
                 Comment{id:1, reply_to_post_id: Some(1), timestamp:5, ..default_comment.clone()},
                 Comment{id:2, reply_to_post_id: Some(3), timestamp:5, ..default_comment.clone()},
-                Comment{id:3, reply_to_comment_id: Some(2), timestamp:6, ..default_comment.clone()},
+                Comment{
+                    id:3, reply_to_post_id: Some(3), reply_to_comment_id: Some(2), timestamp:6,
+                    ..default_comment.clone()
+                },
                 Comment::from_watermark("10", 0),
-                Comment{id:4, reply_to_comment_id: Some(1), timestamp:11, ..default_comment.clone()},
-                Comment{id:5, reply_to_comment_id: Some(3), timestamp:12, ..default_comment.clone()},
-                Comment{id:6, reply_to_comment_id: Some(2), timestamp:13, ..default_comment.clone()},
+                Comment{
+                    id:4, reply_to_post_id: Some(1), reply_to_comment_id: Some(1), timestamp:11,
+                    ..default_comment.clone()
+                },
+                Comment{
+                    id:5, reply_to_post_id: Some(3), reply_to_comment_id: Some(3), timestamp:12,
+                    ..default_comment.clone()
+                },
+                Comment{
+                    id:6, reply_to_post_id: Some(3), reply_to_comment_id: Some(2), timestamp:13,
+                    ..default_comment.clone()
+                },
                 Comment::from_watermark("15", 0),
                 Comment::from_watermark("20", 0),
             ];
@@ -225,6 +272,8 @@ mod link_replies_tests {
                     Pipeline,
                     Pipeline,
                     5,
+                    100,
+                    10,
                 ).inspect_batch(move |t, xs: &[Comment]| match t {
                     10  => assert_eq!(get_reply_to_post_id(xs), vec![1, 3, 3]),
                     15  => assert_eq!(get_reply_to_post_id(xs), vec![1, 3, 3]),
@@ -255,4 +304,84 @@ mod link_replies_tests {
         })
         .unwrap();
     }
+
+    #[test]
+    fn test_link_replies_waits_for_late_parents() {
+        timely::execute_from_args(std::env::args(), |worker| {
+            let mut posts_input = InputHandle::new();
+            let mut comments_input = InputHandle::new();
+
+            let default_post = Post{is_watermark:false, ..Post::from_watermark("0", 0)};
+            let default_comment = Comment{is_watermark:false, ..Comment::from_watermark("0", 0)};
+            let comments_data = vec![
+                Comment{id:1, reply_to_post_id: Some(1), timestamp:5, ..default_comment.clone()},
+                Comment{
+                    id:2, reply_to_post_id: Some(1), reply_to_comment_id: Some(1), timestamp:6,
+                    ..default_comment.clone()
+                },
+                // the post of this reply never arrives
+                Comment{
+                    id:3, reply_to_post_id: Some(9), reply_to_comment_id: Some(8), timestamp:6,
+                    ..default_comment.clone()
+                },
+                Comment::from_watermark("10", 0),
+            ];
+
+            let (posts_probe, comments_probe) = worker.dataflow(|scope| {
+                let posts = scope.input_from(&mut posts_input);
+                let comments = scope.input_from(&mut comments_input);
+
+                let get_ids = |xs: &[Comment]| xs.iter()
+                    .filter(|x| !x.is_watermark())
+                    .map(|x| (x.id, x.reply_to_post_id.unwrap()))
+                    .collect::<Vec<_>>();
+
+                comments.link_replies(
+                    &posts,
+                    Pipeline,
+                    Pipeline,
+                    5,
+                    100,
+                    10,
+                ).inspect_batch(move |t, xs: &[Comment]| match t {
+                    15 => assert_eq!(get_ids(xs), vec![(1, 1), (2, 1)]),
+                    20 => assert_eq!(get_ids(xs), vec![(4, 1)]),
+                    _  => assert_eq!(get_ids(xs), vec![]),
+                });
+
+                (posts.probe(), comments.probe())
+            });
+
+            // the post arrives after the window of its comments is closed
+            let batches = vec![
+                (10, vec![Post::from_watermark("10", 0)], comments_data),
+                (15, vec![Post::from_watermark("15", 0)], vec![Comment::from_watermark("15", 0)]),
+                (20, vec![
+                    Post{id:1, timestamp:7, ..default_post.clone()},
+                    Post::from_watermark("20", 0),
+                ], vec![
+                    Comment{
+                        id:4, reply_to_post_id: Some(1), reply_to_comment_id: Some(2), timestamp:16,
+                        ..default_comment.clone()
+                    },
+                    Comment::from_watermark("20", 0),
+                ]),
+                (30, vec![Post::from_watermark("30", 0)], vec![Comment::from_watermark("30", 0)]),
+            ];
+            for (t, mut p_data, mut c_data) in batches {
+                posts_input.send_batch(&mut p_data);
+                posts_input.advance_to(t);
+                comments_input.send_batch(&mut c_data);
+                comments_input.advance_to(t);
+
+                while posts_probe.less_than(posts_input.time()) {
+                     worker.step();
+                }
+                while comments_probe.less_than(comments_input.time()) {
+                     worker.step();
+                }
+            }
+        })
+        .unwrap();
+    }
 }
@@ -1,8 +1,14 @@
 pub mod buffer;
+pub mod periodic;
 pub mod source;
 
 pub mod active_posts;
 pub mod link_replies;
+pub mod route_replies;
+pub mod thread_trees;
 
 pub mod engaged_users;
 pub mod post_counts;
+
+#[cfg(test)]
+mod properties;
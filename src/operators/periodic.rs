@@ -0,0 +1,111 @@
+extern crate timely;
+
+use timely::dataflow::operators::generic::Notificator;
+use timely::dataflow::operators::Capability;
+
+// Notifies an operator at every multiple of `period`, once the watermark passes it. The
+// boundaries do not depend on when the first input arrives, quiet periods are notified
+// like the others, and the inputs are flushed once they are exhausted.
+pub struct Periodic {
+    period: usize,
+    // the number of inputs of the operator
+    inputs: usize,
+}
+
+impl Periodic {
+    pub fn new(period: usize, inputs: usize) -> Periodic {
+        Periodic { period, inputs }
+    }
+
+    // The first boundary strictly after `time`. The input received at `time` is notified
+    // there; both the input and `next` may ask for the same boundary, which is only
+    // notified once.
+    pub fn boundary(&self, time: usize) -> usize {
+        (time / self.period + 1) * self.period
+    }
+
+    // Called at every notified boundary, and asks for the next one, unless the inputs are
+    // exhausted, so that the dataflow can complete.
+    pub fn next(&self, cap: &Capability<usize>, notificator: &mut Notificator<usize>) {
+        if (0..self.inputs).any(|input| !notificator.frontier(input).is_empty()) {
+            notificator.notify_at(cap.delayed(&self.boundary(*cap.time())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod periodic_tests {
+    extern crate timely;
+
+    use crate::operators::periodic::Periodic;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use timely::dataflow::channels::pact::Pipeline;
+    use timely::dataflow::operators::generic::operator::Operator;
+    use timely::dataflow::operators::{Input, Inspect, Probe};
+    use timely::dataflow::InputHandle;
+
+    #[test]
+    fn test_boundaries_are_aligned_to_the_period() {
+        let periodic = Periodic::new(10, 1);
+        assert_eq!(periodic.boundary(0), 10);
+        assert_eq!(periodic.boundary(9), 10);
+        assert_eq!(periodic.boundary(10), 20);
+        assert_eq!(periodic.boundary(23), 30);
+    }
+
+    #[test]
+    fn test_every_boundary_is_notified_once() {
+        let notified = timely::execute(timely::Configuration::Thread, |worker| {
+            let mut input = InputHandle::new();
+            let notified = Rc::new(RefCell::new(vec![]));
+            let outputs = notified.clone();
+
+            let probe = worker.dataflow(|scope| {
+                let periodic = Periodic::new(10, 1);
+                scope
+                    .input_from(&mut input)
+                    .unary_notify(
+                        Pipeline,
+                        "Periodic",
+                        None,
+                        move |input, output, notificator| {
+                            input.for_each(|cap, data| {
+                                data.swap(&mut vec![]);
+                                let boundary = periodic.boundary(*cap.time());
+                                notificator.notify_at(cap.delayed(&boundary));
+                            });
+                            notificator.for_each(|cap, _, notificator| {
+                                periodic.next(&cap, notificator);
+                                output.session(&cap).give(*cap.time());
+                            });
+                        },
+                    )
+                    .inspect(move |time: &usize| outputs.borrow_mut().push(*time))
+                    .probe()
+            });
+
+            // the input starts in the middle of a period, is quiet between 25 and 52, and
+            // its last record is only flushed when it is closed
+            for time in vec![3, 7, 25, 52, 61] {
+                input.advance_to(time);
+                input.send(time);
+                while probe.less_than(input.time()) {
+                    worker.step();
+                }
+            }
+            drop(input);
+            while worker.step() {}
+
+            notified.replace(vec![])
+        })
+        .unwrap()
+        .join();
+
+        assert_eq!(
+            notified.into_iter().next().unwrap().unwrap(),
+            vec![10, 20, 30, 40, 50, 60, 70]
+        );
+    }
+}
@@ -93,7 +93,10 @@ where
                 });
 
                 notificator.for_each(|cap, _, notificator| {
-                    notificator.notify_at(cap.delayed(&(cap.time() + collection_period)));
+                    // stop once the inputs are exhausted, so that the dataflow can complete
+                    if !notificator.frontier(0).is_empty() || !notificator.frontier(1).is_empty() {
+                        notificator.notify_at(cap.delayed(&(cap.time() + collection_period)));
+                    }
                     let mut session = output.session(&cap);
                     for post_id in active_posts_at_time
                         .remove(cap.time())
@@ -0,0 +1,558 @@
+extern crate rand;
+extern crate timely;
+
+// Randomised checks of the watermark and windowing invariants of the operators. Every case
+// is generated from its seed, which is reported when an invariant does not hold.
+
+use crate::connection::delay::*;
+use crate::dto::comment::Comment;
+use crate::dto::common::{Timestamped, Watermarkable};
+use crate::dto::like::Like;
+use crate::dto::post::Post;
+use crate::operators::active_posts::ActivePosts;
+use crate::operators::buffer::{Buffer, IDLE_AFTER};
+use crate::operators::link_replies::LinkReplies;
+use crate::operators::post_counts::PostCounts;
+use crate::operators::route_replies::RouteReplies;
+
+use crate::tasks::harness::{execute, Inputs};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::{Input, Inspect, Probe};
+use timely::dataflow::InputHandle;
+use timely::{Configuration, Data};
+
+const CASES: u64 = 50;
+const DELAY: usize = 10; // seconds
+const SPAN: usize = 30 * DELAY; // seconds
+const ACTIVE_POST_PERIOD: usize = 5 * DELAY; // seconds
+const COLLECTION_PERIOD: usize = 3 * DELAY; // seconds
+
+const POSTS: u32 = 8;
+const PERSONS: u32 = 6;
+
+// The messages given to an input, as (time, batch), in the order they are given.
+type Messages<D> = Vec<(usize, Vec<D>)>;
+
+fn default_post() -> Post {
+    Post {
+        is_watermark: false,
+        ..Post::from_watermark("0", 0)
+    }
+}
+
+fn default_comment() -> Comment {
+    Comment {
+        is_watermark: false,
+        ..Comment::from_watermark("0", 0)
+    }
+}
+
+struct Generator {
+    rng: StdRng,
+    delay: Box<dyn DelayModel>,
+    start: usize,
+    end: usize,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let delay: Box<dyn DelayModel> = match rng.gen_range(0, 5) {
+            0 => Box::new(NoDelay::new(DELAY)),
+            1 => Box::new(Uniform::new(DELAY)),
+            2 => Box::new(Exponential::new(DELAY as f64 / 4., DELAY)),
+            3 => Box::new(Pareto::new(1., 1.2, DELAY)),
+            _ => Box::new(BurstReorder::new(0.2, 5, DELAY)),
+        };
+        // the watermarks are placed at a random offset
+        let start = rng.gen_range(1, 1000);
+
+        Generator {
+            rng,
+            delay,
+            start,
+            end: start,
+        }
+    }
+
+    fn timestamp(&mut self, from: usize, length: usize) -> usize {
+        let timestamp = from + self.rng.gen_range(0, length);
+        self.end = self.end.max(timestamp);
+        timestamp
+    }
+
+    fn post(&mut self) -> u32 {
+        self.rng.gen_range(1, POSTS + 1)
+    }
+
+    fn person(&mut self) -> u32 {
+        self.rng.gen_range(1, PERSONS + 1)
+    }
+
+    // A watermark every DELAY seconds, continuing after the last event so that every
+    // delayed event is released and every window is closed.
+    fn watermarks(&self) -> Vec<usize> {
+        let mut watermarks = vec![self.start];
+        while *watermarks.last().unwrap() <= self.end + 3 * DELAY {
+            let next = watermarks.last().unwrap() + DELAY;
+            watermarks.push(next);
+        }
+        watermarks
+    }
+
+    // Posts, and comments and replies forming threads under them, with the root post of
+    // every comment. Comments may be created right after their post, so they can arrive
+    // before it.
+    fn threads(
+        &mut self,
+        posts: u32,
+        comments: u32,
+    ) -> (Vec<Post>, Vec<Comment>, HashMap<u32, u32>) {
+        let mut all_posts = vec![];
+        for id in 1..=posts {
+            let timestamp = self.timestamp(self.start, SPAN);
+            all_posts.push(Post {
+                id,
+                person_id: self.person(),
+                timestamp,
+                ..default_post()
+            });
+        }
+
+        let mut all_comments: Vec<Comment> = vec![];
+        let mut roots = HashMap::new();
+        for id in 1..=comments {
+            let comment = if all_comments.is_empty() || self.rng.gen_bool(0.4) {
+                let post = &all_posts[self.rng.gen_range(0, all_posts.len())];
+                roots.insert(id, post.id);
+                Comment {
+                    id,
+                    person_id: self.person(),
+                    reply_to_post_id: Some(post.id),
+                    timestamp: self.timestamp(post.timestamp + 1, 3 * DELAY),
+                    ..default_comment()
+                }
+            } else {
+                let parent = &all_comments[self.rng.gen_range(0, all_comments.len())];
+                let root = roots[&parent.id];
+                roots.insert(id, root);
+                Comment {
+                    id,
+                    person_id: self.person(),
+                    reply_to_comment_id: Some(parent.id),
+                    timestamp: self.timestamp(parent.timestamp + 1, 3 * DELAY),
+                    ..default_comment()
+                }
+            };
+            all_comments.push(comment);
+        }
+
+        (all_posts, all_comments, roots)
+    }
+
+    // Replays the records as the Kafka source emits them: each record arrives after a
+    // delay drawn from the delay model and is given at the time of the last watermark
+    // before its arrival, in batches of random size.
+    fn replay<D: Data + Timestamped + Watermarkable>(
+        &mut self,
+        mut records: Vec<D>,
+    ) -> Messages<D> {
+        records.sort_by_key(|record| record.timestamp());
+        let mut arrivals = vec![];
+        for record in records {
+            let creation_time = record.timestamp();
+            let arrival = creation_time + self.delay.delay(&mut self.rng, creation_time);
+            arrivals.push((arrival, record));
+        }
+        arrivals.sort_by_key(|(arrival, _)| *arrival);
+
+        let mut messages = vec![];
+        let mut arrivals = arrivals.into_iter().peekable();
+        for watermark in self.watermarks() {
+            messages.push((
+                watermark,
+                vec![D::from_watermark(&watermark.to_string(), 0)],
+            ));
+
+            let mut batch = vec![];
+            while arrivals
+                .peek()
+                .map_or(false, |(arrival, _)| *arrival < watermark + DELAY)
+            {
+                batch.push(arrivals.next().unwrap().1);
+                if self.rng.gen_bool(0.3) {
+                    messages.push((watermark, batch));
+                    batch = vec![];
+                }
+            }
+            if !batch.is_empty() {
+                messages.push((watermark, batch));
+            }
+        }
+        messages
+    }
+
+    // Gives the records as the windowed operators expect them: each record is given
+    // either as it happens or at the watermark closing its window.
+    fn windowed<D: Data + Timestamped + Watermarkable>(&mut self, records: Vec<D>) -> Messages<D> {
+        let mut messages: Messages<D> = self
+            .watermarks()
+            .into_iter()
+            .map(|watermark| {
+                (
+                    watermark,
+                    vec![D::from_watermark(&watermark.to_string(), 0)],
+                )
+            })
+            .collect();
+        for record in records {
+            let window = (record.timestamp() - self.start) / DELAY;
+            let time = self.start + (window + self.rng.gen_range(0, 2)) * DELAY;
+            messages.push((time, vec![record]));
+        }
+        // the sort is stable, so the watermarks stay first
+        messages.sort_by_key(|(time, _)| *time);
+        messages
+    }
+}
+
+// An input given the messages at their time.
+struct Feed<D: Data> {
+    input: InputHandle<usize, D>,
+    messages: Messages<D>,
+}
+
+impl<D: Data> Feed<D> {
+    fn new(messages: Messages<D>) -> Feed<D> {
+        Feed {
+            input: InputHandle::new(),
+            messages,
+        }
+    }
+}
+
+impl<D: Data> Inputs for Feed<D> {
+    fn advance(&mut self, time: usize) {
+        self.input.advance_to(time);
+        for (_, batch) in self.messages.iter().filter(|(t, _)| *t == time) {
+            self.input.send_batch(&mut batch.clone());
+        }
+    }
+}
+
+#[test]
+fn test_buffer_releases_every_record_once_and_in_order() {
+    for seed in 0..CASES {
+        let mut generator = Generator::new(seed);
+        let start = generator.start;
+        let mut posts = vec![];
+        for id in 1..=50 {
+            let timestamp = generator.timestamp(start, SPAN);
+            posts.push(Post {
+                id,
+                timestamp,
+                ..default_post()
+            });
+        }
+        let messages = generator.replay(posts);
+        let watermarks = generator.watermarks();
+
+        // a record is released by the first watermark it does not pass
+        let mut expected = HashMap::new();
+        for (time, batch) in messages.iter() {
+            for post in batch.iter().filter(|post| !post.is_watermark()) {
+                let release = if post.timestamp <= *time {
+                    *time
+                } else {
+                    time + DELAY
+                };
+                expected.insert(post.id, release);
+            }
+        }
+        let expected_watermarks = watermarks.clone();
+
+        let outputs = execute(Configuration::Thread, move |worker, outputs, probe| {
+            let mut posts = Feed::new(messages.clone());
+            worker.dataflow(|scope| {
+                let outputs = outputs.clone();
+                scope
+                    .input_from(&mut posts.input)
+                    .buffer(Pipeline, IDLE_AFTER)
+                    .inspect_batch(move |t, xs: &[Post]| {
+                        for x in xs {
+                            outputs.borrow_mut().push((*t, x.clone()));
+                        }
+                    })
+                    .probe_with(probe);
+            });
+            (posts, watermarks.clone())
+        });
+
+        let mut released = HashMap::new();
+        let mut released_watermarks = vec![];
+        let mut last = (0, 0);
+        for (time, post) in outputs {
+            // ordered within the release of each watermark
+            assert!(
+                time != last.0 || post.timestamp >= last.1,
+                "seed {}: release at {} is not ordered",
+                seed,
+                time
+            );
+            last = (time, post.timestamp);
+
+            if post.is_watermark() {
+                assert_eq!(post.timestamp, time, "seed {}", seed);
+                released_watermarks.push(time);
+            } else {
+                assert!(
+                    released.insert(post.id, time).is_none(),
+                    "seed {}: post {} released twice",
+                    seed,
+                    post.id
+                );
+            }
+        }
+        assert_eq!(released_watermarks, expected_watermarks, "seed {}", seed);
+        assert_eq!(released, expected, "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_routed_replies_are_linked_to_their_root_post_once() {
+    for seed in 0..CASES {
+        let mut generator = Generator::new(seed);
+        let (posts, comments, roots) = generator.threads(20, 80);
+        let timestamps: HashMap<u32, usize> =
+            comments.iter().map(|c| (c.id, c.timestamp)).collect();
+        let posts = generator.replay(posts);
+        let comments = generator.replay(comments);
+        let watermarks = generator.watermarks();
+
+        let outputs = execute(Configuration::Thread, move |worker, outputs, probe| {
+            let mut posts = Feed::new(posts.clone());
+            let mut comments = Feed::new(comments.clone());
+            worker.dataflow(|scope| {
+                let outputs = outputs.clone();
+                let posts = scope
+                    .input_from(&mut posts.input)
+                    .buffer(Pipeline, IDLE_AFTER);
+                scope
+                    .input_from(&mut comments.input)
+                    .route_replies(SPAN, SPAN)
+                    .link_replies(&posts, Pipeline, Pipeline, DELAY, SPAN, SPAN)
+                    .inspect_batch(move |t, xs: &[Comment]| {
+                        for x in xs.iter().filter(|x| !x.is_watermark()) {
+                            outputs.borrow_mut().push((*t, x.id, x.reply_to_post_id));
+                        }
+                    })
+                    .probe_with(probe);
+            });
+            ((posts, comments), watermarks.clone())
+        });
+
+        let mut linked = HashMap::new();
+        for (time, id, post_id) in outputs {
+            // emitted by the watermark closing the window of the comment, or, when the
+            // post was released after it, by the watermark releasing the post
+            let timestamp = timestamps[&id];
+            assert!(
+                timestamp < time && time <= timestamp + 2 * DELAY,
+                "seed {}: comment {} created at {} emitted at {}",
+                seed,
+                id,
+                timestamp,
+                time
+            );
+            assert_eq!(post_id, Some(roots[&id]), "seed {}: comment {}", seed, id);
+            assert!(
+                linked.insert(id, time).is_none(),
+                "seed {}: comment {} emitted twice",
+                seed,
+                id
+            );
+        }
+        assert_eq!(
+            linked.len(),
+            roots.len(),
+            "seed {}: comments were lost",
+            seed
+        );
+    }
+}
+
+#[test]
+fn test_active_posts_match_batch_recomputation() {
+    for seed in 0..CASES {
+        let mut generator = Generator::new(seed);
+        let start = generator.start;
+        let mut comments = vec![];
+        let mut likes = vec![];
+        let mut activity = vec![];
+        for id in 1..=60 {
+            let timestamp = generator.timestamp(start, SPAN);
+            let (post_id, person_id) = (generator.post(), generator.person());
+            activity.push((timestamp, post_id, person_id));
+            if generator.rng.gen_bool(0.5) {
+                comments.push(Comment {
+                    id,
+                    person_id,
+                    reply_to_post_id: Some(post_id),
+                    timestamp,
+                    ..default_comment()
+                });
+            } else {
+                likes.push(Like {
+                    person_id,
+                    post_id,
+                    timestamp,
+                    is_watermark: false,
+                });
+            }
+        }
+        let comments = generator.windowed(comments);
+        let likes = generator.windowed(likes);
+        let watermarks = generator.watermarks();
+
+        // active posts have been engaged with in the period before the watermark, along
+        // with everyone that ever engaged with them
+        let mut expected = vec![];
+        for &time in watermarks.iter() {
+            let time_lhs = if time > ACTIVE_POST_PERIOD {
+                time - ACTIVE_POST_PERIOD
+            } else {
+                0
+            };
+            let mut last_active = HashMap::new();
+            let mut persons = HashMap::new();
+            for &(timestamp, post_id, person_id) in activity.iter().filter(|(t, _, _)| *t < time) {
+                let last = last_active.entry(post_id).or_insert(timestamp);
+                *last = timestamp.max(*last);
+                persons
+                    .entry(post_id)
+                    .or_insert_with(BTreeSet::new)
+                    .insert(person_id);
+            }
+            for (post_id, last) in last_active {
+                if last > time_lhs {
+                    expected.push((time, post_id, persons[&post_id].clone()));
+                }
+            }
+        }
+        expected.sort();
+
+        let mut outputs = execute(Configuration::Thread, move |worker, outputs, probe| {
+            let mut comments = Feed::new(comments.clone());
+            let mut likes = Feed::new(likes.clone());
+            worker.dataflow(|scope| {
+                let outputs = outputs.clone();
+                let likes = scope.input_from(&mut likes.input);
+                scope
+                    .input_from(&mut comments.input)
+                    .active_post_ids(&likes, Pipeline, Pipeline, DELAY, ACTIVE_POST_PERIOD)
+                    .inspect_batch(move |t, xs: &[(u32, HashSet<u32>)]| {
+                        for (post_id, persons) in xs {
+                            let persons: BTreeSet<u32> = persons.iter().cloned().collect();
+                            outputs.borrow_mut().push((*t, *post_id, persons));
+                        }
+                    })
+                    .probe_with(probe);
+            });
+            ((comments, likes), watermarks.clone())
+        });
+        outputs.sort();
+
+        assert_eq!(outputs, expected, "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_post_counts_match_batch_recomputation() {
+    for seed in 0..CASES {
+        let mut generator = Generator::new(seed);
+        let start = generator.start;
+        let mut comments = vec![];
+        for id in 1..=60 {
+            let timestamp = generator.timestamp(start, SPAN);
+            let reply_to_comment_id = if generator.rng.gen_bool(0.5) {
+                Some(0)
+            } else {
+                None
+            };
+            comments.push(Comment {
+                id,
+                reply_to_post_id: Some(generator.post()),
+                reply_to_comment_id,
+                timestamp,
+                ..default_comment()
+            });
+        }
+        let comments = generator.windowed(comments);
+        let watermarks = generator.watermarks();
+
+        // random sets of active posts
+        let mut active_posts = vec![];
+        for &time in watermarks.iter() {
+            let posts: Vec<(u32, HashSet<u32>)> = (1..=POSTS)
+                .filter(|_| generator.rng.gen_bool(0.5))
+                .map(|post_id| (post_id, HashSet::new()))
+                .collect();
+            if !posts.is_empty() {
+                active_posts.push((time, posts));
+            }
+        }
+
+        // the counts are reported every period after the first comments, for the posts
+        // active at that time, and cover every comment given until then
+        let mut expected = vec![];
+        for (time, posts) in active_posts.iter() {
+            if *time == start || (time - start) % COLLECTION_PERIOD != 0 {
+                continue;
+            }
+            for (post_id, _) in posts {
+                let (mut comment_count, mut reply_count): (usize, usize) = (0, 0);
+                for (_, batch) in comments.iter().filter(|(t, _)| t <= time) {
+                    for comment in batch.iter().filter(|c| !c.is_watermark()) {
+                        if comment.reply_to_post_id != Some(*post_id) {
+                            continue;
+                        }
+                        match comment.reply_to_comment_id {
+                            Some(_) => reply_count += 1,
+                            None => comment_count += 1,
+                        }
+                    }
+                }
+                expected.push((*time, *post_id, comment_count, reply_count));
+            }
+        }
+        expected.sort();
+
+        let mut outputs = execute(Configuration::Thread, move |worker, outputs, probe| {
+            let mut posts = Feed::new(active_posts.clone());
+            let mut comments = Feed::new(comments.clone());
+            worker.dataflow(|scope| {
+                let outputs = outputs.clone();
+                let comments = scope.input_from(&mut comments.input);
+                scope
+                    .input_from(&mut posts.input)
+                    .counts(&comments, Pipeline, Pipeline, COLLECTION_PERIOD)
+                    .inspect_batch(move |t, xs: &[(u32, usize, usize)]| {
+                        for (post_id, comment_count, reply_count) in xs {
+                            outputs
+                                .borrow_mut()
+                                .push((*t, *post_id, *comment_count, *reply_count));
+                        }
+                    })
+                    .probe_with(probe);
+            });
+            ((posts, comments), watermarks.clone())
+        });
+        outputs.sort();
+
+        assert_eq!(outputs, expected, "seed {}", seed);
+    }
+}
@@ -0,0 +1,263 @@
+extern crate timely;
+
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::exchange::Exchange as ExchangeOperator;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::{Concat, ConnectLoop, Enter, Feedback, Filter, Leave};
+use timely::dataflow::{Scope, Stream};
+use timely::order::Product;
+
+use crate::dsa::dsu::Dsu;
+use crate::dsa::pending::Pending;
+use crate::dto::comment::Comment;
+use crate::operators::link_replies::{Node, COMMENT, POST};
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+pub trait RouteReplies<G: Scope> {
+    fn route_replies(&self, horizon: usize, orphan_timeout: usize) -> Stream<G, Comment>;
+}
+
+fn hash(id: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u32(id);
+    hasher.finish()
+}
+
+// Sends every comment and reply to the worker owning its root post, that is the worker
+// the posts are exchanged to, with the id of the root post in `reply_to_post_id`. The
+// watermarks stay on their worker.
+//
+// The root of a reply is resolved by the worker owning its parent comment id: every
+// resolved comment is sent there, and resolves the replies waiting on it in turn, so that
+// the threads are resolved one level per iteration until no reply is left to resolve. The
+// roots of the threads without comments for longer than the horizon are forgotten.
+impl<G: Scope<Timestamp = usize>> RouteReplies<G> for Stream<G, Comment> {
+    fn route_replies(&self, horizon: usize, orphan_timeout: usize) -> Stream<G, Comment> {
+        let index = self.scope().index();
+        let comments = self.filter(|c| c.is_watermark || c.reply_to_post_id.is_some());
+        let replies = self.filter(|c| !c.is_watermark && c.reply_to_post_id.is_none());
+
+        let resolved_replies = self.scope().iterative::<u32, _, _>(|inner| {
+            // the comments resolved, in the set of their root post, whose value is its id
+            let mut roots: Dsu<Node, u32> = Dsu::new();
+            // map: parent comment -> replies waiting for its root
+            let mut waiting: Pending<u32, Comment> = Pending::new(orphan_timeout);
+
+            let (handle, resolved) = inner.feedback(Product::new(0, 1));
+            let resolved_replies = replies.enter(inner).binary(
+                &comments
+                    .filter(|c| !c.is_watermark)
+                    .enter(inner)
+                    .concat(&resolved),
+                Exchange::new(|r: &Comment| hash(r.reply_to_comment_id.unwrap_or(0))),
+                Exchange::new(|c: &Comment| hash(c.id)),
+                "RouteReplies",
+                move |_, _| {
+                    move |r_input, c_input, output| {
+                        // the resolved comments first, so that their replies in the same
+                        // batch are resolved at once
+                        let mut latest = None;
+                        c_input.for_each(|cap, input| {
+                            latest = latest.max(Some(cap.time().outer));
+                            let mut session = output.session(&cap);
+                            for comment in input.iter() {
+                                let root = comment.reply_to_post_id.unwrap();
+                                roots.insert((POST, root), root);
+                                roots.insert((COMMENT, comment.id), root);
+                                roots.union((POST, root), (COMMENT, comment.id));
+                                roots.touch((COMMENT, comment.id), comment.timestamp);
+                                for mut reply in waiting.resolve(&comment.id) {
+                                    reply.reply_to_post_id = Some(root);
+                                    session.give(reply);
+                                }
+                            }
+                        });
+
+                        r_input.for_each(|cap, input| {
+                            latest = latest.max(Some(cap.time().outer));
+                            let mut session = output.session(&cap);
+                            let mut vec = vec![];
+                            input.swap(&mut vec);
+                            for mut reply in vec.drain(..) {
+                                // skip the replies attached to nothing
+                                let parent = match reply.reply_to_comment_id {
+                                    Some(parent) => parent,
+                                    None => continue,
+                                };
+                                match roots.value((COMMENT, parent)) {
+                                    Some(root) => {
+                                        reply.reply_to_post_id = Some(*root);
+                                        session.give(reply);
+                                    }
+                                    None => waiting.park(parent, reply.timestamp, reply),
+                                }
+                            }
+                        });
+
+                        // forget the inactive threads, and drop the replies whose parent
+                        // never arrived
+                        if let Some(time) = latest {
+                            roots.expire(time.saturating_sub(horizon));
+                            let dropped = waiting.expire(time);
+                            if dropped > 0 {
+                                println!(
+                                    "Dropped {} orphaned replies @t={} ({} in total)",
+                                    dropped,
+                                    time,
+                                    waiting.dropped()
+                                );
+                            }
+                        }
+                    }
+                },
+            );
+
+            // the resolved replies resolve their own replies in the next iteration
+            resolved_replies.connect_loop(handle);
+            resolved_replies.leave()
+        });
+
+        comments
+            .concat(&resolved_replies)
+            .exchange(move |c: &Comment| match c.is_watermark {
+                true => index as u64,
+                false => hash(c.reply_to_post_id.unwrap()),
+            })
+    }
+}
+
+#[cfg(test)]
+mod route_replies_tests {
+    extern crate timely;
+
+    use crate::dto::comment::Comment;
+    use crate::dto::common::Watermarkable;
+    use crate::operators::route_replies::{hash, RouteReplies};
+
+    use std::sync::{Arc, Mutex};
+    use timely::dataflow::operators::{Input, Inspect, Probe};
+    use timely::dataflow::InputHandle;
+    use timely::Configuration;
+
+    fn comment(id: u32, post_id: Option<u32>, comment_id: Option<u32>) -> Comment {
+        Comment {
+            id,
+            reply_to_post_id: post_id,
+            reply_to_comment_id: comment_id,
+            is_watermark: false,
+            ..Comment::from_watermark("0", 0)
+        }
+    }
+
+    #[test]
+    fn test_replies_are_routed_to_the_owner_of_their_root_post() {
+        let outputs = Arc::new(Mutex::new(vec![]));
+        let worker_outputs = outputs.clone();
+        timely::execute(Configuration::Process(3), move |worker| {
+            let (index, peers) = (worker.index(), worker.peers());
+            let mut input = InputHandle::new();
+            let outputs = worker_outputs.clone();
+            let probe = worker.dataflow(|scope| {
+                scope
+                    .input_from(&mut input)
+                    .route_replies(100, 100)
+                    .inspect(move |c: &Comment| {
+                        let output = (index, c.id, c.reply_to_post_id, c.is_watermark);
+                        outputs.lock().unwrap().push(output);
+                    })
+                    .probe()
+            });
+
+            // two threads, whose replies arrive before their parents, on any worker
+            let comments = vec![
+                vec![comment(5, None, Some(4)), comment(3, None, Some(2))],
+                vec![comment(4, None, Some(3)), comment(2, None, Some(1))],
+                vec![comment(1, Some(10), None), comment(7, Some(20), None)],
+                vec![comment(8, None, Some(7)), comment(9, None, Some(6))],
+            ];
+            for (time, batch) in comments.into_iter().enumerate() {
+                if time % peers == index {
+                    input.send_batch(&mut batch.clone());
+                }
+                input.send(Comment::from_watermark(&time.to_string(), index));
+                input.advance_to(time + 1);
+                while probe.less_than(input.time()) {
+                    worker.step();
+                }
+            }
+        })
+        .unwrap();
+
+        let mut outputs = outputs.lock().unwrap().clone();
+        outputs.sort();
+        let peers = 3;
+        let watermarks = outputs.iter().filter(|(_, _, _, w)| *w).count();
+        assert_eq!(watermarks, 4 * peers);
+
+        // the reply to the missing comment 6 is never routed
+        let routed: Vec<(usize, u32, Option<u32>)> = outputs
+            .into_iter()
+            .filter(|(_, _, _, watermark)| !watermark)
+            .map(|(worker, id, post_id, _)| (worker, id, post_id))
+            .collect();
+        let owner = |post_id: u32| hash(post_id) as usize % peers;
+        let mut expected: Vec<(usize, u32, Option<u32>)> = vec![
+            (owner(10), 1, Some(10)),
+            (owner(10), 2, Some(10)),
+            (owner(10), 3, Some(10)),
+            (owner(10), 4, Some(10)),
+            (owner(10), 5, Some(10)),
+            (owner(20), 7, Some(20)),
+            (owner(20), 8, Some(20)),
+        ];
+        expected.sort();
+        assert_eq!(routed, expected);
+    }
+
+    #[test]
+    fn test_roots_of_inactive_threads_are_forgotten() {
+        let outputs = Arc::new(Mutex::new(vec![]));
+        let worker_outputs = outputs.clone();
+        timely::execute(Configuration::Thread, move |worker| {
+            let mut input = InputHandle::new();
+            let outputs = worker_outputs.clone();
+            let probe = worker.dataflow(|scope| {
+                scope
+                    .input_from(&mut input)
+                    .route_replies(10, 100)
+                    .inspect(move |c: &Comment| {
+                        if !c.is_watermark {
+                            outputs.lock().unwrap().push((c.id, c.reply_to_post_id));
+                        }
+                    })
+                    .probe()
+            });
+
+            // the thread of post 10 is inactive for longer than the horizon when the
+            // comment on post 30 arrives
+            let comments = vec![
+                (1, comment(1, Some(10), None)),
+                (5, comment(2, None, Some(1))),
+                (20, comment(4, Some(30), None)),
+                (25, comment(3, None, Some(2))),
+            ];
+            for (time, comment) in comments {
+                input.send(Comment {
+                    timestamp: time,
+                    ..comment
+                });
+                input.advance_to(time + 1);
+                while probe.less_than(input.time()) {
+                    worker.step();
+                }
+            }
+        })
+        .unwrap();
+
+        let mut outputs = outputs.lock().unwrap().clone();
+        outputs.sort();
+        assert_eq!(outputs, vec![(1, Some(10)), (2, Some(10)), (4, Some(30))]);
+    }
+}
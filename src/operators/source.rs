@@ -4,11 +4,13 @@ extern crate rdkafka;
 extern crate rdkafka_sys;
 extern crate timely;
 
+use crate::connection::producer::END_OF_STREAM;
 use crate::dto::common::{Importable, Timestamped, Watermarkable};
+use crate::operators::buffer::IDLE_AFTER;
 
 use timely::dataflow::operators::generic::operator::source;
 use timely::dataflow::scopes::Scope;
-use timely::dataflow::Stream;
+use timely::dataflow::{ProbeHandle, Stream};
 use timely::Data;
 
 use rdkafka::config::ClientConfig;
@@ -17,14 +19,100 @@ use rdkafka::message::Message;
 
 use self::rdkafka::consumer::BaseConsumer;
 use csv::StringRecord;
-use rdkafka::TopicPartitionList;
-use std::time::Duration;
+use rdkafka::{Offset, TopicPartitionList};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Where the sources start reading their partition.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StartPosition {
+    Earliest,
+    // only the messages written after the task started
+    Latest,
+    // the offset of every partition, by partition
+    Offsets(Vec<i64>),
+    // the first message of every partition written at or after this event time, in seconds,
+    // found by the message timestamps, which Kafka keeps in milliseconds
+    Time(usize),
+}
+
+impl FromStr for StartPosition {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("earliest"), None) => Ok(StartPosition::Earliest),
+            (Some("latest"), None) => Ok(StartPosition::Latest),
+            (Some("offsets"), Some(offsets)) => offsets
+                .split(',')
+                .map(|offset| offset.parse())
+                .collect::<Result<_, _>>()
+                .map(StartPosition::Offsets)
+                .map_err(|_| "Offsets must be integers"),
+            (Some("time"), Some(time)) => time
+                .parse()
+                .map(StartPosition::Time)
+                .map_err(|_| "Time must be integer"),
+            _ => Err("Unrecognised start position"),
+        }
+    }
+}
+
+// How the sources read their partition.
+#[derive(Clone, Debug)]
+pub struct SourceConfig {
+    // the maximum number of messages read per activation
+    pub max_batch: usize,
+    // how long the source waits before polling again once the partition is drained or paused
+    pub poll_timeout: Duration,
+    // the partition is paused while the dataflow lags behind the source by more than this
+    pub max_lag: usize, // seconds
+    // the source ends at the first watermark at or past this event time
+    pub until: Option<usize>,
+    // the source ends once it has had nothing left to read for this long
+    pub end_after_idle: Option<Duration>,
+    // the buffers stop waiting for a partition which sent no watermark for this long
+    pub idle_partition: Duration,
+    pub start: StartPosition,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig {
+            max_batch: 1024,
+            poll_timeout: Duration::from_millis(100),
+            max_lag: 12 * 60 * 60,
+            until: None,
+            end_after_idle: None,
+            idle_partition: IDLE_AFTER,
+            start: StartPosition::Earliest,
+        }
+    }
+}
+
+// The number of messages of the partition not read yet, if Kafka can tell.
+fn lag(consumer: &BaseConsumer, topic: &str, partition: i32, timeout: Duration) -> Option<i64> {
+    let (low, high) = consumer.fetch_watermarks(topic, partition, timeout).ok()?;
+    let position = consumer.position().ok()?;
+    let next = match position.find_partition(topic, partition)?.offset() {
+        Offset::Offset(next) => next,
+        // nothing was read yet
+        _ => low,
+    };
+    Some(high - next)
+}
 
 pub trait KafkaSource<G: Scope> {
+    // Reads the partition `index` of the topic. The partition is paused while `probe`, the
+    // frontier of the outputs of the dataflow, lags behind the source. The source ends, and
+    // drops its capability, at the end-of-stream marker of the partition, or as configured.
     fn kafka_string_source<D: Importable<D> + Watermarkable + Data + Timestamped>(
         &self,
         topic: String,
         index: usize,
+        config: &SourceConfig,
+        probe: &ProbeHandle<usize>,
     ) -> Stream<G, D>;
 }
 
@@ -33,15 +121,21 @@ impl<G: Scope<Timestamp = usize>> KafkaSource<G> for G {
         &self,
         topic: String,
         index: usize,
+        config: &SourceConfig,
+        probe: &ProbeHandle<usize>,
     ) -> Stream<G, D> {
         // Extract Kafka topic.
         let brokers = "localhost:9092";
 
         // Create Kafka consumer configuration.
+        let reset = match config.start {
+            StartPosition::Latest => "latest",
+            _ => "earliest",
+        };
         let mut consumer_config = ClientConfig::new();
         consumer_config
             .set("produce.offset.report", "true")
-            .set("auto.offset.reset", "earliest")
+            .set("auto.offset.reset", reset)
             .set("group.id", "example")
             .set("enable.auto.commit", "false")
             .set("enable.partition.eof", "false")
@@ -52,17 +146,74 @@ impl<G: Scope<Timestamp = usize>> KafkaSource<G> for G {
         let mut topic_partiton_list = TopicPartitionList::new();
         topic_partiton_list.add_partition(&topic, index as i32);
         let consumer: BaseConsumer = consumer_config.create().unwrap();
-        consumer.subscribe(&[&topic]).expect("Failed to subscribe");
+
+        // The partition is assigned directly, rather than through a subscription, so that
+        // the group does not move it to the offsets it committed.
+        let partition = index as i32;
+        let mut start = TopicPartitionList::new();
+        match &config.start {
+            StartPosition::Earliest => {
+                start.add_partition_offset(&topic, partition, Offset::Beginning);
+            }
+            StartPosition::Latest => start.add_partition_offset(&topic, partition, Offset::End),
+            StartPosition::Offsets(offsets) => {
+                let offset = offsets
+                    .get(index)
+                    .expect("No offset given for the partition");
+                start.add_partition_offset(&topic, partition, Offset::Offset(*offset));
+            }
+            StartPosition::Time(time) => {
+                // the producer writes the event times as the message timestamps, and Kafka
+                // looks the offsets up by timestamps in milliseconds
+                let mut times = TopicPartitionList::new();
+                let millis = *time as i64 * 1000;
+                times.add_partition_offset(&topic, partition, Offset::Offset(millis));
+                start = consumer
+                    .offsets_for_times(times, Duration::from_secs(10))
+                    .expect("Unable to find the offset of the start time");
+            }
+        }
         consumer
-            .assign(&topic_partiton_list)
+            .assign(&start)
             .expect("Unable to configure partition correctly");
 
-        source(self, "Source", |mut capability, info| {
-            //let mut message_stream = consumer.start();
+        let config = config.clone();
+        let probe = probe.clone();
+        source(self, "Source", |capability, info| {
             let activator = self.activator_for(&info.address[..]);
+            let mut held = Some(capability);
+            let mut paused = false;
+            let mut idle_since = Instant::now();
+            let started = Instant::now();
+            let mut records = 0;
             move |output| {
-                activator.activate();
-                for message in consumer.poll(Duration::from_secs(0)) {
+                let capability = match held.as_mut() {
+                    Some(capability) => capability,
+                    None => return,
+                };
+                let mut end = None;
+
+                // pause the partition while the dataflow lags behind, so that the messages
+                // are not read faster than they are processed
+                let behind = probe.less_than(&capability.time().saturating_sub(config.max_lag));
+                if behind != paused {
+                    let result = match behind {
+                        true => consumer.pause(&topic_partiton_list),
+                        false => consumer.resume(&topic_partiton_list),
+                    };
+                    result.expect("Unable to pause or resume the partition");
+                    paused = behind;
+                }
+
+                let mut batch = vec![];
+                let mut read = 0;
+                while read < config.max_batch {
+                    let message = match consumer.poll(Duration::from_secs(0)) {
+                        Some(message) => message,
+                        None => break,
+                    };
+                    read += 1;
+
                     match message {
                         Err(_) => println!("Error while reading from stream."),
                         Ok(m) => match m.payload_view::<str>() {
@@ -73,19 +224,30 @@ impl<G: Scope<Timestamp = usize>> KafkaSource<G> for G {
                                 let v: Vec<&str> = text.split("|").collect();
                                 let record = StringRecord::from(v);
 
-                                if &record[0] == "Watermark" {
+                                if &record[0] == END_OF_STREAM {
+                                    end = Some("end of the stream".to_string());
+                                    break;
+                                } else if &record[0] == "Watermark" {
                                     let watermarked = D::from_watermark(&record[1], index);
                                     if watermarked.timestamp() < *capability.time() {
                                         // stuff on kafka from previous runs, we will igore them
                                         // until we arrive at a relevant event
                                     } else {
+                                        // the records read before the watermark keep its time
+                                        output.session(&capability).give_iterator(batch.drain(..));
                                         capability.downgrade(&watermarked.timestamp());
-                                        output.session(&capability).give(watermarked);
+                                        let time = watermarked.timestamp();
+                                        batch.push(watermarked);
+                                        if config.until.map_or(false, |until| time >= until) {
+                                            end = Some(format!("watermark {}", time));
+                                            break;
+                                        }
                                     }
                                 } else {
                                     match D::from_record(record) {
                                         Ok(record) => {
-                                            output.session(&capability).give(record);
+                                            records += 1;
+                                            batch.push(record);
                                         }
                                         Err(_) => {}
                                     }
@@ -94,7 +256,61 @@ impl<G: Scope<Timestamp = usize>> KafkaSource<G> for G {
                         },
                     };
                 }
+                if !batch.is_empty() {
+                    output.session(&capability).give_iterator(batch.drain(..));
+                }
+
+                // a full batch leaves messages pending, otherwise the source is only
+                // activated again after the poll timeout, so that an idle source does not spin
+                let pending = read == config.max_batch && !paused;
+                if read > 0 || paused {
+                    idle_since = Instant::now();
+                } else if let Some(after) = config.end_after_idle {
+                    // only asks Kafka for the lag once idle for long enough
+                    if idle_since.elapsed() >= after
+                        && lag(&consumer, &topic, index as i32, config.poll_timeout) == Some(0)
+                    {
+                        end = Some(format!("no lag for {}s", after.as_secs()));
+                    }
+                }
+
+                match end {
+                    Some(reason) => {
+                        println!(
+                            "Source {}/{} ended ({}) @t={}: {} records in {}s",
+                            topic,
+                            index,
+                            reason,
+                            capability.time(),
+                            records,
+                            started.elapsed().as_secs()
+                        );
+                        // releasing the capability lets the operators downstream flush
+                        held = None;
+                    }
+                    None if pending => activator.activate(),
+                    None => activator.activate_after(config.poll_timeout),
+                }
             }
         })
     }
 }
+
+#[cfg(test)]
+mod source_tests {
+    use crate::operators::source::StartPosition;
+
+    #[test]
+    fn test_start_positions_are_parsed() {
+        assert_eq!("earliest".parse(), Ok(StartPosition::Earliest));
+        assert_eq!("latest".parse(), Ok(StartPosition::Latest));
+        assert_eq!(
+            "offsets:0,12,7,3".parse(),
+            Ok(StartPosition::Offsets(vec![0, 12, 7, 3]))
+        );
+        assert_eq!("time:1234".parse(), Ok(StartPosition::Time(1234)));
+        assert!("offsets:1,a".parse::<StartPosition>().is_err());
+        assert!("latest:1".parse::<StartPosition>().is_err());
+        assert!("newest".parse::<StartPosition>().is_err());
+    }
+}
@@ -0,0 +1,233 @@
+extern crate timely;
+
+use timely::dataflow::channels::pact::{ParallelizationContract, Pipeline};
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::Broadcast;
+use timely::dataflow::{Scope, Stream};
+
+use crate::dsa::thread::{ThreadStats, Threads};
+use crate::dto::comment::Comment;
+use crate::dto::common::Watermarkable;
+use crate::operators::periodic::Periodic;
+
+use std::collections::HashMap;
+
+pub trait ThreadTrees<G, P>
+where
+    G: Scope,
+    P: ParallelizationContract<usize, Comment>,
+{
+    // Returns the statistics of the threads which changed in each period, given the comments
+    // linked to their root post, on the worker owning the post.
+    fn thread_stats(
+        &self,
+        c_pact: P,
+        period: usize,
+        horizon: usize,
+        orphan_timeout: usize,
+    ) -> Stream<G, ThreadStats>;
+
+    // Answers the queries for the subtree of a comment, once every comment linked before
+    // the query has been added. The queries are sent to every worker, and answered by the
+    // one owning the thread of the comment; unknown comments are not answered.
+    fn subtrees(
+        &self,
+        queries: &Stream<G, u32>,
+        c_pact: P,
+        horizon: usize,
+        orphan_timeout: usize,
+    ) -> Stream<G, Comment>;
+}
+
+impl<G, P> ThreadTrees<G, P> for Stream<G, Comment>
+where
+    G: Scope<Timestamp = usize>,
+    P: ParallelizationContract<usize, Comment>,
+{
+    fn thread_stats(
+        &self,
+        c_pact: P,
+        period: usize,
+        horizon: usize,
+        orphan_timeout: usize,
+    ) -> Stream<G, ThreadStats> {
+        let mut threads = Threads::new(orphan_timeout);
+        let periodic = Periodic::new(period, 1);
+
+        self.unary_notify(
+            c_pact,
+            "ThreadStats",
+            None,
+            move |input, output, notificator| {
+                let mut data = Vec::new();
+                input.for_each(|cap, input| {
+                    input.swap(&mut data);
+                    for comment in data.drain(..) {
+                        if !comment.is_watermark() {
+                            threads.add(comment);
+                        }
+                    }
+                    notificator.notify_at(cap.delayed(&periodic.boundary(*cap.time())));
+                });
+
+                notificator.for_each(|cap, _, notificator| {
+                    periodic.next(&cap, notificator);
+                    let time = *cap.time();
+                    output
+                        .session(&cap)
+                        .give_iterator(threads.changed().into_iter());
+
+                    // forget the threads inactive for longer than the horizon
+                    threads.expire(time.saturating_sub(horizon), time);
+                });
+            },
+        )
+    }
+
+    fn subtrees(
+        &self,
+        queries: &Stream<G, u32>,
+        c_pact: P,
+        horizon: usize,
+        orphan_timeout: usize,
+    ) -> Stream<G, Comment> {
+        let mut threads = Threads::new(orphan_timeout);
+        // map: time -> queries asked at that time
+        let mut pending_queries: HashMap<usize, Vec<u32>> = HashMap::new();
+
+        self.binary_notify(
+            &queries.broadcast(),
+            c_pact,
+            Pipeline,
+            "Subtrees",
+            None,
+            move |c_input, q_input, output, notificator| {
+                let mut c_data = Vec::new();
+                c_input.for_each(|cap, input| {
+                    input.swap(&mut c_data);
+                    let time = *cap.time();
+                    for comment in c_data.drain(..) {
+                        if !comment.is_watermark() {
+                            threads.add(comment);
+                        }
+                    }
+                    threads.expire(time.saturating_sub(horizon), time);
+                });
+
+                q_input.for_each(|cap, input| {
+                    let queries = pending_queries.entry(*cap.time()).or_insert(vec![]);
+                    queries.extend(input.iter().cloned());
+                    notificator.notify_at(cap.retain());
+                });
+
+                notificator.for_each(|cap, _, _| {
+                    let queries = pending_queries.remove(cap.time()).unwrap_or(vec![]);
+                    let mut session = output.session(&cap);
+                    for id in queries {
+                        if let Some(subtree) = threads.subtree(id) {
+                            session.give(subtree);
+                        }
+                    }
+                });
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod thread_trees_tests {
+    extern crate timely;
+
+    use crate::dto::comment::Comment;
+    use crate::dto::common::Watermarkable;
+    use crate::operators::thread_trees::ThreadTrees;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use timely::dataflow::channels::pact::Pipeline;
+    use timely::dataflow::operators::{Input, Inspect, Probe};
+    use timely::dataflow::InputHandle;
+
+    fn comment(id: u32, parent: Option<u32>, timestamp: usize) -> Comment {
+        Comment {
+            id,
+            person_id: id % 2,
+            timestamp,
+            reply_to_post_id: Some(1),
+            reply_to_comment_id: parent,
+            is_watermark: false,
+            ..Comment::from_watermark("0", 0)
+        }
+    }
+
+    #[test]
+    fn test_threads_are_reported_and_queried() {
+        let outputs = timely::execute(timely::Configuration::Thread, |worker| {
+            let mut comments_input = InputHandle::new();
+            let mut queries_input = InputHandle::new();
+            let stats = Rc::new(RefCell::new(vec![]));
+            let subtrees = Rc::new(RefCell::new(vec![]));
+            let (stats_output, subtrees_output) = (stats.clone(), subtrees.clone());
+
+            let probe = worker.dataflow(|scope| {
+                let comments = scope.input_from(&mut comments_input);
+                let queries = scope.input_from(&mut queries_input);
+                comments
+                    .thread_stats(Pipeline, 10, 100, 100)
+                    .inspect_batch(move |t, xs| {
+                        for x in xs {
+                            let output = (*t, x.depth, x.longest_chain.clone());
+                            stats_output.borrow_mut().push(output);
+                        }
+                    });
+                comments
+                    .subtrees(&queries, Pipeline, 100, 100)
+                    .inspect(move |c: &Comment| {
+                        let replies: Vec<u32> = c.replies.iter().map(|r| r.id).collect();
+                        subtrees_output.borrow_mut().push((c.id, replies));
+                    })
+                    .probe()
+            });
+
+            let batches = vec![
+                (vec![comment(2, None, 1), comment(3, Some(2), 2)], vec![]),
+                (vec![comment(4, Some(2), 12)], vec![2, 7]),
+                (vec![comment(5, Some(4), 21)], vec![4]),
+            ];
+            for (time, (mut comments, mut queries)) in batches.into_iter().enumerate() {
+                let time = time * 10;
+                comments_input.advance_to(time);
+                queries_input.advance_to(time);
+                comments_input.send_batch(&mut comments);
+                queries_input.send_batch(&mut queries);
+                while probe.less_than(comments_input.time()) {
+                    worker.step();
+                }
+            }
+            drop(comments_input);
+            drop(queries_input);
+            while worker.step() {}
+
+            let stats = stats.replace(vec![]);
+            let subtrees = subtrees.replace(vec![]);
+            (stats, subtrees)
+        })
+        .unwrap()
+        .join()
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            outputs.0,
+            vec![
+                (10, 2, vec![2, 3]),
+                (20, 2, vec![2, 3]),
+                (30, 3, vec![2, 4, 5])
+            ]
+        );
+        // the unknown comment 7 is not answered
+        assert_eq!(outputs.1, vec![(2, vec![3, 4]), (4, vec![5])]);
+    }
+}
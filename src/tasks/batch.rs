@@ -0,0 +1,419 @@
+use crate::connection::import::parse_csv;
+use crate::dsa::anomaly::Observations;
+use crate::dsa::features::FeatureExtractor;
+use crate::dsa::kmeans::Point;
+use crate::dto::comment::Comment;
+use crate::dto::common::{Importable, Timestamped};
+use crate::dto::like::Like;
+use crate::dto::post::Post;
+use crate::tasks::load::EventStream;
+use crate::tasks::unusual_activity::{Config, Reporter, SuspicionReport};
+use crate::tasks::who_to_follow::{Tables, PEOPLE_OF_INTEREST};
+use crate::tasks::{post_stats, who_to_follow};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+// The outputs of the tasks, named as in the streaming outputs.
+pub const OUTPUTS: [&str; 4] = ["counts", "engaged", "recommendations", "suspicious"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Comment,
+    Reply,
+    Like,
+}
+
+// A comment, reply or like on a post.
+#[derive(Clone, Debug)]
+struct Engagement {
+    timestamp: usize,
+    post_id: u32,
+    person_id: u32,
+    kind: Kind,
+}
+
+fn read<D: Importable<D> + Debug + Timestamped>(path: &PathBuf) -> Vec<D> {
+    let mut records = vec![];
+    parse_csv(path.to_str().unwrap(), |record: D| records.push(record));
+    records.sort_by_key(|record| record.timestamp());
+    records
+}
+
+// The post at the root of the thread of a comment, if the whole thread is known.
+fn root(comment: &Comment, comments: &HashMap<u32, &Comment>) -> Option<u32> {
+    let mut current = comment;
+    // a thread cannot be longer than the number of comments
+    for _ in 0..=comments.len() {
+        match (current.reply_to_post_id, current.reply_to_comment_id) {
+            (Some(post_id), _) => return Some(post_id),
+            (None, Some(comment_id)) => current = *comments.get(&comment_id)?,
+            (None, None) => return None,
+        }
+    }
+    None
+}
+
+// The complete streams, read from the CSV files.
+pub struct Dataset {
+    posts: Vec<Post>,
+    // every engagement linked to a known post, in timestamp order
+    engagements: Vec<Engagement>,
+    end_time: usize,
+}
+
+impl Dataset {
+    pub fn load(streams_path: &PathBuf) -> Dataset {
+        let posts: Vec<Post> = read(&streams_path.join(EventStream::Posts.file_name()));
+        let comments: Vec<Comment> = read(&streams_path.join(EventStream::Comments.file_name()));
+        let likes: Vec<Like> = read(&streams_path.join(EventStream::Likes.file_name()));
+
+        let post_ids: HashSet<u32> = posts.iter().map(|post| post.id).collect();
+        let comments_by_id: HashMap<u32, &Comment> = comments
+            .iter()
+            .map(|comment| (comment.id, comment))
+            .collect();
+
+        let mut engagements = vec![];
+        for comment in comments.iter() {
+            if let Some(post_id) = root(comment, &comments_by_id) {
+                if post_ids.contains(&post_id) {
+                    let kind = match comment.reply_to_comment_id {
+                        Some(_) => Kind::Reply,
+                        None => Kind::Comment,
+                    };
+                    engagements.push(Engagement {
+                        timestamp: comment.timestamp,
+                        post_id,
+                        person_id: comment.person_id,
+                        kind,
+                    });
+                }
+            }
+        }
+        for like in likes.iter().filter(|like| post_ids.contains(&like.post_id)) {
+            engagements.push(Engagement {
+                timestamp: like.timestamp,
+                post_id: like.post_id,
+                person_id: like.person_id,
+                kind: Kind::Like,
+            });
+        }
+        engagements.sort_by_key(|engagement| engagement.timestamp);
+
+        let end_time = vec![
+            posts.last().map_or(0, |post| post.timestamp),
+            comments.last().map_or(0, |comment| comment.timestamp),
+            likes.last().map_or(0, |like| like.timestamp),
+        ]
+        .into_iter()
+        .max()
+        .unwrap();
+
+        Dataset {
+            posts,
+            engagements,
+            end_time,
+        }
+    }
+
+    pub fn start_time(&self) -> usize {
+        self.posts.first().map_or(0, |post| post.timestamp)
+    }
+
+    pub fn end_time(&self) -> usize {
+        self.end_time
+    }
+}
+
+// The exact state of the posts, built by going through the engagements in timestamp order.
+#[derive(Default)]
+struct Sweep {
+    next: usize,
+    last_active: HashMap<u32, usize>,
+    // (last active time, post id) of every post
+    by_last_active: BTreeSet<(usize, u32)>,
+    persons: HashMap<u32, HashSet<u32>>,
+    comments: HashMap<u32, usize>,
+    replies: HashMap<u32, usize>,
+}
+
+impl Sweep {
+    // Takes in the engagements created before `time`.
+    fn advance(&mut self, engagements: &[Engagement], time: usize) {
+        while self.next < engagements.len() && engagements[self.next].timestamp < time {
+            let engagement = &engagements[self.next];
+            self.next += 1;
+
+            let post_id = engagement.post_id;
+            if let Some(last) = self.last_active.insert(post_id, engagement.timestamp) {
+                self.by_last_active.remove(&(last, post_id));
+            }
+            self.by_last_active.insert((engagement.timestamp, post_id));
+
+            self.persons
+                .entry(post_id)
+                .or_insert(HashSet::new())
+                .insert(engagement.person_id);
+            match engagement.kind {
+                Kind::Comment => *self.comments.entry(post_id).or_insert(0) += 1,
+                Kind::Reply => *self.replies.entry(post_id).or_insert(0) += 1,
+                Kind::Like => {}
+            }
+        }
+    }
+
+    // The posts engaged with in the period before `time`, in increasing order of id.
+    fn active_posts(&self, time: usize, period: usize) -> Vec<u32> {
+        // the interval is non-inclusive in the lhs
+        let time_lhs = if time > period { time - period } else { 0 };
+        let mut posts: Vec<u32> = self
+            .by_last_active
+            .range((time_lhs + 1, 0)..)
+            .map(|(_, post_id)| *post_id)
+            .collect();
+        posts.sort();
+        posts
+    }
+}
+
+// Computes the exact outputs of the tasks from the complete streams.
+pub struct Oracle {
+    dataset: Dataset,
+    tables: Tables,
+    config: Config,
+}
+
+impl Oracle {
+    pub fn new(dataset: Dataset, tables: Tables, config: Config) -> Oracle {
+        Oracle {
+            dataset,
+            tables,
+            config,
+        }
+    }
+
+    pub fn dataset(&self) -> &Dataset {
+        &self.dataset
+    }
+
+    // The period of the output in the streaming tasks.
+    pub fn period(&self, name: &str) -> usize {
+        match name {
+            "counts" => post_stats::COLLECTION_PERIOD,
+            "engaged" => 2 * post_stats::COLLECTION_PERIOD,
+            "recommendations" => who_to_follow::COLLECTION_PERIOD,
+            "suspicious" => self.config.notify_period,
+            _ => panic!("Unknown output {}", name),
+        }
+    }
+
+    // The period boundaries of the output `name` after the first post, up to `end`.
+    pub fn boundaries(&self, name: &str, end: usize) -> Vec<usize> {
+        let start = self.dataset.start_time();
+        let period = self.period(name);
+        // the suspicious people are reported at the multiples of the period
+        let first = match name {
+            "suspicious" => start / period * period,
+            _ => start,
+        };
+        (1..)
+            .map(|i| first + i * period)
+            .take_while(|time| *time <= end)
+            .collect()
+    }
+
+    // The exact outputs of every task at each of their period boundaries up to `end`.
+    pub fn all_answers(&self, end: usize) -> Vec<(usize, String)> {
+        let mut answers = vec![];
+        for name in OUTPUTS.iter() {
+            answers.append(&mut self.answers(name, &self.boundaries(name, end)));
+        }
+        answers
+    }
+
+    // The exact output `name` at each of the given times, which must be increasing,
+    // formatted as the streaming outputs.
+    pub fn answers(&self, name: &str, times: &[usize]) -> Vec<(usize, String)> {
+        if name == "suspicious" {
+            return self.suspicious(times);
+        }
+
+        let mut sweep = Sweep::default();
+        let mut answers = vec![];
+        for &time in times {
+            sweep.advance(&self.dataset.engagements, time);
+            let outputs = match name {
+                "counts" => self.counts(&sweep, time),
+                "engaged" => self.engaged(&sweep, time),
+                "recommendations" => self.recommendations(&sweep, time),
+                _ => panic!("Unknown output {}", name),
+            };
+            answers.extend(outputs.into_iter().map(|output| (time, output)));
+        }
+        answers
+    }
+
+    fn counts(&self, sweep: &Sweep, time: usize) -> Vec<String> {
+        sweep
+            .active_posts(time, post_stats::ACTIVE_POST_PERIOD)
+            .into_iter()
+            .map(|post_id| {
+                let comments = *sweep.comments.get(&post_id).unwrap_or(&0);
+                let replies = *sweep.replies.get(&post_id).unwrap_or(&0);
+                format!("counts {:?}", (post_id, comments, replies))
+            })
+            .collect()
+    }
+
+    fn engaged(&self, sweep: &Sweep, time: usize) -> Vec<String> {
+        sweep
+            .active_posts(time, post_stats::ACTIVE_POST_PERIOD)
+            .into_iter()
+            .map(|post_id| format!("engaged {:?}", (post_id, sweep.persons[&post_id].len())))
+            .collect()
+    }
+
+    fn recommendations(&self, sweep: &Sweep, time: usize) -> Vec<String> {
+        let forums: HashMap<u32, u32> = self
+            .dataset
+            .posts
+            .iter()
+            .map(|post| (post.id, post.forum_id))
+            .collect();
+
+        // the forums of the active posts each person of interest is engaged with
+        let mut forums_of_interest = HashMap::new();
+        for post_id in sweep.active_posts(time, who_to_follow::ACTIVE_POST_PERIOD) {
+            for person_id in sweep.persons[&post_id].iter() {
+                if PEOPLE_OF_INTEREST.contains(person_id) {
+                    forums_of_interest
+                        .entry(*person_id)
+                        .or_insert(HashSet::new())
+                        .insert(forums[&post_id]);
+                }
+            }
+        }
+
+        let mut persons: Vec<_> = forums_of_interest.keys().cloned().collect();
+        persons.sort();
+        persons
+            .into_iter()
+            .map(|person_id| {
+                let recommendations = self
+                    .tables
+                    .recommend(person_id, &forums_of_interest[&person_id]);
+                format!("recommendations {:?}", (person_id, recommendations))
+            })
+            .collect()
+    }
+
+    // The people posting unusual content. At every boundary, the posts of the last
+    // `history` periods are clustered from scratch, and the authors of the outliers among
+    // the posts of the period are reported. Unlike the streaming detector, the points are
+    // neither summarised nor decayed, so the streamed answers only approximate these.
+    fn suspicious(&self, times: &[usize]) -> Vec<(usize, String)> {
+        let config = &self.config;
+        let period = config.notify_period;
+        let last = match times.last() {
+            Some(last) => *last,
+            None => return vec![],
+        };
+        // the boundaries are on the same grid as the given times
+        let mut time = times[0];
+        while time > self.dataset.start_time() + period {
+            time -= period;
+        }
+
+        // (point, author, creation time) of every post
+        let mut extractor = FeatureExtractor::new(&config.features);
+        let posts: Vec<(Point, u32, usize)> = self
+            .dataset
+            .posts
+            .iter()
+            .filter_map(|post| {
+                let point = extractor.extract(post)?;
+                Some((point, post.person_id, post.timestamp))
+            })
+            .collect();
+
+        let mut reporter = Reporter::new(config.cooldown);
+        let mut answers = vec![];
+        while time <= last {
+            let window_start = time.saturating_sub(config.history * period);
+            let window: Vec<&(Point, u32, usize)> = posts
+                .iter()
+                .filter(|(_, _, timestamp)| window_start <= *timestamp && *timestamp < time)
+                .collect();
+
+            let mut flagged = BTreeSet::new();
+            if window.len() > config.min_points {
+                let (points, persons): (Vec<Point>, Vec<u32>) = window
+                    .iter()
+                    .map(|(point, person_id, _)| (point.clone(), *person_id))
+                    .unzip();
+                let centers = config.kmeans().fit(&[], &points);
+                let scores = config
+                    .detector
+                    .detector(config.outlier_percentile, config.seed)
+                    .scores(&Observations {
+                        points: &points,
+                        persons: &persons,
+                        centers: &centers,
+                    });
+                for ((_, person_id, timestamp), score) in window.iter().zip(scores) {
+                    if *timestamp + period >= time && score >= 1. {
+                        flagged.insert(*person_id);
+                    }
+                }
+            }
+
+            // only the people are compared, so the reports carry no evidence
+            let reports = flagged
+                .into_iter()
+                .map(|person_id| SuspicionReport {
+                    person_id,
+                    score: 1.,
+                    post_ids: vec![],
+                    features: vec![],
+                    distance: None,
+                    first_flagged: time,
+                    last_flagged: time,
+                })
+                .collect();
+            let reports = reporter.report(time, reports);
+            if times.contains(&time) {
+                answers.extend(
+                    reports
+                        .into_iter()
+                        .map(|report| (time, format!("suspicious {:?}", report.person_id))),
+                );
+            }
+            time += period;
+        }
+        answers
+    }
+}
+
+// Prints the exact outputs of every task at each of its period boundaries, starting from
+// the first post.
+pub fn run(streams_path: &PathBuf, tables_path: &PathBuf) {
+    let oracle = Oracle::new(
+        Dataset::load(streams_path),
+        Tables::load(tables_path),
+        Config::default(),
+    );
+    let end = oracle.dataset().end_time();
+
+    let mut answers = vec![];
+    for name in OUTPUTS.iter() {
+        let times = oracle.boundaries(name, end + oracle.period(name));
+        answers.append(&mut oracle.answers(name, &times));
+    }
+
+    answers.sort();
+    for (time, answer) in answers {
+        println!("{}: {}", time, answer);
+    }
+}
@@ -0,0 +1,170 @@
+extern crate timely;
+
+use crate::connection::replay::Replay;
+use crate::dto::common::{Importable, Timestamped, Watermarkable};
+
+use timely::communication::Allocator;
+use timely::dataflow::operators::{Inspect, Probe};
+use timely::dataflow::{ProbeHandle, Scope, Stream};
+use timely::worker::Worker;
+use timely::{Configuration, Data};
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+#[cfg(test)]
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+#[cfg(test)]
+use std::{env, fs};
+
+// The tasks are replayed with the default bounded delay for two hours of event time.
+#[cfg(test)]
+pub const DELAY: usize = 300; // seconds
+#[cfg(test)]
+const DURATION: usize = 2 * 60 * 60; // seconds
+
+// Set this variable to rewrite the golden files with the current outputs.
+#[cfg(test)]
+const UPDATE_GOLDEN: &str = "UPDATE_GOLDEN";
+
+// The outputs of a dataflow on a worker, as (time, output).
+pub type Outputs<T> = Rc<RefCell<Vec<(usize, T)>>>;
+
+// The inputs of a dataflow under test.
+pub trait Inputs {
+    // Gives the inputs up to the watermark `time`.
+    fn advance(&mut self, time: usize);
+}
+
+impl<D: Data + Debug + Importable<D> + Watermarkable + Timestamped> Inputs for Replay<D> {
+    fn advance(&mut self, time: usize) {
+        Replay::advance(self, time);
+    }
+}
+
+impl<A: Inputs, B: Inputs> Inputs for (A, B) {
+    fn advance(&mut self, time: usize) {
+        self.0.advance(time);
+        self.1.advance(time);
+    }
+}
+
+impl<A: Inputs, B: Inputs, C: Inputs> Inputs for (A, B, C) {
+    fn advance(&mut self, time: usize) {
+        self.0.advance(time);
+        self.1.advance(time);
+        self.2.advance(time);
+    }
+}
+
+// Runs a dataflow built on every worker by `build`, which returns the inputs of the
+// dataflow and the watermarks to advance them to, and collects its outputs, probed by the
+// probe. The workers wait for the outputs to catch up with every watermark, then close the
+// inputs and let the windows flush. Returns the outputs of all the workers.
+pub fn execute<I, T, F>(config: Configuration, build: F) -> Vec<(usize, T)>
+where
+    I: Inputs,
+    T: Send + 'static,
+    F: Fn(&mut Worker<Allocator>, &Outputs<T>, &mut ProbeHandle<usize>) -> (I, Vec<usize>)
+        + Send
+        + Sync
+        + 'static,
+{
+    timely::execute(config, move |worker| {
+        let outputs = Rc::new(RefCell::new(vec![]));
+        let mut probe = ProbeHandle::new();
+        let (mut inputs, watermarks) = build(worker, &outputs, &mut probe);
+
+        for time in watermarks {
+            inputs.advance(time);
+            while probe.less_than(&time) {
+                worker.step();
+            }
+        }
+
+        // close the inputs and let the windows flush
+        drop(inputs);
+        while worker.step() {}
+
+        outputs.replace(vec![])
+    })
+    .unwrap()
+    .join()
+    .into_iter()
+    .flat_map(|outputs| outputs.unwrap())
+    .collect()
+}
+
+// Collects the outputs of a stream, formatted as "<name> <output>".
+pub fn collect<G: Scope<Timestamp = usize>, D: Data + Debug>(
+    stream: &Stream<G, D>,
+    name: &'static str,
+    outputs: &Outputs<String>,
+    probe: &mut ProbeHandle<usize>,
+) {
+    let outputs = outputs.clone();
+    stream
+        .inspect_batch(move |t, xs| {
+            for x in xs {
+                outputs.borrow_mut().push((*t, format!("{} {:?}", name, x)));
+            }
+        })
+        .probe_with(probe);
+}
+
+#[cfg(test)]
+pub fn data_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("data")
+}
+
+#[cfg(test)]
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.txt", name))
+}
+
+// Replays one of the streams of the test dataset with a watermark every DELAY seconds.
+#[cfg(test)]
+pub fn replay<D: Data + Debug + Importable<D> + Watermarkable + Timestamped>(
+    file: &str,
+) -> Replay<D> {
+    Replay::new(&data_path().join("streams").join(file), DELAY)
+}
+
+// The watermarks emitted while replaying the dataset starting from `start_time`.
+#[cfg(test)]
+pub fn watermarks(start_time: usize) -> Vec<usize> {
+    (0..=DURATION / DELAY)
+        .map(|i| start_time + i * DELAY)
+        .collect()
+}
+
+// Compares the outputs, sorted by time and then by value, with the golden file `name`.
+#[cfg(test)]
+pub fn assert_golden(name: &str, mut outputs: Vec<(usize, String)>) {
+    outputs.sort();
+    let actual: String = outputs
+        .iter()
+        .map(|(time, output)| format!("{}: {}\n", time, output))
+        .collect();
+
+    let path = golden_path(name);
+    if env::var(UPDATE_GOLDEN).is_ok() {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap();
+    assert!(
+        actual == expected,
+        "{} does not match {:?}; rerun with {} set if the change is intended.\nactual:\n{}",
+        name,
+        path,
+        UPDATE_GOLDEN,
+        actual
+    );
+}
@@ -1,45 +1,102 @@
+use crate::connection::delay::Delay;
 use crate::connection::producer::Producer;
-use chrono::DateTime;
+use chrono::{DateTime, FixedOffset};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::thread;
 
-pub fn run(records: Option<usize>, streams_path: &PathBuf) {
-    let posts_path = streams_path.join("post_event_stream.csv");
-    let comments_path = streams_path.join("comment_event_stream.csv");
-    let likes_path = streams_path.join("likes_event_stream.csv");
+// The event streams that can be loaded into Kafka.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventStream {
+    Posts,
+    Comments,
+    Likes,
+}
+
+impl EventStream {
+    pub fn topic(&self) -> &'static str {
+        match self {
+            EventStream::Posts => "posts",
+            EventStream::Comments => "comments",
+            EventStream::Likes => "likes",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            EventStream::Posts => "post_event_stream.csv",
+            EventStream::Comments => "comment_event_stream.csv",
+            EventStream::Likes => "likes_event_stream.csv",
+        }
+    }
+}
+
+impl FromStr for EventStream {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "posts" => Ok(EventStream::Posts),
+            "comments" => Ok(EventStream::Comments),
+            "likes" => Ok(EventStream::Likes),
+            _ => Err("Unrecognised event stream"),
+        }
+    }
+}
 
-    let mut creation_time = None;
+pub struct LoadConfig {
+    // streams to be written to their topics
+    pub streams: Vec<EventStream>,
+    // maximum number of records per topic
+    pub records: Option<usize>,
+    pub delay: Delay,
+    // bound of the delays and spacing between watermarks
+    pub bound: usize,
+    // each stream is delayed by a generator seeded from this value
+    pub seed: u64,
+}
 
-    let posts_file = File::open(posts_path.clone()).unwrap();
+// The watermarks of all streams are aligned on the creation time of the first post,
+// even if the posts stream is not loaded, so that streams loaded by separate runs
+// share the same watermarks.
+fn start_time(streams_path: &PathBuf) -> DateTime<FixedOffset> {
+    let posts_path = streams_path.join(EventStream::Posts.file_name());
+    let posts_file = File::open(posts_path).unwrap();
     let posts_file = BufReader::new(posts_file);
 
-    for line in posts_file.lines().skip(1) {
-        let line = line.unwrap();
-        let fields: Vec<&str> = line.split("|").collect();
-        creation_time = Some(DateTime::parse_from_rfc3339(fields[2]).unwrap());
-        break;
+    // skip the header
+    let line = posts_file
+        .lines()
+        .nth(1)
+        .expect("Posts stream is empty")
+        .unwrap();
+    let fields: Vec<&str> = line.split("|").collect();
+    DateTime::parse_from_rfc3339(fields[2]).unwrap()
+}
+
+// Loads the configured streams into Kafka and blocks until all of them are written.
+pub fn run(config: &LoadConfig, streams_path: &PathBuf) {
+    let creation_time = start_time(streams_path);
+
+    let mut handles = vec![];
+    for (i, stream) in config.streams.clone().into_iter().enumerate() {
+        let path = streams_path.join(stream.file_name());
+        let records = config.records;
+        let model = config.delay.model(config.bound);
+        let seed = config.seed.wrapping_add(i as u64);
+        handles.push(thread::spawn(move || {
+            let cnt = Producer::new(stream.topic().to_string(), model, seed).write_file(
+                path.to_str().unwrap(),
+                records,
+                &creation_time,
+            );
+            println!("Loaded {} records onto topic {}", cnt, stream.topic());
+        }));
+    }
+
+    for handle in handles {
+        handle.join().unwrap();
     }
-    thread::spawn(move || {
-        Producer::new("posts".to_string()).write_file(
-            posts_path.to_str().unwrap(),
-            records,
-            &(creation_time.unwrap()),
-        );
-    });
-    thread::spawn(move || {
-        Producer::new("comments".to_string()).write_file(
-            comments_path.to_str().unwrap(),
-            records,
-            &(creation_time.unwrap()),
-        );
-    });
-    thread::spawn(move || {
-        Producer::new("likes".to_string()).write_file(
-            likes_path.to_str().unwrap(),
-            records,
-            &(creation_time.unwrap()),
-        );
-    });
 }
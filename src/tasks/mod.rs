@@ -1,4 +1,7 @@
+pub mod batch;
+pub mod harness;
 pub mod load;
 pub mod post_stats;
 pub mod unusual_activity;
+pub mod verify;
 pub mod who_to_follow;
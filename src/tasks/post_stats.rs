@@ -1,13 +1,16 @@
 extern crate timely;
 
-use crate::connection::producer::FIXED_BOUNDED_DELAY;
 use crate::operators::buffer::Buffer;
-use crate::operators::source::KafkaSource;
+use crate::operators::periodic::Periodic;
+use crate::operators::source::{KafkaSource, SourceConfig};
+use crate::util::LineChart;
 
 use crate::operators::active_posts::ActivePosts;
 use crate::operators::engaged_users::EngagedUsers;
-use crate::operators::link_replies::LinkReplies;
+use crate::operators::link_replies::{LinkReplies, ThreadConfig};
 use crate::operators::post_counts::PostCounts;
+use crate::operators::route_replies::RouteReplies;
+use crate::operators::thread_trees::ThreadTrees;
 
 use crate::dto::comment::Comment;
 use crate::dto::like::Like;
@@ -15,64 +18,437 @@ use crate::dto::post::Post;
 
 use timely::dataflow::channels::pact::Exchange;
 use timely::dataflow::channels::pact::Pipeline;
-use timely::dataflow::operators::broadcast::Broadcast;
-use timely::dataflow::operators::Inspect;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::{Inspect, Probe};
+use timely::dataflow::{ProbeHandle, Scope, Stream};
 
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::Hasher;
+use std::path::PathBuf;
+use std::time::Duration;
 use timely::Configuration;
 
-const COLLECTION_PERIOD: usize = 1800; // seconds
-const ACTIVE_POST_PERIOD: usize = 43200; // seconds
+pub const COLLECTION_PERIOD: usize = 1800; // seconds
+pub const ACTIVE_POST_PERIOD: usize = 43200; // seconds
 
-pub fn run() {
-    timely::execute(Configuration::Process(4), |worker| {
-        let index = worker.index();
-        worker.dataflow::<usize, _, _>(|scope| {
-            let posts = scope.kafka_string_source::<Post>("posts".to_string(), index);
-            let comments = scope.kafka_string_source::<Comment>("comments".to_string(), index);
-            let likes = scope.kafka_string_source::<Like>("likes".to_string(), index);
+// the charts are saved once a day of event time, and when the streams end
+const CHARTS_PERIOD: usize = 24 * 60 * 60; // seconds
+const TOP_POSTS: usize = 5;
+
+// A value of a metric at the end of a window.
+type Sample = (usize, f64);
+
+// The metrics of every window, charted over time.
+pub struct Metrics {
+    active_posts: Vec<Sample>,
+    comments: Vec<Sample>,
+    replies: Vec<Sample>,
+    // the engaged people of each post, summed over the posts
+    engaged_people: Vec<Sample>,
+    // map: post -> engaged people in each window, for the candidate top posts
+    posts: HashMap<u32, Vec<Sample>>,
+    top: usize,
+}
+
+impl Metrics {
+    // Charts the `top` posts with the most people engaged.
+    pub fn new(top: usize) -> Metrics {
+        Metrics {
+            active_posts: vec![],
+            comments: vec![],
+            replies: vec![],
+            engaged_people: vec![],
+            posts: HashMap::new(),
+            top,
+        }
+    }
 
-            let buffered_likes = likes.buffer(Exchange::new(|l: &Like| {
-                if l.is_watermark {
-                    return l.post_id as u64;
+    // Records the counts of the active posts in the window ending at `time`.
+    pub fn counts(&mut self, time: usize, counts: &[(u32, usize, usize)]) {
+        let comments: usize = counts.iter().map(|(_, comments, _)| comments).sum();
+        let replies: usize = counts.iter().map(|(_, _, replies)| replies).sum();
+        self.active_posts.push((time, counts.len() as f64));
+        self.comments.push((time, comments as f64));
+        self.replies.push((time, replies as f64));
+    }
+
+    // Records the number of people engaged with each post in the window ending at `time`.
+    pub fn engaged(&mut self, time: usize, engaged: &[(u32, usize)]) {
+        let people: usize = engaged.iter().map(|(_, people)| people).sum();
+        self.engaged_people.push((time, people as f64));
+        for (post_id, people) in engaged.iter() {
+            self.posts
+                .entry(*post_id)
+                .or_insert(vec![])
+                .push((time, *people as f64));
+        }
+
+        // only the top posts so far and the posts still engaged with can be top posts later
+        let engaged_now: HashSet<u32> = engaged.iter().map(|(post_id, _)| *post_id).collect();
+        let top: HashSet<u32> = self.top_posts().into_iter().collect();
+        self.posts
+            .retain(|post_id, _| top.contains(post_id) || engaged_now.contains(post_id));
+    }
+
+    // The posts with the most people engaged in any window, most engaging first.
+    fn top_posts(&self) -> Vec<u32> {
+        let max = |samples: &Vec<Sample>| samples.iter().map(|(_, y)| *y).fold(0., f64::max);
+        let mut posts: Vec<(u32, f64)> = self
+            .posts
+            .iter()
+            .map(|(post_id, samples)| (*post_id, max(samples)))
+            .collect();
+        posts.sort_by(|(id1, y1), (id2, y2)| y2.partial_cmp(y1).unwrap().then(id1.cmp(id2)));
+        posts
+            .into_iter()
+            .take(self.top)
+            .map(|(post_id, _)| post_id)
+            .collect()
+    }
+
+    // The charts by name, with the time in hours since the first window.
+    pub fn charts(&self) -> Vec<(&'static str, LineChart)> {
+        let times = self.active_posts.iter().chain(self.engaged_people.iter());
+        let start = match times.map(|(time, _)| *time).min() {
+            Some(start) => start,
+            None => return vec![],
+        };
+        let x_label = format!("Hours since {}", start);
+        let hours = |time: usize| (time - start) as f64 / 3600.;
+        let chart = |y_label: &str, lines: Vec<(String, &Vec<Sample>)>| {
+            let mut chart = LineChart::new(&x_label, y_label);
+            for (line, samples) in lines {
+                for (time, y) in samples.iter() {
+                    chart.add(&line, hours(*time), *y);
                 }
-                let mut hasher = DefaultHasher::new();
-                hasher.write_u32(l.post_id);
-                hasher.finish()
-            }));
-            let buffered_posts = posts.buffer(Exchange::new(|p: &Post| {
-                if p.is_watermark {
-                    return p.id as u64;
+            }
+            chart
+        };
+
+        let top_posts = self
+            .top_posts()
+            .into_iter()
+            .enumerate()
+            .map(|(i, post_id)| {
+                (
+                    format!("{}. post {}", i + 1, post_id),
+                    &self.posts[&post_id],
+                )
+            })
+            .collect();
+        vec![
+            (
+                "active_posts",
+                chart(
+                    "Active posts",
+                    vec![("active posts".to_string(), &self.active_posts)],
+                ),
+            ),
+            (
+                "comments",
+                chart(
+                    "Comments and replies",
+                    vec![
+                        ("comments".to_string(), &self.comments),
+                        ("replies".to_string(), &self.replies),
+                    ],
+                ),
+            ),
+            (
+                "engaged_people",
+                chart(
+                    "Engaged people, summed over the posts",
+                    vec![("engaged people".to_string(), &self.engaged_people)],
+                ),
+            ),
+            ("top_posts", chart("Engaged people", top_posts)),
+        ]
+    }
+}
+
+// Builds the dataflow computing the number of uniquely engaged people and the number of
+// comments and replies for the active posts, along with the comments linked to their post.
+pub fn dataflow<G: Scope<Timestamp = usize>>(
+    posts: &Stream<G, Post>,
+    comments: &Stream<G, Comment>,
+    likes: &Stream<G, Like>,
+    delay: usize,
+    idle_after: Duration,
+    thread_config: ThreadConfig,
+) -> (
+    Stream<G, (u32, usize)>,
+    Stream<G, (u32, usize, usize)>,
+    Stream<G, Comment>,
+) {
+    let buffered_likes = likes.buffer(
+        Exchange::new(|l: &Like| {
+            if l.is_watermark {
+                return l.post_id as u64;
+            }
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u32(l.post_id);
+            hasher.finish()
+        }),
+        idle_after,
+    );
+    let buffered_posts = posts.buffer(
+        Exchange::new(|p: &Post| {
+            if p.is_watermark {
+                return p.id as u64;
+            }
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u32(p.id);
+            hasher.finish()
+        }),
+        idle_after,
+    );
+
+    let linked_comments = comments
+        .route_replies(thread_config.horizon, thread_config.orphan_timeout)
+        .link_replies(
+            &buffered_posts,
+            Pipeline,
+            Pipeline,
+            delay,
+            thread_config.horizon,
+            thread_config.orphan_timeout,
+        );
+
+    let active_posts = linked_comments.active_post_ids(
+        &buffered_likes,
+        Pipeline,
+        Pipeline,
+        delay,
+        ACTIVE_POST_PERIOD,
+    );
+
+    let engaged_users = active_posts.engaged_users(Pipeline, 2 * COLLECTION_PERIOD);
+    let counts = active_posts.counts(&linked_comments, Pipeline, Pipeline, COLLECTION_PERIOD);
+    (engaged_users, counts, linked_comments)
+}
+
+// Gathers the outputs of all the workers on the first one, and charts them in `dir` once
+// every CHARTS_PERIOD and when the streams end.
+fn charts<G: Scope<Timestamp = usize>>(
+    engaged_users: &Stream<G, (u32, usize)>,
+    counts: &Stream<G, (u32, usize, usize)>,
+    dir: PathBuf,
+) -> Stream<G, ()> {
+    std::fs::create_dir_all(&dir).expect("Could not create the charts directory");
+    let mut metrics = Metrics::new(TOP_POSTS);
+    // map: time -> (engaged users, counts)
+    let mut windows: BTreeMap<usize, (Vec<(u32, usize)>, Vec<(u32, usize, usize)>)> =
+        BTreeMap::new();
+    let periodic = Periodic::new(CHARTS_PERIOD, 2);
+
+    engaged_users.binary_notify(
+        counts,
+        Exchange::new(|_: &(u32, usize)| 0),
+        Exchange::new(|_: &(u32, usize, usize)| 0),
+        "Charts",
+        None,
+        move |e_input, c_input, _, notificator| {
+            e_input.for_each(|cap, input| {
+                let window = windows.entry(*cap.time()).or_default();
+                window.0.extend(input.iter().cloned());
+                notificator.notify_at(cap.delayed(&periodic.boundary(*cap.time())));
+            });
+            c_input.for_each(|cap, input| {
+                let window = windows.entry(*cap.time()).or_default();
+                window.1.extend(input.iter().cloned());
+                notificator.notify_at(cap.delayed(&periodic.boundary(*cap.time())));
+            });
+
+            notificator.for_each(|cap, _, notificator| {
+                periodic.next(&cap, notificator);
+
+                let later = windows.split_off(cap.time());
+                // the windows of the two outputs end at different times
+                for (time, (engaged, counts)) in std::mem::replace(&mut windows, later) {
+                    if !engaged.is_empty() {
+                        metrics.engaged(time, &engaged);
+                    }
+                    if !counts.is_empty() {
+                        metrics.counts(time, &counts);
+                    }
                 }
-                let mut hasher = DefaultHasher::new();
-                hasher.write_u32(p.id);
-                hasher.finish()
-            }));
-
-            let linked_comments = comments.broadcast().link_replies(
-                &buffered_posts,
-                Pipeline,
-                Pipeline,
-                FIXED_BOUNDED_DELAY,
-            );
+                for (name, chart) in metrics.charts() {
+                    chart.save(&dir.join(format!("{}.svg", name)));
+                }
+            });
+        },
+    )
+}
 
-            let active_posts = linked_comments.active_post_ids(
-                &buffered_likes,
-                Pipeline,
-                Pipeline,
-                FIXED_BOUNDED_DELAY,
-                ACTIVE_POST_PERIOD,
-            );
+// Asks for the subtrees of the given comments at the end of every period. The queries are
+// sent to every worker, so only the first one asks them.
+fn subtree_queries<G: Scope<Timestamp = usize>>(
+    comments: &Stream<G, Comment>,
+    ids: Vec<u32>,
+    period: usize,
+) -> Stream<G, u32> {
+    let index = comments.scope().index();
+    let periodic = Periodic::new(period, 1);
+    comments.unary_notify(
+        Pipeline,
+        "SubtreeQueries",
+        None,
+        move |input, output, notificator| {
+            input.for_each(|cap, _| {
+                notificator.notify_at(cap.delayed(&periodic.boundary(*cap.time())));
+            });
+
+            notificator.for_each(|cap, _, notificator| {
+                periodic.next(&cap, notificator);
+                if index == 0 {
+                    output.session(&cap).give_iterator(ids.iter().cloned());
+                }
+            });
+        },
+    )
+}
 
-            active_posts
-                .engaged_users(Pipeline, 2 * COLLECTION_PERIOD)
-                .inspect_batch(|t, xs| println!("#uniquely engaged people @t={:?}: {:?}", t, xs));
+pub fn run(
+    delay: usize,
+    charts_dir: Option<PathBuf>,
+    threads: bool,
+    subtrees: Vec<u32>,
+    thread_config: ThreadConfig,
+    source: SourceConfig,
+) {
+    timely::execute(Configuration::Process(4), move |worker| {
+        let index = worker.index();
+        let mut probe = ProbeHandle::new();
+        worker.dataflow::<usize, _, _>(|scope| {
+            let posts =
+                scope.kafka_string_source::<Post>("posts".to_string(), index, &source, &probe);
+            let comments = scope.kafka_string_source::<Comment>(
+                "comments".to_string(),
+                index,
+                &source,
+                &probe,
+            );
+            let likes =
+                scope.kafka_string_source::<Like>("likes".to_string(), index, &source, &probe);
 
-            active_posts
-                .counts(&linked_comments, Pipeline, Pipeline, COLLECTION_PERIOD)
-                .inspect_batch(|t, xs| println!("#comments and replies @t={:?}: {:?}", t, xs));
+            let (engaged_users, counts, linked_comments) = dataflow(
+                &posts,
+                &comments,
+                &likes,
+                delay,
+                source.idle_partition,
+                thread_config,
+            );
+            if let Some(dir) = charts_dir.clone() {
+                charts(&engaged_users, &counts, dir);
+            }
+            if threads {
+                linked_comments
+                    .thread_stats(
+                        Pipeline,
+                        COLLECTION_PERIOD,
+                        thread_config.horizon,
+                        thread_config.orphan_timeout,
+                    )
+                    .inspect_batch(|t, xs| println!("threads @t={:?}: {:?}", t, xs))
+                    .probe_with(&mut probe);
+            }
+            if !subtrees.is_empty() {
+                let queries =
+                    subtree_queries(&linked_comments, subtrees.clone(), COLLECTION_PERIOD);
+                linked_comments
+                    .subtrees(
+                        &queries,
+                        Pipeline,
+                        thread_config.horizon,
+                        thread_config.orphan_timeout,
+                    )
+                    .inspect_batch(|t, xs| println!("subtrees @t={:?}: {:?}", t, xs))
+                    .probe_with(&mut probe);
+            }
+            engaged_users
+                .inspect_batch(|t, xs| println!("#uniquely engaged people @t={:?}: {:?}", t, xs))
+                .probe_with(&mut probe);
+            counts
+                .inspect_batch(|t, xs| println!("#comments and replies @t={:?}: {:?}", t, xs))
+                .probe_with(&mut probe);
         });
     })
     .unwrap();
 }
+
+#[cfg(test)]
+mod post_stats_tests {
+    extern crate timely;
+
+    use crate::dto::comment::Comment;
+    use crate::dto::like::Like;
+    use crate::dto::post::Post;
+    use crate::operators::buffer::IDLE_AFTER;
+    use crate::operators::link_replies::ThreadConfig;
+    use crate::tasks::harness::*;
+    use crate::tasks::post_stats::{dataflow, Metrics};
+
+    use timely::dataflow::operators::Input;
+    use timely::Configuration;
+
+    #[test]
+    fn test_post_stats_matches_golden() {
+        let outputs = execute(Configuration::Thread, |worker, outputs, probe| {
+            let mut posts = replay::<Post>("post_event_stream.csv");
+            let mut comments = replay::<Comment>("comment_event_stream.csv");
+            let mut likes = replay::<Like>("likes_event_stream.csv");
+
+            worker.dataflow(|scope| {
+                let (engaged_users, counts, _) = dataflow(
+                    &scope.input_from(posts.input()),
+                    &scope.input_from(comments.input()),
+                    &scope.input_from(likes.input()),
+                    DELAY,
+                    IDLE_AFTER,
+                    ThreadConfig::default(),
+                );
+                collect(&engaged_users, "engaged", outputs, probe);
+                collect(&counts, "counts", outputs, probe);
+            });
+
+            let watermarks = watermarks(posts.start_time());
+            ((posts, comments, likes), watermarks)
+        });
+
+        assert_golden("post_stats", outputs);
+    }
+
+    #[test]
+    fn test_metrics_are_charted_over_time() {
+        let mut metrics = Metrics::new(2);
+        metrics.counts(1800, &[(1, 2, 1), (2, 0, 3)]);
+        metrics.engaged(3600, &[(1, 4), (2, 1), (3, 6)]);
+        metrics.counts(3600, &[(1, 5, 0)]);
+        metrics.engaged(7200, &[(1, 5)]);
+
+        // post 2 can no longer be a top post
+        assert_eq!(metrics.posts.len(), 2);
+
+        let charts = metrics.charts();
+        let names: Vec<&str> = charts.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            vec!["active_posts", "comments", "engaged_people", "top_posts"]
+        );
+
+        let lines = charts[1].1.lines();
+        assert_eq!(lines["comments"], vec![(0., 2.), (0.5, 5.)]);
+        assert_eq!(lines["replies"], vec![(0., 4.), (0.5, 0.)]);
+        assert_eq!(
+            charts[2].1.lines()["engaged people"],
+            vec![(0.5, 11.), (1.5, 5.)]
+        );
+
+        // the posts with the most engaged people in any window
+        let top: Vec<&String> = charts[3].1.lines().keys().collect();
+        assert_eq!(top, vec!["1. post 3", "2. post 1"]);
+        assert_eq!(charts[3].1.lines()["2. post 1"], vec![(0.5, 4.), (1.5, 5.)]);
+    }
+}
@@ -1,158 +1,921 @@
 extern crate rand;
 extern crate timely;
 
+use crate::dsa::anomaly::{AnomalyDetector, DetectorKind, Observations};
+use crate::dsa::behaviour::{population, Baselines, Behaviour, Posts, BEHAVIOURS};
+use crate::dsa::features::{FeatureExtractor, FeatureKind};
 use crate::dsa::kmeans::*;
-use crate::dsa::stash::*;
+use crate::dsa::online_kmeans::{summarise, OnlineKMeans, Statistics};
+use crate::dsa::pca::Projection;
+use crate::dsa::stash::Stash;
+use crate::dto::comment::Comment;
+use crate::dto::like::Like;
 use crate::dto::post::Post;
 use crate::operators::buffer::Buffer;
-use crate::operators::source::KafkaSource;
+use crate::operators::periodic::Periodic;
+use crate::operators::source::{KafkaSource, SourceConfig};
 use crate::util::Plotter;
 
-use std::collections::HashSet;
-use std::iter::FromIterator;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
 use std::string::ToString;
+use std::time::Duration;
 
 use std::collections::hash_map::DefaultHasher;
 use std::hash::Hasher;
 use timely::dataflow::channels::pact::{Exchange, Pipeline};
+use timely::dataflow::operators::broadcast::Broadcast;
+use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::filter::Filter;
 use timely::dataflow::operators::generic::operator::Operator;
 use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::probe::Probe;
+use timely::dataflow::{ProbeHandle, Scope, Stream};
 
 const OUTLIER_PERECENTILE: f64 = 0.99;
 const NUM_CLUSTERS: usize = 10;
 const MIN_COVERAGE: usize = 30;
 const MIN_POINTS: usize = 2000;
+const SEED: u64 = 0;
+const HISTORY: usize = 14; // periods
+const DECAY: f64 = 0.8;
 
-const MAX_POST_LENGTH: usize = 64;
 const NOTIFY_PERIOD: usize = 12 * 60 * 60; // seconds
+const COOLDOWN: usize = 3 * 24 * 60 * 60; // seconds
 
-fn get_data_point(text: &String) -> Option<Point> {
-    let mut alpha_text = text.clone();
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub num_clusters: usize,
+    pub min_coverage: usize,
+    pub min_points: usize,
+    pub outlier_percentile: f64,
+    pub notify_period: usize,
+    // plot the clusters of every period in this directory
+    pub plot: Option<PathBuf>,
+    // the features the points are made of
+    pub features: Vec<FeatureKind>,
+    // seed of the k-means initialisation
+    pub seed: u64,
+    // refine the clusters with mini-batches of this size instead of all the points
+    pub batch_size: Option<usize>,
+    // number of periods whose points are searched for outliers
+    pub history: usize,
+    // fraction of the weight of the clusters kept from one period to the next
+    pub decay: f64,
+    // how the outliers are told apart
+    pub detector: DetectorKind,
+    // also flag the people that behave unusually in the comments and likes streams
+    pub behaviour: bool,
+    // a person is not reported again for this long after a report
+    pub cooldown: usize,
+}
+
+impl Config {
+    pub fn kmeans(&self) -> KMeans {
+        let kmeans = KMeans::new(self.num_clusters, self.min_coverage, self.seed);
+        match self.batch_size {
+            Some(batch_size) => kmeans.mini_batch(batch_size),
+            None => kmeans,
+        }
+    }
+}
 
-    // remove punctuation
-    for sep in "?!.,;:".chars() {
-        alpha_text = alpha_text.replace(sep, " ");
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            num_clusters: NUM_CLUSTERS,
+            min_coverage: MIN_COVERAGE,
+            min_points: MIN_POINTS,
+            outlier_percentile: OUTLIER_PERECENTILE,
+            notify_period: NOTIFY_PERIOD,
+            plot: None,
+            // the other constants are tuned for the lengths
+            features: vec![FeatureKind::Lengths],
+            seed: SEED,
+            batch_size: None,
+            history: HISTORY,
+            decay: DECAY,
+            detector: DetectorKind::Clusters,
+            behaviour: true,
+            cooldown: COOLDOWN,
+        }
     }
+}
 
-    // keep only alpha text
-    alpha_text.retain(|c| c.is_alphabetic() || c.is_whitespace());
-    let mut words: Vec<_> = alpha_text
-        .split_whitespace()
-        .map(|x| x.to_string())
-        .collect();
+// The plots show the first two principal components of the points, whose axes are labelled
+// with the feature that weighs the most in them.
+fn project(
+    names: &[String],
+    centers: &[Point],
+    points: &[Point],
+    outliers: &[Point],
+) -> ((String, String), Vec<Vec<(f64, f64)>>) {
+    let projection = Projection::fit(points, 2);
+    let mut labels = projection.axes().iter().enumerate().map(|(i, axis)| {
+        let (main, _) = axis
+            .iter()
+            .enumerate()
+            .fold((0, 0.), |(main, max), (j, x)| match x.abs() > max {
+                true => (j, x.abs()),
+                false => (main, max),
+            });
+        match names.len() <= 2 {
+            true => names[main].clone(),
+            false => format!("PC{}, mostly {}", i + 1, names[main]),
+        }
+    });
+    let labels = (
+        labels.next().unwrap_or(String::new()),
+        labels.next().unwrap_or(String::new()),
+    );
 
-    // lower case
-    words = words.iter().map(|x| x.to_lowercase()).collect();
+    let project = |points: &[Point]| -> Vec<(f64, f64)> {
+        points
+            .iter()
+            .map(|point| {
+                let point = projection.project(point);
+                (
+                    point.get(0).cloned().unwrap_or(0.),
+                    point.get(1).cloned().unwrap_or(0.),
+                )
+            })
+            .collect()
+    };
+    (
+        labels,
+        vec![project(centers), project(points), project(outliers)],
+    )
+}
 
-    let text_len = words.len();
-    if text_len < 2 {
-        return None;
+// The evidence against a person flagged as suspicious.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SuspicionReport {
+    pub person_id: u32,
+    // at least 1, how unusual the person is
+    pub score: f64,
+    // the unusual posts, which are none if only the behaviour of the person is unusual
+    pub post_ids: Vec<u32>,
+    // the values of the features of the most unusual post, or of the behaviour
+    pub features: Vec<(String, f64)>,
+    // the distance from the most unusual post to the closest cluster center
+    pub distance: Option<f64>,
+    // when the person was first and last flagged, in the current streak of flags
+    pub first_flagged: usize,
+    pub last_flagged: usize,
+}
+
+impl SuspicionReport {
+    // Merges two reports on the same person, keeping the features of the most unusual one
+    // and the posts of both.
+    pub fn merge(self, other: SuspicionReport) -> SuspicionReport {
+        let (mut best, other) = match other.score > self.score {
+            true => (other, self),
+            false => (self, other),
+        };
+        best.post_ids.extend(other.post_ids);
+        best.post_ids.sort();
+        best.post_ids.dedup();
+        best.distance = best.distance.or(other.distance);
+        best.first_flagged = best.first_flagged.min(other.first_flagged);
+        best.last_flagged = best.last_flagged.max(other.last_flagged);
+        best
     }
+}
 
-    let unique_words: HashSet<_> = HashSet::from_iter(words.iter().cloned());
-    let mut unique_bigrams = HashSet::new();
-    for i in 0..words.len() - 2 {
-        unique_bigrams.insert(words[i].clone() + " " + &words[i + 1].clone());
+// Deduplicates the reports across periods. A person flagged again within the cool-down of
+// their last report is not reported again, but their streak of flags goes on.
+pub struct Reporter {
+    cooldown: usize,
+    // map: person -> (first flagged, last flagged, last reported)
+    flagged: HashMap<u32, (usize, usize, Option<usize>)>,
+}
+
+impl Reporter {
+    pub fn new(cooldown: usize) -> Reporter {
+        Reporter {
+            cooldown,
+            flagged: HashMap::new(),
+        }
     }
 
-    let uniq_words_len = unique_words.len();
-    let uniq_bigam_len = unique_bigrams.len();
+    // Takes in the reports of the period ending at `time`, in any order, and returns the
+    // ones to emit, in increasing order of person id.
+    pub fn report(&mut self, time: usize, reports: Vec<SuspicionReport>) -> Vec<SuspicionReport> {
+        let mut by_person: BTreeMap<u32, SuspicionReport> = BTreeMap::new();
+        for report in reports {
+            let merged = match by_person.remove(&report.person_id) {
+                Some(previous) => previous.merge(report),
+                None => report,
+            };
+            by_person.insert(merged.person_id, merged);
+        }
 
-    let uniq_words_len = if uniq_words_len > MAX_POST_LENGTH {
-        1.
-    } else {
-        uniq_words_len as f64 / MAX_POST_LENGTH as f64
-    };
-    let uniq_bigam_len = if uniq_bigam_len > MAX_POST_LENGTH {
-        1.
-    } else {
-        uniq_bigam_len as f64 / MAX_POST_LENGTH as f64
-    };
+        let cooldown = self.cooldown;
+        let mut emitted = vec![];
+        for (person_id, mut report) in by_person {
+            let (first, last, reported) =
+                self.flagged.entry(person_id).or_insert((time, time, None));
+            // a streak ends when a person is not flagged for a whole cool-down
+            if *last + cooldown < time {
+                *first = time;
+                *reported = None;
+            }
+            *last = time;
 
-    return Some((uniq_words_len, uniq_bigam_len));
+            if reported.map_or(true, |reported| time >= reported + cooldown) {
+                *reported = Some(time);
+                report.first_flagged = *first;
+                report.last_flagged = time;
+                emitted.push(report);
+            }
+        }
+
+        // forget the streaks that ended
+        self.flagged
+            .retain(|_, (_, last, _)| *last + cooldown >= time);
+        emitted
+    }
 }
 
-pub fn run() {
-    timely::execute_from_args(std::env::args(), |worker| {
-        let index = worker.index();
-        worker.dataflow::<usize, _, _>(|scope| {
-            let posts = scope.kafka_string_source::<Post>("posts".to_string(), index);
-            let buffered_posts = posts.buffer(Exchange::new(|p: &Post| {
-                if p.is_watermark {
-                    return p.id as u64;
+// Summarises the posts of a period on one worker, so that only the statistics of their
+// clusters need to be shared with the other workers.
+pub struct Summariser {
+    kmeans: KMeans,
+}
+
+impl Summariser {
+    pub fn new(config: &Config) -> Summariser {
+        Summariser {
+            kmeans: KMeans::new(config.num_clusters, 1, config.seed),
+        }
+    }
+
+    // Sorts the posts, since the clusters depend on the order of the points.
+    pub fn summarise(&mut self, posts: &mut Vec<(Point, Post)>) -> Vec<Statistics> {
+        posts.sort_by_key(|(_, post)| (post.timestamp, post.id));
+        let points: Vec<Point> = posts.iter().map(|(point, _)| point.clone()).collect();
+        summarise(&mut self.kmeans, &points)
+    }
+}
+
+// Finds the authors of unusual posts one period at a time. The clusters are maintained
+// online from the statistics of all the workers, so every worker has the same model. The
+// outliers are searched among the local points of the last `history` periods, so the cost
+// of a period does not grow with the stream.
+pub struct Detector {
+    config: Config,
+    model: OnlineKMeans,
+    // the local points of the last periods with their authors, the most recent last
+    points: VecDeque<Vec<(Point, u32)>>,
+    // the statistics of all the workers in the last periods, the most recent last
+    statistics: VecDeque<Vec<Statistics>>,
+    anomaly_detector: Box<dyn AnomalyDetector>,
+    // the name of each dimension of the points
+    names: Vec<String>,
+    plotter: Option<Plotter>,
+}
+
+impl Detector {
+    // The plots of each worker are told apart by its index.
+    pub fn new(config: &Config, worker: usize) -> Detector {
+        Detector {
+            config: config.clone(),
+            model: OnlineKMeans::new(config.kmeans(), config.decay, config.min_coverage as f64),
+            points: VecDeque::new(),
+            statistics: VecDeque::new(),
+            anomaly_detector: config
+                .detector
+                .detector(config.outlier_percentile, config.seed),
+            names: FeatureExtractor::new(&config.features).names(),
+            plotter: config
+                .plot
+                .as_ref()
+                .map(|dir| Plotter::new(dir, &format!("worker{}", worker))),
+        }
+    }
+
+    // Takes in the local posts of the period ending at `time` and the statistics of all the
+    // workers in that period, and reports the authors of the local outliers, in increasing
+    // order of id.
+    pub fn period(
+        &mut self,
+        time: usize,
+        posts: Vec<(Point, Post)>,
+        statistics: Vec<Statistics>,
+    ) -> Vec<SuspicionReport> {
+        self.points.push_back(
+            posts
+                .iter()
+                .map(|(point, post)| (point.clone(), post.person_id))
+                .collect(),
+        );
+        self.statistics.push_back(statistics.clone());
+        while self.points.len() > self.config.history {
+            self.points.pop_front();
+            self.statistics.pop_front();
+        }
+
+        // the decision only depends on the statistics, so that all the models stay equal
+        let window: Vec<Statistics> = self.statistics.iter().flatten().cloned().collect();
+        let count: f64 = window.iter().map(|cluster| cluster.count).sum();
+        if count <= self.config.min_points as f64 {
+            return vec![];
+        }
+        self.model.update(&statistics, &window);
+
+        let (points, persons): (Vec<Point>, Vec<u32>) =
+            self.points.iter().flatten().cloned().unzip();
+        if points.is_empty() {
+            return vec![];
+        }
+        let centers = self.model.centers();
+        let scores = self.anomaly_detector.scores(&Observations {
+            points: &points,
+            persons: &persons,
+            centers,
+        });
+        let outliers: Vec<Point> = points
+            .iter()
+            .zip(scores.iter())
+            .filter(|(_, score)| **score >= 1.)
+            .map(|(point, _)| point.clone())
+            .collect();
+
+        // plot points for debugging
+        if let Some(plotter) = self.plotter.as_mut() {
+            let ((x_label, y_label), projected) = project(&self.names, centers, &points, &outliers);
+            plotter.plot_points(
+                (&x_label, &y_label),
+                &projected[0],
+                &projected[1],
+                &projected[2],
+            );
+        }
+
+        // finding outliers from current batch, which are the last points of the window
+        let mut reports: BTreeMap<u32, SuspicionReport> = BTreeMap::new();
+        let first = points.len() - posts.len();
+        for (i, (point, post)) in posts.iter().enumerate() {
+            let score = scores[first + i];
+            if score < 1. {
+                continue;
+            }
+            let features = self.names.iter().cloned().zip(point.iter().cloned());
+            let report = SuspicionReport {
+                person_id: post.person_id,
+                score,
+                post_ids: vec![post.id],
+                features: features.collect(),
+                distance: match centers.is_empty() {
+                    true => None,
+                    false => Some(min_dist(centers, point).sqrt()),
+                },
+                first_flagged: time,
+                last_flagged: time,
+            };
+            match reports.remove(&post.person_id) {
+                Some(previous) => reports.insert(post.person_id, previous.merge(report)),
+                None => reports.insert(post.person_id, report),
+            };
+        }
+        reports.into_iter().map(|(_, report)| report).collect()
+    }
+}
+
+// The posts of a period on one worker, with their statistics.
+#[derive(Clone, Debug)]
+struct Period {
+    posts: Vec<(Point, Post)>,
+    statistics: Vec<Statistics>,
+}
+
+// Builds the dataflow outputting the reports on the people that post unusual content.
+pub fn dataflow<G: Scope<Timestamp = usize>>(
+    posts: &Stream<G, Post>,
+    config: &Config,
+    idle_after: Duration,
+) -> Stream<G, SuspicionReport> {
+    reports(&content_dataflow(posts, config, idle_after), config)
+}
+
+// Deduplicates the reports of each period and across periods.
+pub fn reports<G: Scope<Timestamp = usize>>(
+    reports: &Stream<G, SuspicionReport>,
+    config: &Config,
+) -> Stream<G, SuspicionReport> {
+    let mut stash: Stash<SuspicionReport> = Stash::new();
+    let mut reporter = Reporter::new(config.cooldown);
+    reports.unary_notify(
+        Exchange::new(|report: &SuspicionReport| {
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u32(report.person_id);
+            hasher.finish()
+        }),
+        "Suspicion Reports",
+        None,
+        move |input, output, notificator| {
+            input.for_each(|cap, data| {
+                let mut vec = vec![];
+                data.swap(&mut vec);
+                for report in vec.drain(..) {
+                    stash.stash(*cap.time(), report);
                 }
-                let mut hasher = DefaultHasher::new();
-                hasher.write_u32(p.id);
-                hasher.finish()
-            }));
-
-            let mut points: Vec<Point> = vec![];
-            let mut centers: Vec<Point> = vec![];
-
-            let mut first_notified = false;
-            let mut stash = Stash::new();
-
-            let mut plotter = Plotter::new();
-            buffered_posts
-                .unary_notify(
-                    Pipeline,
-                    "Unusual Activity",
-                    None,
-                    move |input, output, notificator| {
-                        let mut vec = vec![];
-                        while let Some((time, data)) = input.next() {
-                            data.swap(&mut vec);
-                            if !first_notified {
-                                notificator.notify_at(time.delayed(&(time.time() + NOTIFY_PERIOD)));
-                                first_notified = true;
-                            }
-                            for post in vec.drain(..) {
-                                if let Some(data_point) = get_data_point(&post.content.clone()) {
-                                    stash.stash(*time.time(), (data_point, post));
-                                }
-                            }
-
-                            notificator.for_each(|cap, _, notificator| {
-                                notificator.notify_at(cap.delayed(&(cap.time() + NOTIFY_PERIOD)));
-
-                                let possible_outliers = stash.extract(NOTIFY_PERIOD, *cap.time());
-                                for (point, _) in possible_outliers.iter() {
-                                    points.push(*point);
-                                }
-
-                                if points.len() > MIN_POINTS {
-                                    centers = compute_centers(
-                                        &centers,
-                                        &points,
-                                        NUM_CLUSTERS,
-                                        MIN_COVERAGE,
-                                    );
-                                    let outliers =
-                                        compute_outliers(&centers, &points, OUTLIER_PERECENTILE);
-
-                                    // plot points for debugging
-                                    plotter.plot_points(&centers, &points, &outliers);
-
-                                    // finding outliers from current batch
-                                    let mut session = output.session(&cap);
-                                    let mut people_ids = HashSet::new();
-                                    for outlier in outliers {
-                                        for (point, post) in possible_outliers.iter() {
-                                            if sqr_dist(point, &outlier) < EPS {
-                                                if !people_ids.contains(&post.person_id) {
-                                                    session.give(post.person_id);
-                                                    people_ids.insert(&post.person_id);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            });
-                        }
-                    },
+                notificator.notify_at(cap.retain());
+            });
+
+            notificator.for_each(|cap, _, _| {
+                let reports = stash.remove(cap.time()).unwrap_or(vec![]);
+                let mut session = output.session(&cap);
+                for report in reporter.report(*cap.time(), reports) {
+                    session.give(report);
+                }
+            });
+        },
+    )
+}
+
+// Builds the dataflow flagging the people that post unusual content, in every period.
+fn content_dataflow<G: Scope<Timestamp = usize>>(
+    posts: &Stream<G, Post>,
+    config: &Config,
+    idle_after: Duration,
+) -> Stream<G, SuspicionReport> {
+    let config = config.clone();
+    let worker = posts.scope().index();
+    let buffered_posts = posts.buffer(
+        Exchange::new(|p: &Post| {
+            if p.is_watermark {
+                return p.id as u64;
+            }
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u32(p.id);
+            hasher.finish()
+        }),
+        idle_after,
+    );
+
+    let mut stash = Stash::new();
+    let mut extractor = FeatureExtractor::new(&config.features);
+    let mut summariser = Summariser::new(&config);
+
+    let notify_period = config.notify_period;
+    let periodic = Periodic::new(notify_period, 1);
+    let periods = buffered_posts.unary_notify(
+        Pipeline,
+        "Local Statistics",
+        None,
+        move |input, output, notificator| {
+            input.for_each(|time, data| {
+                let mut vec = vec![];
+                data.swap(&mut vec);
+                notificator.notify_at(time.delayed(&periodic.boundary(*time.time())));
+                for post in vec.drain(..) {
+                    if let Some(data_point) = extractor.extract(&post) {
+                        stash.stash(*time.time(), (data_point, post));
+                    }
+                }
+            });
+
+            // every period is summarised, even without posts, so that all the workers
+            // send their statistics at every boundary
+            notificator.for_each(|cap, _, notificator| {
+                periodic.next(&cap, notificator);
+
+                let mut posts = stash.extract(notify_period, *cap.time());
+                // the posts arriving after their period are dropped
+                stash.evict(cap.time().saturating_sub(notify_period));
+                let statistics = summariser.summarise(&mut posts);
+                output.session(&cap).give(Period { posts, statistics });
+            });
+        },
+    );
+
+    // every worker merges the statistics of all the workers
+    let statistics = periods
+        .map(move |period| (worker, period.statistics))
+        .broadcast();
+
+    let mut local_periods = Stash::new();
+    let mut global_statistics = Stash::new();
+    let mut detector = Detector::new(&config, worker);
+    periods.binary_notify(
+        &statistics,
+        Pipeline,
+        Pipeline,
+        "Unusual Activity",
+        None,
+        move |local_input, global_input, output, notificator| {
+            local_input.for_each(|cap, input| {
+                let mut vec = vec![];
+                input.swap(&mut vec);
+                for period in vec.drain(..) {
+                    local_periods.stash(*cap.time(), period);
+                }
+                notificator.notify_at(cap.retain());
+            });
+            global_input.for_each(|cap, input| {
+                let mut vec = vec![];
+                input.swap(&mut vec);
+                for statistics in vec.drain(..) {
+                    global_statistics.stash(*cap.time(), statistics);
+                }
+                notificator.notify_at(cap.retain());
+            });
+
+            notificator.for_each(|cap, _, _| {
+                let posts: Vec<(Point, Post)> = local_periods
+                    .remove(cap.time())
+                    .unwrap_or(vec![])
+                    .into_iter()
+                    .flat_map(|period: Period| period.posts)
+                    .collect();
+
+                // merge in the same order on every worker
+                let mut statistics = global_statistics.remove(cap.time()).unwrap_or(vec![]);
+                statistics.sort_by_key(|(worker, _): &(usize, Vec<Statistics>)| *worker);
+                let statistics = statistics.into_iter().flat_map(|(_, s)| s).collect();
+
+                let mut session = output.session(&cap);
+                for report in detector.period(*cap.time(), posts, statistics) {
+                    session.give(report);
+                }
+            });
+        },
+    )
+}
+
+// An event of any of the streams. The comments and likes carry the (author, creation time)
+// of the post they are on, once it is looked up.
+#[derive(Clone, Debug)]
+enum Activity {
+    Post(Post),
+    Comment(Comment, Option<(u32, usize)>),
+    Like(Like, Option<(u32, usize)>),
+}
+
+impl Activity {
+    fn timestamp(&self) -> usize {
+        match self {
+            Activity::Post(post) => post.timestamp,
+            Activity::Comment(comment, _) => comment.timestamp,
+            Activity::Like(like, _) => like.timestamp,
+        }
+    }
+
+    fn person_id(&self) -> u32 {
+        match self {
+            Activity::Post(post) => post.person_id,
+            Activity::Comment(comment, _) => comment.person_id,
+            Activity::Like(like, _) => like.person_id,
+        }
+    }
+}
+
+fn hash(id: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u32(id);
+    hasher.finish()
+}
+
+// Builds the dataflow flagging the people that behave unusually, in every period. The
+// comments and likes are first sent to the worker owning the post they are on, to look up
+// its author, and then every activity is sent to the worker owning the person. The people
+// are compared with all the people active in the period, so every worker shares the values
+// of the behaviours of its people, without their ids.
+pub fn behaviour_dataflow<G: Scope<Timestamp = usize>>(
+    posts: &Stream<G, Post>,
+    comments: &Stream<G, Comment>,
+    likes: &Stream<G, Like>,
+    config: &Config,
+    idle_after: Duration,
+) -> Stream<G, SuspicionReport> {
+    // the replies to comments are not on a known post, so they go straight to their author
+    let activities = posts
+        .buffer(Exchange::new(|post: &Post| hash(post.id)), idle_after)
+        .filter(|post| !post.is_watermark)
+        .map(Activity::Post)
+        .concat(
+            &comments
+                .buffer(
+                    Exchange::new(|comment: &Comment| {
+                        hash(comment.reply_to_post_id.unwrap_or(comment.person_id))
+                    }),
+                    idle_after,
                 )
-                .inspect_batch(|t, x| println!("@{}: suspicious person #{:?}", t, x));
+                .filter(|comment| !comment.is_watermark)
+                .map(|comment| Activity::Comment(comment, None)),
+        )
+        .concat(
+            &likes
+                .buffer(Exchange::new(|like: &Like| hash(like.post_id)), idle_after)
+                .filter(|like| !like.is_watermark)
+                .map(|like| Activity::Like(like, None)),
+        );
+
+    let mut stash = Stash::new();
+    let mut authors = Posts::new();
+    let notify_period = config.notify_period;
+    let periodic = Periodic::new(notify_period, 1);
+    let linked = activities.unary_notify(
+        Pipeline,
+        "Post Authors",
+        None,
+        move |input, output, notificator| {
+            input.for_each(|time, data| {
+                let mut vec = vec![];
+                data.swap(&mut vec);
+                notificator.notify_at(time.delayed(&periodic.boundary(*time.time())));
+                for activity in vec.drain(..) {
+                    stash.stash(*time.time(), activity);
+                }
+            });
+
+            notificator.for_each(|cap, _, notificator| {
+                periodic.next(&cap, notificator);
+
+                // the authors of the posts of the period are known before the comments and
+                // likes of the period are linked to them, whatever their order
+                for activity in stash.peek(notify_period, *cap.time()) {
+                    if let Activity::Post(post) = activity {
+                        authors.publish(post);
+                    }
+                }
+
+                let mut activities = stash.extract(notify_period, *cap.time());
+                // the activities arriving after their period are dropped
+                stash.evict(cap.time().saturating_sub(notify_period));
+                activities.sort_by_key(|activity| activity.timestamp());
+                let mut session = output.session(&cap);
+                for activity in activities {
+                    session.give(match activity {
+                        Activity::Post(post) => Activity::Post(post),
+                        Activity::Comment(comment, _) => {
+                            let post = comment
+                                .reply_to_post_id
+                                .and_then(|post_id| authors.get(post_id));
+                            Activity::Comment(comment, post)
+                        }
+                        Activity::Like(like, _) => {
+                            let post = authors.get(like.post_id);
+                            Activity::Like(like, post)
+                        }
+                    });
+                }
+                authors.expire(*cap.time());
+            });
+        },
+    );
+
+    let mut stash = Stash::new();
+    let mut behaviour = Behaviour::new();
+    let local = linked.unary_notify(
+        Exchange::new(|activity: &Activity| hash(activity.person_id())),
+        "Local Behaviour",
+        None,
+        move |input, output, notificator| {
+            input.for_each(|cap, data| {
+                let mut vec = vec![];
+                data.swap(&mut vec);
+                for activity in vec.drain(..) {
+                    stash.stash(*cap.time(), activity);
+                }
+                notificator.notify_at(cap.retain());
+            });
+
+            notificator.for_each(|cap, _, _| {
+                for activity in stash.remove(cap.time()).unwrap_or(vec![]) {
+                    match activity {
+                        Activity::Post(post) => behaviour.post(&post),
+                        Activity::Comment(comment, post) => behaviour.comment(&comment, post),
+                        Activity::Like(like, post) => behaviour.like(&like, post),
+                    }
+                }
+                output.session(&cap).give(behaviour.period());
+            });
+        },
+    );
+
+    // every worker computes the population baseline from the values of all the workers
+    let values = local
+        .map(|current| {
+            current
+                .into_iter()
+                .map(|(_, values)| values)
+                .collect::<Vec<Vec<f64>>>()
+        })
+        .broadcast();
+
+    let mut local_periods = Stash::new();
+    let mut global_values = Stash::new();
+    let mut baselines = Baselines::new();
+    local.binary_notify(
+        &values,
+        Pipeline,
+        Pipeline,
+        "Unusual Behaviour",
+        None,
+        move |local_input, global_input, output, notificator| {
+            local_input.for_each(|cap, input| {
+                let mut vec = vec![];
+                input.swap(&mut vec);
+                for current in vec.drain(..) {
+                    local_periods.stash(*cap.time(), current);
+                }
+                notificator.notify_at(cap.retain());
+            });
+            global_input.for_each(|cap, input| {
+                let mut vec = vec![];
+                input.swap(&mut vec);
+                for values in vec.drain(..) {
+                    global_values.stash(*cap.time(), values);
+                }
+                notificator.notify_at(cap.retain());
+            });
+
+            notificator.for_each(|cap, _, _| {
+                let current: Vec<(u32, Vec<f64>)> = local_periods
+                    .remove(cap.time())
+                    .unwrap_or(vec![])
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                let values: Vec<Vec<f64>> = global_values
+                    .remove(cap.time())
+                    .unwrap_or(vec![])
+                    .into_iter()
+                    .flatten()
+                    .collect();
+
+                let time = *cap.time();
+                let mut session = output.session(&cap);
+                for flag in baselines.flag(current, &population(&values)) {
+                    let names = BEHAVIOURS.iter().map(|(name, _)| name.to_string());
+                    session.give(SuspicionReport {
+                        person_id: flag.person_id,
+                        score: flag.score,
+                        post_ids: vec![],
+                        features: names.zip(flag.values).collect(),
+                        distance: None,
+                        first_flagged: time,
+                        last_flagged: time,
+                    });
+                }
+            });
+        },
+    )
+}
+
+pub fn run(config: Config, source: SourceConfig) {
+    timely::execute_from_args(std::env::args(), move |worker| {
+        let index = worker.index();
+        let mut probe = ProbeHandle::new();
+        worker.dataflow::<usize, _, _>(|scope| {
+            let posts =
+                scope.kafka_string_source::<Post>("posts".to_string(), index, &source, &probe);
+            let mut suspicious = content_dataflow(&posts, &config, source.idle_partition);
+            if config.behaviour {
+                let comments = scope.kafka_string_source::<Comment>(
+                    "comments".to_string(),
+                    index,
+                    &source,
+                    &probe,
+                );
+                let likes =
+                    scope.kafka_string_source::<Like>("likes".to_string(), index, &source, &probe);
+                suspicious = suspicious.concat(&behaviour_dataflow(
+                    &posts,
+                    &comments,
+                    &likes,
+                    &config,
+                    source.idle_partition,
+                ));
+            }
+            reports(&suspicious, &config)
+                .inspect_batch(|t, x| println!("@{}: suspicious person {:?}", t, x))
+                .probe_with(&mut probe);
         })
     })
     .unwrap();
 }
+
+#[cfg(test)]
+mod unusual_activity_tests {
+    extern crate timely;
+
+    use crate::dsa::anomaly::DetectorKind;
+    use crate::dsa::features::FeatureKind;
+    use crate::dto::comment::Comment;
+    use crate::dto::like::Like;
+    use crate::dto::post::Post;
+    use crate::operators::buffer::IDLE_AFTER;
+    use crate::tasks::harness::*;
+    use crate::tasks::unusual_activity::{
+        behaviour_dataflow, content_dataflow, reports, Config, Reporter, SuspicionReport,
+    };
+
+    use timely::dataflow::operators::{Concat, Input};
+    use timely::Configuration;
+
+    #[test]
+    fn test_unusual_activity_matches_golden() {
+        // the dataset is too small for the default configuration
+        let config = Config {
+            num_clusters: 2,
+            min_coverage: 2,
+            min_points: 10,
+            outlier_percentile: 0.5,
+            notify_period: 60 * 60,
+            plot: None,
+            features: vec![FeatureKind::Lengths],
+            seed: 0,
+            batch_size: None,
+            history: 4,
+            decay: 0.8,
+            detector: DetectorKind::Clusters,
+            behaviour: true,
+            cooldown: 0,
+        };
+
+        let outputs = execute(Configuration::Thread, move |worker, outputs, probe| {
+            let mut posts = replay::<Post>("post_event_stream.csv");
+            let mut comments = replay::<Comment>("comment_event_stream.csv");
+            let mut likes = replay::<Like>("likes_event_stream.csv");
+
+            worker.dataflow(|scope| {
+                let posts = scope.input_from(posts.input());
+                let comments = scope.input_from(comments.input());
+                let likes = scope.input_from(likes.input());
+
+                // the same reports as the task, on the content and on the behaviour
+                let suspicious = content_dataflow(&posts, &config, IDLE_AFTER).concat(
+                    &behaviour_dataflow(&posts, &comments, &likes, &config, IDLE_AFTER),
+                );
+                collect(&reports(&suspicious, &config), "suspicious", outputs, probe);
+            });
+
+            let watermarks = watermarks(posts.start_time());
+            ((posts, comments, likes), watermarks)
+        });
+
+        assert_golden("unusual_activity", outputs);
+    }
+
+    fn report(person_id: u32, post_id: u32, score: f64, time: usize) -> SuspicionReport {
+        SuspicionReport {
+            person_id,
+            score,
+            post_ids: vec![post_id],
+            features: vec![("length".to_string(), score)],
+            distance: Some(score),
+            first_flagged: time,
+            last_flagged: time,
+        }
+    }
+
+    #[test]
+    fn test_reports_of_a_period_are_merged() {
+        let mut reporter = Reporter::new(10);
+        let reports = reporter.report(
+            0,
+            vec![
+                report(2, 5, 1., 0),
+                report(1, 3, 1., 0),
+                report(2, 4, 3., 0),
+            ],
+        );
+
+        // only the features of the most unusual post are kept
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0], report(1, 3, 1., 0));
+        assert_eq!(reports[1].post_ids, vec![4, 5]);
+        assert_eq!(reports[1].features, vec![("length".to_string(), 3.)]);
+        assert_eq!((reports[1].score, reports[1].distance), (3., Some(3.)));
+    }
+
+    #[test]
+    fn test_people_are_not_reported_again_within_the_cooldown() {
+        let mut reporter = Reporter::new(10);
+        assert_eq!(reporter.report(0, vec![report(1, 1, 1., 0)]).len(), 1);
+        assert_eq!(reporter.report(5, vec![report(1, 2, 1., 5)]), vec![]);
+
+        // the streak goes on, so the next report covers the whole of it
+        let reports = reporter.report(10, vec![report(1, 3, 1., 10)]);
+        assert_eq!((reports[0].first_flagged, reports[0].last_flagged), (0, 10));
+    }
+
+    #[test]
+    fn test_streaks_end_after_the_cooldown() {
+        let mut reporter = Reporter::new(10);
+        reporter.report(0, vec![report(1, 1, 1., 0)]);
+        reporter.report(5, vec![report(1, 2, 1., 5)]);
+
+        let reports = reporter.report(20, vec![report(1, 3, 1., 20)]);
+        assert_eq!(
+            (reports[0].first_flagged, reports[0].last_flagged),
+            (20, 20)
+        );
+        assert!(reporter.report(40, vec![]).is_empty());
+        assert!(reporter.flagged.is_empty());
+    }
+}
@@ -0,0 +1,414 @@
+extern crate timely;
+
+use crate::connection::replay::Replay;
+use crate::dto::comment::Comment;
+use crate::dto::like::Like;
+use crate::dto::post::Post;
+use crate::operators::buffer::IDLE_AFTER;
+use crate::operators::link_replies::ThreadConfig;
+use crate::tasks::batch::{Dataset, Oracle, OUTPUTS};
+use crate::tasks::harness::{collect, execute};
+use crate::tasks::load::EventStream;
+use crate::tasks::unusual_activity::Config;
+use crate::tasks::who_to_follow::Tables;
+use crate::tasks::{post_stats, unusual_activity, who_to_follow};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use timely::dataflow::operators::{Input, Map};
+use timely::Configuration;
+
+// the streamed suspicious people only approximate the exact ones, so they are compared by
+// the fraction of people reported by both
+pub const MIN_AGREEMENT: f64 = 0.5;
+
+// The outputs printed by the tasks, as (prefix, separator before the outputs, name): the
+// time is between the prefix and the separator, followed by the list of outputs.
+const PRINTED: [(&str, &str, &str); 4] = [
+    ("#uniquely engaged people @t=", ": ", "engaged"),
+    ("#comments and replies @t=", ": ", "counts"),
+    ("Recommendations @", ": ", "recommendations"),
+    ("@", ": suspicious person ", "suspicious"),
+];
+
+// A difference between the streaming and the batch outputs at some time.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub time: usize,
+    pub name: String,
+    // batch outputs that were not streamed
+    pub missing: Vec<String>,
+    // streamed outputs that are not batch outputs
+    pub unexpected: Vec<String>,
+}
+
+// Replays the streams in creation order through the dataflows of all the tasks for
+// `duration` seconds of event time, then closes them. Every worker replays its own
+// partition of the streams. The outputs are formatted as "<name> <output>".
+pub fn stream_outputs(
+    streams_path: &PathBuf,
+    tables_path: &PathBuf,
+    bound: usize,
+    duration: usize,
+    config: &Config,
+) -> Vec<(usize, String)> {
+    let streams_path = streams_path.clone();
+    let tables_path = tables_path.clone();
+    let config = config.clone();
+
+    execute(Configuration::Process(4), move |worker, outputs, probe| {
+        let (index, peers) = (worker.index(), worker.peers());
+        let mut posts =
+            Replay::<Post>::new(&streams_path.join(EventStream::Posts.file_name()), bound)
+                .partition(index, peers);
+        let mut comments =
+            Replay::<Comment>::new(&streams_path.join(EventStream::Comments.file_name()), bound)
+                .partition(index, peers);
+        let mut likes =
+            Replay::<Like>::new(&streams_path.join(EventStream::Likes.file_name()), bound)
+                .partition(index, peers);
+
+        worker.dataflow(|scope| {
+            let posts = scope.input_from(posts.input());
+            let comments = scope.input_from(comments.input());
+            let likes = scope.input_from(likes.input());
+
+            let (engaged_users, counts, _) = post_stats::dataflow(
+                &posts,
+                &comments,
+                &likes,
+                bound,
+                IDLE_AFTER,
+                ThreadConfig::default(),
+            );
+            collect(&counts, "counts", outputs, probe);
+            collect(&engaged_users, "engaged", outputs, probe);
+
+            let recommendations = who_to_follow::dataflow(
+                &posts,
+                &comments,
+                &likes,
+                &tables_path,
+                bound,
+                IDLE_AFTER,
+                ThreadConfig::default(),
+            );
+            collect(&recommendations, "recommendations", outputs, probe);
+
+            // the batch outputs only name the suspicious people
+            let suspicious = unusual_activity::dataflow(&posts, &config, IDLE_AFTER)
+                .map(|report| report.person_id);
+            collect(&suspicious, "suspicious", outputs, probe);
+        });
+
+        let start = posts.start_time();
+        let watermarks = (0..=duration / bound).map(|i| start + i * bound).collect();
+        ((posts, comments, likes), watermarks)
+    })
+}
+
+// The elements of a printed list, split at its top level commas.
+fn split_list(list: &str) -> Vec<String> {
+    let list = list.trim();
+    let list = match list.starts_with('[') && list.ends_with(']') {
+        true => &list[1..list.len() - 1],
+        false => list,
+    };
+
+    let mut elements = vec![];
+    let mut element = String::new();
+    let (mut depth, mut quoted, mut escaped) = (0, false, false);
+    for c in list.chars() {
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => {}
+            }
+        } else {
+            match c {
+                '"' => quoted = true,
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth -= 1,
+                ',' if depth == 0 => {
+                    elements.push(element.trim().to_string());
+                    element.clear();
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        element.push(c);
+    }
+    elements.push(element.trim().to_string());
+    elements.retain(|element| !element.is_empty());
+    elements
+}
+
+// The outputs in the text printed by the tasks, formatted like the batch outputs. The
+// suspicious people are only named, like in the batch outputs.
+pub fn parse_printed(text: &str) -> Vec<(usize, String)> {
+    let mut outputs = vec![];
+    for line in text.lines() {
+        for (prefix, separator, name) in PRINTED.iter() {
+            if !line.starts_with(prefix) {
+                continue;
+            }
+            let rest = &line[prefix.len()..];
+            let split = match rest.find(separator) {
+                Some(split) => split,
+                None => continue,
+            };
+            let time = match rest[..split].parse() {
+                Ok(time) => time,
+                Err(_) => continue,
+            };
+            for element in split_list(&rest[split + separator.len()..]) {
+                let output = match *name {
+                    "suspicious" => element
+                        .split("person_id: ")
+                        .nth(1)
+                        .and_then(|rest| rest.split(',').next())
+                        .unwrap_or(&element)
+                        .to_string(),
+                    _ => element,
+                };
+                outputs.push((time, format!("{} {}", name, output)));
+            }
+            break;
+        }
+    }
+    outputs
+}
+
+// The outputs printed by the tasks into a file, or into the files of a directory.
+pub fn read_printed(path: &Path) -> Vec<(usize, String)> {
+    let mut files = vec![path.to_path_buf()];
+    if path.is_dir() {
+        files = fs::read_dir(path)
+            .expect("Unable to read the outputs directory")
+            .map(|entry| entry.unwrap().path())
+            .filter(|file| file.is_file())
+            .collect();
+        files.sort();
+    }
+    files
+        .iter()
+        .flat_map(|file| {
+            let text = fs::read_to_string(file).expect("Unable to read the outputs");
+            parse_printed(&text)
+        })
+        .collect()
+}
+
+// Groups the outputs by name and time, leaving out the ones after `end`.
+fn by_name(
+    outputs: &[(usize, String)],
+    end: usize,
+) -> BTreeMap<String, BTreeMap<usize, Vec<String>>> {
+    // map: name -> time -> outputs
+    let mut by_name: BTreeMap<String, BTreeMap<usize, Vec<String>>> = BTreeMap::new();
+    for (time, output) in outputs.iter().filter(|(time, _)| *time <= end) {
+        let name = output.split_whitespace().next().unwrap().to_string();
+        by_name
+            .entry(name)
+            .or_insert(BTreeMap::new())
+            .entry(*time)
+            .or_insert(vec![])
+            .push(output.clone());
+    }
+    by_name
+}
+
+// Compares the streamed outputs with the batch outputs of every task at each of their
+// period boundaries up to `end`. The streams are flushed at the end, so the later outputs
+// are left out.
+pub fn compare(streamed: &[(usize, String)], oracle: &Oracle, end: usize) -> Vec<Mismatch> {
+    let mut streamed_by_name = by_name(streamed, end);
+    let mut batch_by_name = by_name(&oracle.all_answers(end), end);
+
+    let names: BTreeSet<String> = OUTPUTS
+        .iter()
+        .map(|name| name.to_string())
+        .chain(streamed_by_name.keys().cloned())
+        .collect();
+
+    let mut mismatches = vec![];
+    for name in names {
+        let mut streamed_at_time = streamed_by_name.remove(&name).unwrap_or(BTreeMap::new());
+        let mut batch_at_time = batch_by_name.remove(&name).unwrap_or(BTreeMap::new());
+        let times: BTreeSet<usize> = streamed_at_time
+            .keys()
+            .chain(batch_at_time.keys())
+            .cloned()
+            .collect();
+
+        for time in times {
+            let mut outputs = streamed_at_time.remove(&time).unwrap_or(vec![]);
+            let mut answers = batch_at_time.remove(&time).unwrap_or(vec![]);
+            outputs.sort();
+            answers.sort();
+            if outputs == answers {
+                continue;
+            }
+
+            let missing = answers
+                .iter()
+                .filter(|answer| !outputs.contains(answer))
+                .cloned()
+                .collect();
+            let unexpected = outputs
+                .iter()
+                .filter(|output| !answers.contains(output))
+                .cloned()
+                .collect();
+            mismatches.push(Mismatch {
+                time,
+                name: name.clone(),
+                missing,
+                unexpected,
+            });
+        }
+    }
+    mismatches
+}
+
+// The fraction of the outputs `name`, streamed or batch, that are both streamed and batch
+// outputs at the same time.
+pub fn agreement(mismatches: &[Mismatch], streamed: &[(usize, String)], name: &str) -> f64 {
+    let streamed = streamed
+        .iter()
+        .filter(|(_, output)| output.split_whitespace().next() == Some(name))
+        .count();
+    let (missing, unexpected) = mismatches
+        .iter()
+        .filter(|mismatch| mismatch.name == name)
+        .fold((0, 0), |(missing, unexpected), mismatch| {
+            (
+                missing + mismatch.missing.len(),
+                unexpected + mismatch.unexpected.len(),
+            )
+        });
+    let both = streamed - unexpected;
+    match both + missing + unexpected {
+        0 => 1.,
+        all => both as f64 / all as f64,
+    }
+}
+
+// Reports where the outputs of the tasks differ from the exact answers. The outputs are the
+// ones printed by the tasks run on Kafka, in `printed`, or else the ones of the complete
+// streams replayed through the tasks.
+pub fn run(streams_path: &PathBuf, tables_path: &PathBuf, bound: usize, printed: Option<PathBuf>) {
+    // the batch outputs only cover the content of the posts
+    let config = Config {
+        behaviour: false,
+        ..Config::default()
+    };
+    let dataset = Dataset::load(streams_path);
+    // replay until every delayed event is released
+    let duration = dataset.end_time().saturating_sub(dataset.start_time()) + bound;
+    let end = dataset.end_time();
+    let oracle = Oracle::new(dataset, Tables::load(tables_path), config.clone());
+
+    let streamed = match printed {
+        Some(path) => read_printed(&path),
+        None => stream_outputs(streams_path, tables_path, bound, duration, &config),
+    };
+    let mismatches = compare(&streamed, &oracle, end);
+    for mismatch in mismatches
+        .iter()
+        .filter(|mismatch| mismatch.name != "suspicious")
+    {
+        println!(
+            "Mismatch of {} @t={}: missing {:?}, unexpected {:?}",
+            mismatch.name, mismatch.time, mismatch.missing, mismatch.unexpected
+        );
+    }
+    println!(
+        "Verified {} streamed outputs: {} mismatches",
+        streamed.len(),
+        mismatches
+            .iter()
+            .filter(|mismatch| mismatch.name != "suspicious")
+            .count()
+    );
+    println!(
+        "Agreement of the suspicious people: {:.2}",
+        agreement(&mismatches, &streamed, "suspicious")
+    );
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use crate::dsa::anomaly::DetectorKind;
+    use crate::dsa::features::FeatureKind;
+    use crate::tasks::batch::{Dataset, Oracle};
+    use crate::tasks::harness::*;
+    use crate::tasks::unusual_activity::Config;
+    use crate::tasks::verify::{agreement, compare, parse_printed, stream_outputs, MIN_AGREEMENT};
+    use crate::tasks::who_to_follow::Tables;
+
+    #[test]
+    fn test_streamed_outputs_match_batch_outputs() {
+        // the same configuration as the end-to-end test of the task
+        let config = Config {
+            num_clusters: 2,
+            min_coverage: 2,
+            min_points: 10,
+            outlier_percentile: 0.5,
+            notify_period: 60 * 60,
+            plot: None,
+            features: vec![FeatureKind::Lengths],
+            seed: 0,
+            batch_size: None,
+            history: 4,
+            decay: 0.8,
+            detector: DetectorKind::Clusters,
+            behaviour: false,
+            cooldown: 0,
+        };
+        let streams_path = data_path().join("streams");
+        let tables_path = data_path().join("tables");
+
+        let duration = 2 * 60 * 60;
+        let streamed = stream_outputs(&streams_path, &tables_path, DELAY, duration, &config);
+        let dataset = Dataset::load(&streams_path);
+        let end = dataset.start_time() + duration;
+        let oracle = Oracle::new(dataset, Tables::load(&tables_path), config);
+
+        assert!(!streamed.is_empty());
+        let mismatches = compare(&streamed, &oracle, end);
+        let exact: Vec<_> = mismatches
+            .iter()
+            .filter(|mismatch| mismatch.name != "suspicious")
+            .collect();
+        assert!(exact.is_empty(), "{:?}", exact);
+        assert!(agreement(&mismatches, &streamed, "suspicious") >= MIN_AGREEMENT);
+    }
+
+    #[test]
+    fn test_printed_outputs_are_parsed_like_batch_outputs() {
+        let printed = "\
+#uniquely engaged people @t=600: [(1, 2), (3, 4)]
+#comments and replies @t=600: [(1, 5, 0)]
+Recommendations @1800: [(7, [8, 9]), (10, [])]
+@3600: suspicious person [SuspicionReport { person_id: 5, features: [(\"a, b\", 1.0)] }]
+threads @t=1800: [Thread { depth: 1 }]
+Source posts/0 ended (end of the stream) @t=3600: 10 records in 1s";
+
+        assert_eq!(
+            parse_printed(printed),
+            vec![
+                (600, "engaged (1, 2)".to_string()),
+                (600, "engaged (3, 4)".to_string()),
+                (600, "counts (1, 5, 0)".to_string()),
+                (1800, "recommendations (7, [8, 9])".to_string()),
+                (1800, "recommendations (10, [])".to_string()),
+                (3600, "suspicious 5".to_string()),
+            ]
+        );
+    }
+}
@@ -1,11 +1,11 @@
 extern crate timely;
 
-use crate::connection::producer::FIXED_BOUNDED_DELAY;
 use crate::operators::buffer::Buffer;
-use crate::operators::source::KafkaSource;
+use crate::operators::source::{KafkaSource, SourceConfig};
 
 use crate::operators::active_posts::ActivePosts;
-use crate::operators::link_replies::LinkReplies;
+use crate::operators::link_replies::{LinkReplies, ThreadConfig};
+use crate::operators::route_replies::RouteReplies;
 
 use crate::dto::comment::Comment;
 use crate::dto::like::Like;
@@ -17,9 +17,9 @@ use crate::dto::parse::*;
 
 use timely::dataflow::channels::pact::Exchange;
 use timely::dataflow::channels::pact::Pipeline;
-use timely::dataflow::operators::broadcast::Broadcast;
 use timely::dataflow::operators::generic::operator::Operator;
-use timely::dataflow::operators::Inspect;
+use timely::dataflow::operators::{Inspect, Probe};
+use timely::dataflow::{ProbeHandle, Scope, Stream};
 
 use crate::dto::person::Person;
 use std::collections::hash_map::DefaultHasher;
@@ -28,239 +28,347 @@ use std::collections::HashSet;
 use std::hash::Hasher;
 use std::iter::FromIterator;
 use std::path::PathBuf;
+use std::time::Duration;
 use timely::Configuration;
 
-const COLLECTION_PERIOD: usize = 60 * 60; // seconds
-const ACTIVE_POST_PERIOD: usize = 4 * 60 * 60; // seconds
+pub const COLLECTION_PERIOD: usize = 60 * 60; // seconds
+pub const ACTIVE_POST_PERIOD: usize = 4 * 60 * 60; // seconds
+
 const RECOMMENDATIONS: usize = 5;
 
-pub fn run(tables_path: PathBuf) {
-    timely::execute(Configuration::Process(4), move |worker| {
-        let index = worker.index();
-        worker.dataflow::<usize, _, _>(|scope| {
-            let posts = scope.kafka_string_source::<Post>("posts".to_string(), index);
-            let comments = scope.kafka_string_source::<Comment>("comments".to_string(), index);
-            let likes = scope.kafka_string_source::<Like>("likes".to_string(), index);
+pub const PEOPLE_OF_INTEREST: [u32; 10] = [129, 986, 618, 296, 814, 379, 441, 655, 836, 929];
 
-            let buffered_likes = likes.buffer(Exchange::new(|l: &Like| {
-                if l.is_watermark {
-                    return l.post_id as u64;
-                }
-                let mut hasher = DefaultHasher::new();
-                hasher.write_u32(l.post_id);
-                hasher.finish()
-            }));
-            let buffered_posts = posts.buffer(Exchange::new(|p: &Post| {
-                if p.is_watermark {
-                    return p.id as u64;
+// The forums and friendships the recommendations are based on.
+pub struct Tables {
+    forums: HashMap<u32, Forum>,
+    persons: HashMap<u32, Person>,
+    // map: person -> forums the person is a member of
+    person_forums: HashMap<u32, HashSet<u32>>,
+}
+
+impl Tables {
+    pub fn load(tables_path: &PathBuf) -> Tables {
+        // getting the forum map
+        let forum_path = tables_path.join("forum.csv");
+        let mut forums = csv_to_map::<Forum>(forum_path.to_str().unwrap());
+
+        let forum_members_path = tables_path.join("forum_hasMember_person.csv");
+        parse_forum_member_csv(forum_members_path.to_str().unwrap(), &mut forums);
+
+        let person_path = tables_path.join("person.csv");
+        let mut persons = csv_to_map::<Person>(person_path.to_str().unwrap());
+
+        let person_friend_path = tables_path.join("person_knows_person.csv");
+        parse_person_friends(person_friend_path.to_str().unwrap(), &mut persons);
+
+        let mut person_forums: HashMap<u32, HashSet<u32>> = HashMap::new();
+        for (forum_id, forum) in &forums {
+            for member in &forum.member_ids {
+                person_forums
+                    .entry(*member)
+                    .or_insert(HashSet::new())
+                    .insert(*forum_id);
+            }
+        }
+
+        Tables {
+            forums,
+            persons,
+            person_forums,
+        }
+    }
+
+    // Recommends the members of the given forums that share the most forums and friends
+    // with the person, leaving out the people they already know.
+    pub fn recommend(&self, person_id: u32, forum_set: &HashSet<u32>) -> Vec<u32> {
+        let mut candidate_friends = HashSet::new();
+        for forum_id in forum_set {
+            if let Some(forum) = self.forums.get(forum_id) {
+                for forum_member in forum.member_ids.clone() {
+                    candidate_friends.insert(forum_member);
                 }
-                let mut hasher = DefaultHasher::new();
-                hasher.write_u32(p.id);
-                hasher.finish()
-            }));
-
-            let linked_comments = comments.broadcast().buffer(Pipeline).link_replies(
-                &buffered_posts,
-                Pipeline,
-                Pipeline,
-                FIXED_BOUNDED_DELAY,
+            }
+        }
+
+        // Filter out friends
+        for friend in self.persons.get(&person_id).unwrap().friends.clone() {
+            candidate_friends.remove(&friend);
+        }
+        candidate_friends.remove(&person_id);
+
+        // Find the forums your friends like
+        let mut candidate_friends_forums = HashMap::new();
+        for candidate in &candidate_friends {
+            candidate_friends_forums.insert(
+                candidate,
+                self.person_forums
+                    .get(&candidate)
+                    .unwrap()
+                    .intersection(self.person_forums.get(&person_id).unwrap())
+                    .collect::<Vec<_>>()
+                    .len(),
             );
-
-            let active_posts = linked_comments.active_post_ids(
-                &buffered_likes,
-                Pipeline,
-                Pipeline,
-                FIXED_BOUNDED_DELAY,
-                ACTIVE_POST_PERIOD,
+        }
+
+        // Find the number of common friends
+        let mut candidate_friends_mutual_friends = HashMap::new();
+        for candidate in &candidate_friends {
+            let mut friends1 = self.persons.get(&person_id).unwrap().friends.clone();
+            let mut friends2 = match self.persons.get(&candidate) {
+                Some(candidate) => candidate.friends.clone(),
+                None => Vec::new(),
+            };
+
+            let friends1_set: HashSet<u32> = HashSet::from_iter(friends1.drain(..));
+            let friends2_set: HashSet<u32> = HashSet::from_iter(friends2.drain(..));
+
+            candidate_friends_mutual_friends.insert(
+                candidate,
+                friends1_set
+                    .intersection(&friends2_set)
+                    .collect::<Vec<_>>()
+                    .len(),
             );
+        }
+
+        // find top friends
+        let mut candidate_metrics = HashMap::new();
+        for candidate in &candidate_friends {
+            // compute metric
+            let metric = candidate_friends_forums.get(&candidate).unwrap()
+                + candidate_friends_mutual_friends.get(&candidate).unwrap();
+
+            candidate_metrics.insert(candidate, metric);
+        }
+
+        let mut recommendations = Vec::new();
+        for _ in 0..RECOMMENDATIONS {
+            let mut best_candidate: Option<&u32> = None;
+            let mut best_metric = 0;
+            for (candidate, metric) in &candidate_metrics {
+                // ties go to the lowest id, so that the recommendations are deterministic
+                if *metric > best_metric
+                    || (*metric == best_metric && best_candidate.map_or(false, |b| *candidate < b))
+                {
+                    best_metric = *metric;
+                    best_candidate = Some(*candidate);
+                }
+            }
 
-            let people_of_interest: Vec<u32> =
-                vec![129, 986, 618, 296, 814, 379, 441, 655, 836, 929];
+            if let Some(candidate) = best_candidate {
+                candidate_metrics.remove(&candidate);
+                recommendations.push(*candidate);
+            }
+        }
 
-            // getting the forum map
-            let forum_path = tables_path.join("forum.csv");
-            let mut forum_map = csv_to_map::<Forum>(forum_path.to_str().unwrap());
+        recommendations
+    }
+}
 
-            let forum_members_path = tables_path.join("forum_hasMember_person.csv");
-            parse_forum_member_csv(forum_members_path.to_str().unwrap(), &mut forum_map);
+// Builds the dataflow recommending friends to the people of interest based on the
+// forums of the active posts they are engaged with.
+pub fn dataflow<G: Scope<Timestamp = usize>>(
+    posts: &Stream<G, Post>,
+    comments: &Stream<G, Comment>,
+    likes: &Stream<G, Like>,
+    tables_path: &PathBuf,
+    delay: usize,
+    idle_after: Duration,
+    thread_config: ThreadConfig,
+) -> Stream<G, (u32, Vec<u32>)> {
+    let buffered_likes = likes.buffer(
+        Exchange::new(|l: &Like| {
+            if l.is_watermark {
+                return l.post_id as u64;
+            }
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u32(l.post_id);
+            hasher.finish()
+        }),
+        idle_after,
+    );
+    let buffered_posts = posts.buffer(
+        Exchange::new(|p: &Post| {
+            if p.is_watermark {
+                return p.id as u64;
+            }
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u32(p.id);
+            hasher.finish()
+        }),
+        idle_after,
+    );
+
+    let linked_comments = comments
+        .route_replies(thread_config.horizon, thread_config.orphan_timeout)
+        .buffer(Pipeline, idle_after)
+        .link_replies(
+            &buffered_posts,
+            Pipeline,
+            Pipeline,
+            delay,
+            thread_config.horizon,
+            thread_config.orphan_timeout,
+        );
+
+    let active_posts = linked_comments.active_post_ids(
+        &buffered_likes,
+        Pipeline,
+        Pipeline,
+        delay,
+        ACTIVE_POST_PERIOD,
+    );
+
+    let tables = Tables::load(tables_path);
+
+    let mut first_notified = false;
+    let mut post_info = HashMap::new(); // map: post_id -> (forum, tags)
+    let mut active_posts_at_time: HashMap<usize, Vec<(u32, HashSet<u32>)>> = HashMap::new();
+    active_posts.binary_notify(
+        &buffered_posts,
+        Pipeline,
+        Pipeline,
+        "WhoToFollow",
+        None,
+        move |ap_input, bp_input, output, notificator| {
+            // keep all bp information from beginning of time
+            let mut bp_data = Vec::new();
+            bp_input.for_each(|cap, input| {
+                input.swap(&mut bp_data);
+                for post in bp_data.drain(..) {
+                    post_info
+                        .entry(post.id)
+                        .or_insert((post.forum_id, post.tags));
+                }
+                if !first_notified {
+                    notificator.notify_at(cap.delayed(&(cap.time() + COLLECTION_PERIOD)));
+                    first_notified = true;
+                }
+            });
+
+            // keep the latest snapshot that we received
+            ap_input.for_each(|cap, input| {
+                let mut vec = vec![];
+                input.swap(&mut vec);
+                active_posts_at_time.insert(*cap.time(), vec);
+            });
+
+            // do the actual computation at each notification
+            notificator.for_each(|cap, _, notificator| {
+                // stop once the inputs are exhausted, so that the dataflow can complete
+                if !notificator.frontier(0).is_empty() || !notificator.frontier(1).is_empty() {
+                    notificator.notify_at(cap.delayed(&(cap.time() + COLLECTION_PERIOD)));
+                }
 
-            let person_path = tables_path.join("person.csv");
-            let mut person_map = csv_to_map::<Person>(person_path.to_str().unwrap());
+                // the posts that our users are engaged with
+                let mut posts_of_interest = HashMap::new(); // map: user -> set[posts]
+
+                // you have the guarantee that we will have a new snapshot before the
+                // next notification, so we can drain it
+                for (post_id, engaged_people) in active_posts_at_time
+                    .remove(cap.time())
+                    .unwrap_or(vec![])
+                    .drain(..)
+                {
+                    for person_id in engaged_people {
+                        if PEOPLE_OF_INTEREST.contains(&person_id) {
+                            posts_of_interest
+                                .entry(person_id)
+                                .or_insert(HashSet::new())
+                                .insert(post_id);
+                        }
+                    }
+                }
+
+                for (interest_person_id, post_set) in posts_of_interest {
+                    // find all the unique forums this person is interested in
+                    let mut forum_set = HashSet::new();
+                    for post_id in post_set {
+                        let (forum, _) = post_info.get(&post_id).unwrap();
+                        forum_set.insert(*forum);
+                    }
 
-            let person_friend_path = tables_path.join("person_knows_person.csv");
-            parse_person_friends(person_friend_path.to_str().unwrap(), &mut person_map);
+                    let recommendations = tables.recommend(interest_person_id, &forum_set);
 
-            let mut person_forums: HashMap<u32, HashSet<u32>> = HashMap::new();
-            for (forum_id, forum) in &forum_map {
-                for member in &forum.member_ids {
-                    person_forums
-                        .entry(*member)
-                        .or_insert(HashSet::new())
-                        .insert(*forum_id);
+                    let mut session = output.session(&cap);
+                    session.give((interest_person_id, recommendations));
                 }
-            }
+            })
+        },
+    )
+}
 
-            let mut first_notified = false;
-            let mut post_info = HashMap::new(); // map: post_id -> (forum, tags)
-            let mut active_posts_at_time: HashMap<usize, Vec<(u32, HashSet<u32>)>> = HashMap::new();
-            active_posts
-                .binary_notify(
-                    &buffered_posts,
-                    Pipeline,
-                    Pipeline,
-                    "WhoToFollow",
-                    None,
-                    move |ap_input, bp_input, output, notificator| {
-                        // keep all bp information from beginning of time
-                        let mut bp_data = Vec::new();
-                        bp_input.for_each(|cap, input| {
-                            input.swap(&mut bp_data);
-                            for post in bp_data.drain(..) {
-                                post_info
-                                    .entry(post.id)
-                                    .or_insert((post.forum_id, post.tags));
-                            }
-                            if !first_notified {
-                                notificator
-                                    .notify_at(cap.delayed(&(cap.time() + COLLECTION_PERIOD)));
-                                first_notified = true;
-                            }
-                        });
-
-                        // keep the latest snapshot that we received
-                        ap_input.for_each(|cap, input| {
-                            let mut vec = vec![];
-                            input.swap(&mut vec);
-                            active_posts_at_time.insert(*cap.time(), vec);
-                        });
-
-                        // do the actual computation at each notification
-                        notificator.for_each(|cap, _, notificator| {
-                            notificator.notify_at(cap.delayed(&(cap.time() + COLLECTION_PERIOD)));
-
-                            // the posts that our users are engaged with
-                            let mut posts_of_interest = HashMap::new(); // map: user -> set[posts]
-
-                            // you have the guarantee that we will have a new snapshot before the
-                            // next notification, so we can drain it
-                            for (post_id, engaged_people) in active_posts_at_time
-                                .remove(cap.time())
-                                .unwrap_or(vec![])
-                                .drain(..)
-                            {
-                                for person_id in engaged_people {
-                                    if people_of_interest.contains(&person_id) {
-                                        posts_of_interest
-                                            .entry(person_id)
-                                            .or_insert(HashSet::new())
-                                            .insert(post_id);
-                                    }
-                                }
-                            }
-
-                            for (interest_person_id, post_set) in posts_of_interest {
-                                // find all the unique forums this person is interested in
-                                let mut forum_set = HashSet::new();
-                                for post_id in post_set {
-                                    let (forum, _) = post_info.get(&post_id).unwrap();
-                                    forum_set.insert(forum);
-                                }
-
-                                let mut candidate_friends = HashSet::new();
-                                for forum_id in forum_set {
-                                    if let Some(forum) = forum_map.get(forum_id) {
-                                        for forum_member in forum.member_ids.clone() {
-                                            candidate_friends.insert(forum_member);
-                                        }
-                                    }
-                                }
-
-                                // Filter out friends
-                                for friend in
-                                    person_map.get(&interest_person_id).unwrap().friends.clone()
-                                {
-                                    candidate_friends.remove(&friend);
-                                }
-                                candidate_friends.remove(&interest_person_id);
-
-                                // Find the forums your friends like
-                                let mut candidate_friends_forums = HashMap::new();
-                                for candidate in &candidate_friends {
-                                    candidate_friends_forums.insert(
-                                        candidate,
-                                        person_forums
-                                            .get(&candidate)
-                                            .unwrap()
-                                            .intersection(
-                                                person_forums.get(&interest_person_id).unwrap(),
-                                            )
-                                            .collect::<Vec<_>>()
-                                            .len(),
-                                    );
-                                }
-
-                                // Find the number of common friends
-                                let mut candidate_friends_mutual_friends = HashMap::new();
-                                for candidate in &candidate_friends {
-                                    let mut friends1 = person_map
-                                        .get(&interest_person_id)
-                                        .unwrap()
-                                        .friends
-                                        .clone();
-                                    let mut friends2 = match person_map.get(&candidate) {
-                                        Some(candidate) => candidate.friends.clone(),
-                                        None => Vec::new(),
-                                    };
-
-                                    let friends1_set: HashSet<u32> =
-                                        HashSet::from_iter(friends1.drain(..));
-                                    let friends2_set: HashSet<u32> =
-                                        HashSet::from_iter(friends2.drain(..));
-
-                                    candidate_friends_mutual_friends.insert(
-                                        candidate,
-                                        friends1_set
-                                            .intersection(&friends2_set)
-                                            .collect::<Vec<_>>()
-                                            .len(),
-                                    );
-                                }
-
-                                // find top friends
-                                let mut candidate_metrics = HashMap::new();
-                                for candidate in &candidate_friends {
-                                    // compute metric
-                                    let metric = candidate_friends_forums.get(&candidate).unwrap()
-                                        + candidate_friends_mutual_friends.get(&candidate).unwrap();
-
-                                    candidate_metrics.insert(candidate, metric);
-                                }
-
-                                let mut recommendations = Vec::new();
-                                for _ in 0..RECOMMENDATIONS {
-                                    let mut best_candidate = None;
-                                    let mut best_metric = 0;
-                                    for (candidate, metric) in &candidate_metrics {
-                                        if *metric > best_metric {
-                                            best_metric = *metric;
-                                            best_candidate = Some(*candidate);
-                                        }
-                                    }
-
-                                    if let Some(candidate) = best_candidate {
-                                        candidate_metrics.remove(&candidate);
-                                        recommendations.push(*candidate);
-                                    }
-                                }
-
-                                let mut session = output.session(&cap);
-                                session.give((interest_person_id, recommendations));
-                            }
-                        })
-                    },
-                )
-                .inspect_batch(|t, xs| println!("Recommendations @{:?}: {:?}", t, xs));
+pub fn run(tables_path: PathBuf, delay: usize, thread_config: ThreadConfig, source: SourceConfig) {
+    timely::execute(Configuration::Process(4), move |worker| {
+        let index = worker.index();
+        let mut probe = ProbeHandle::new();
+        worker.dataflow::<usize, _, _>(|scope| {
+            let posts =
+                scope.kafka_string_source::<Post>("posts".to_string(), index, &source, &probe);
+            let comments = scope.kafka_string_source::<Comment>(
+                "comments".to_string(),
+                index,
+                &source,
+                &probe,
+            );
+            let likes =
+                scope.kafka_string_source::<Like>("likes".to_string(), index, &source, &probe);
+
+            dataflow(
+                &posts,
+                &comments,
+                &likes,
+                &tables_path,
+                delay,
+                source.idle_partition,
+                thread_config,
+            )
+            .inspect_batch(|t, xs| println!("Recommendations @{:?}: {:?}", t, xs))
+            .probe_with(&mut probe);
         });
     })
     .unwrap();
 }
+
+#[cfg(test)]
+mod who_to_follow_tests {
+    extern crate timely;
+
+    use crate::dto::comment::Comment;
+    use crate::dto::like::Like;
+    use crate::dto::post::Post;
+    use crate::operators::buffer::IDLE_AFTER;
+    use crate::operators::link_replies::ThreadConfig;
+    use crate::tasks::harness::*;
+    use crate::tasks::who_to_follow::dataflow;
+
+    use timely::dataflow::operators::Input;
+    use timely::Configuration;
+
+    #[test]
+    fn test_who_to_follow_matches_golden() {
+        let outputs = execute(Configuration::Thread, |worker, outputs, probe| {
+            let mut posts = replay::<Post>("post_event_stream.csv");
+            let mut comments = replay::<Comment>("comment_event_stream.csv");
+            let mut likes = replay::<Like>("likes_event_stream.csv");
+
+            worker.dataflow(|scope| {
+                let recommendations = dataflow(
+                    &scope.input_from(posts.input()),
+                    &scope.input_from(comments.input()),
+                    &scope.input_from(likes.input()),
+                    &data_path().join("tables"),
+                    DELAY,
+                    IDLE_AFTER,
+                    ThreadConfig::default(),
+                );
+                collect(&recommendations, "recommendations", outputs, probe);
+            });
+
+            let watermarks = watermarks(posts.start_time());
+            ((posts, comments, likes), watermarks)
+        });
+
+        assert_golden("who_to_follow", outputs);
+    }
+}
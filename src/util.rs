@@ -1,26 +1,60 @@
 extern crate plotlib;
 
 use plotlib::page::Page;
-use plotlib::repr::Scatter;
-use plotlib::style::{PointMarker, PointStyle};
+use plotlib::repr::{Line, Scatter};
+use plotlib::style::{LineStyle, PointMarker, PointStyle};
 use plotlib::view::ContinuousView;
 
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const INDEX: &str = "index.html";
+// fraction of the range of the points left empty around them
+const MARGIN: f64 = 0.05;
+const COLOURS: [&str; 8] = [
+    "#DD3355", "#442288", "#35C788", "#E69F00", "#0072B2", "#CC79A7", "#56B4E9", "#999999",
+];
+
+type Point = (f64, f64);
+
+// The range of the values, with a margin, which is never empty.
+fn range(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    let (min, max) = values.fold((std::f64::MAX, std::f64::MIN), |(min, max), x| {
+        (min.min(x), max.max(x))
+    });
+    if min > max {
+        return (0., 1.);
+    }
+    let margin = ((max - min) * MARGIN).max(MARGIN);
+    (min - margin, max + margin)
+}
+
+// Saves numbered plots of the clusters in a directory, along with an HTML page showing all
+// the plots in the directory in order.
 pub struct Plotter {
+    dir: PathBuf,
+    // the start of the file names, so that the workers do not overwrite each other's plots
+    name: String,
     ctr: u32,
 }
 
-type Point = (f64, f64);
-
 impl Plotter {
-    pub fn new() -> Plotter {
-        Plotter { ctr: 0 }
+    pub fn new(dir: &Path, name: &str) -> Plotter {
+        fs::create_dir_all(dir).expect("Could not create the plot directory");
+        Plotter {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            ctr: 0,
+        }
     }
 
     pub fn plot_points(
         &mut self,
-        centers: &Vec<Point>,
-        points: &Vec<Point>,
-        outliers: &Vec<Point>,
+        labels: (&str, &str),
+        centers: &[Point],
+        points: &[Point],
+        outliers: &[Point],
     ) {
         let s1 = Scatter::from_slice(points).style(
             PointStyle::new()
@@ -31,19 +65,124 @@ impl Plotter {
         let s2 = Scatter::from_slice(outliers).style(PointStyle::new().colour("#442288").size(1.));
         let s3 = Scatter::from_slice(centers).style(PointStyle::new().colour("#35C788").size(2.));
 
+        let all = || points.iter().chain(centers.iter());
+        let (x_min, x_max) = range(all().map(|(x, _)| *x));
+        let (y_min, y_max) = range(all().map(|(_, y)| *y));
         let v = ContinuousView::new()
             .add(&s1)
             .add(&s2)
             .add(&s3)
-            .x_range(0., 1.)
-            .y_range(0., 1.)
-            .x_label("Some varying variable")
-            .y_label("The response of something");
+            .x_range(x_min, x_max)
+            .y_range(y_min, y_max)
+            .x_label(labels.0)
+            .y_label(labels.1);
 
         // A page with a single view is then saved to an SVG file
-        Page::single(&v)
-            .save(format!("plots/scatter{}.svg", self.ctr))
-            .unwrap();
+        let file = format!("{}-{:05}.svg", self.name, self.ctr);
+        if let Err(err) = Page::single(&v).save(self.dir.join(&file)) {
+            eprintln!("Could not save plot {}: {:?}", file, err);
+        }
         self.ctr += 1;
+        self.write_index();
+    }
+
+    // Rewrites the index with every plot in the directory, including the ones of the
+    // other workers.
+    fn write_index(&self) {
+        let mut files: Vec<String> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|file| file.ends_with(".svg"))
+                .collect(),
+            Err(_) => return,
+        };
+        files.sort();
+
+        let mut html = String::from("<!DOCTYPE html>\n<html>\n");
+        html.push_str("<head><title>Clusters</title></head>\n<body>\n");
+        for file in files {
+            html.push_str(&format!(
+                "<figure><a href=\"{0}\"><img src=\"{0}\"></a>\n",
+                file
+            ));
+            html.push_str(&format!("<figcaption>{}</figcaption></figure>\n", file));
+        }
+        html.push_str("</body>\n</html>\n");
+
+        // written aside and renamed, so that the page is never seen half written
+        let tmp = self.dir.join(format!(".{}.{}", self.name, INDEX));
+        if fs::write(&tmp, html).is_ok() {
+            let _ = fs::rename(&tmp, self.dir.join(INDEX));
+        }
+    }
+}
+
+// A chart of one or more lines over time, such as metrics computed for every window.
+pub struct LineChart {
+    x_label: String,
+    y_label: String,
+    // map: line -> points, in increasing order of x
+    lines: BTreeMap<String, Vec<Point>>,
+}
+
+impl LineChart {
+    pub fn new(x_label: &str, y_label: &str) -> LineChart {
+        LineChart {
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            lines: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, line: &str, x: f64, y: f64) {
+        self.lines
+            .entry(line.to_string())
+            .or_insert(vec![])
+            .push((x, y));
+    }
+
+    pub fn lines(&self) -> &BTreeMap<String, Vec<Point>> {
+        &self.lines
+    }
+
+    // Saves the chart as an SVG file, with a colour for each line, in the order of their
+    // names. Charts without points are not saved.
+    pub fn save(&self, path: &Path) {
+        let all = || self.lines.values().flatten();
+        if all().next().is_none() {
+            return;
+        }
+
+        let lines: Vec<Line> = self
+            .lines
+            .values()
+            .zip(COLOURS.iter().cycle())
+            .map(|(points, colour)| Line::new(points).style(LineStyle::new().colour(*colour)))
+            .collect();
+        let (x_min, x_max) = range(all().map(|(x, _)| *x));
+        let (y_min, y_max) = range(all().map(|(_, y)| *y).chain(Some(0.)));
+        let mut v = ContinuousView::new()
+            .x_range(x_min, x_max)
+            .y_range(y_min, y_max)
+            .x_label(self.x_label.as_str())
+            .y_label(self.legend().as_str());
+        for line in lines.iter() {
+            v = v.add(line);
+        }
+
+        if let Err(err) = Page::single(&v).save(path) {
+            eprintln!("Could not save chart {:?}: {:?}", path, err);
+        }
+    }
+
+    // The names of the lines are listed next to the label of the axis, in their colours'
+    // order, as the views have no legend.
+    fn legend(&self) -> String {
+        if self.lines.len() <= 1 {
+            return self.y_label.clone();
+        }
+        let names: Vec<&str> = self.lines.keys().map(|name| name.as_str()).collect();
+        format!("{} ({})", self.y_label, names.join(", "))
     }
 }